@@ -0,0 +1,165 @@
+//! Measures how many commands per second the server handles as it's given
+//! more threads, with many clients pipelining commands at once. Run with
+//! `cargo bench`.
+use std::io::{BufRead, BufReader, Write};
+use std::iter;
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long each run sends commands for.
+const DURATION: Duration = Duration::from_secs(3);
+
+/// The number of commands each client sends before reading the responses.
+const BATCH: usize = 64;
+
+/// The number of clients per CPU core.
+const CLIENTS_PER_CORE: usize = 4;
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let clients = cores * CLIENTS_PER_CORE;
+    println!("{cores} cores, {clients} clients, {DURATION:?} per run");
+
+    // Powers of two, then every core.
+    let mut thread_counts: Vec<usize> =
+        iter::successors(Some(1), |n| Some(n * 2))
+            .take_while(|&n| n < cores)
+            .collect();
+    thread_counts.push(cores);
+
+    let mut baseline = None;
+    for threads in thread_counts {
+        let rate = run(threads, clients);
+        let baseline = *baseline.get_or_insert(rate);
+        println!(
+            "{threads:>3} threads: {rate:>10.0} commands/s ({:.2}x)",
+            rate / baseline
+        );
+    }
+}
+
+/// Starts a server with `threads` threads, returning the commands per second
+/// handled across all `clients`.
+fn run(threads: usize, clients: usize) -> f64 {
+    let port = free_port();
+    let server = Server(
+        Command::new(env!("CARGO_BIN_EXE_ebeans"))
+            .args(["--allow-root", "--log-level=error", "--listen=127.0.0.1"])
+            .arg(format!("--port={port}"))
+            .arg(format!("--threads={threads}"))
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start ebeans"),
+    );
+
+    let addr = format!("127.0.0.1:{port}");
+    let started = Instant::now();
+    while TcpStream::connect(&addr).is_err() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "ebeans didn't start"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let handled = Arc::new(AtomicU64::new(0));
+    let workers: Vec<_> = (0..clients)
+        .map(|i| {
+            let conn = TcpStream::connect(&addr).unwrap();
+            let stop = stop.clone();
+            let handled = handled.clone();
+            thread::spawn(move || client(conn, i, &stop, &handled))
+        })
+        .collect();
+
+    thread::sleep(DURATION);
+    stop.store(true, Ordering::Relaxed);
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    drop(server);
+
+    handled.load(Ordering::Relaxed) as f64 / DURATION.as_secs_f64()
+}
+
+/// Sends batches of commands on `conn` until `stop` is set, counting each
+/// response in `handled`. Each batch puts jobs, reserves them, then deletes
+/// them.
+fn client(
+    mut conn: TcpStream,
+    i: usize,
+    stop: &AtomicBool,
+    handled: &AtomicU64,
+) {
+    conn.set_nodelay(true).unwrap();
+    let mut responses = BufReader::new(conn.try_clone().unwrap());
+    let mut line = String::new();
+    let mut read_line = |line: &mut String| {
+        line.clear();
+        let n = responses.read_line(line).unwrap();
+        assert!(n > 0, "ebeans closed the connection");
+    };
+
+    // Each client puts to and reserves from its own tube.
+    let setup =
+        format!("use bench-{i}\r\nwatch bench-{i}\r\nignore default\r\n");
+    conn.write_all(setup.as_bytes()).unwrap();
+    for _ in 0..3 {
+        read_line(&mut line);
+    }
+
+    let puts = "put 0 0 60 5\r\nhello\r\n".repeat(BATCH);
+    let reserves = "reserve-with-timeout 0\r\n".repeat(BATCH);
+
+    while !stop.load(Ordering::Relaxed) {
+        conn.write_all(puts.as_bytes()).unwrap();
+        for _ in 0..BATCH {
+            read_line(&mut line);
+            assert!(line.starts_with("INSERTED"), "{line}");
+        }
+
+        conn.write_all(reserves.as_bytes()).unwrap();
+        let mut deletes = String::new();
+        for _ in 0..BATCH {
+            read_line(&mut line);
+            let id = line.strip_prefix("RESERVED ").unwrap_or_else(|| {
+                panic!("{line}");
+            });
+            let id = id.split(' ').next().unwrap();
+            deletes.push_str(&format!("delete {id}\r\n"));
+            // The job's body.
+            read_line(&mut line);
+        }
+
+        conn.write_all(deletes.as_bytes()).unwrap();
+        for _ in 0..BATCH {
+            read_line(&mut line);
+            assert_eq!(line, "DELETED\r\n");
+        }
+        handled.fetch_add(3 * BATCH as u64, Ordering::Relaxed);
+    }
+}
+
+/// Returns a port that was free a moment ago.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// A running server, killed once dropped.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
@@ -0,0 +1,406 @@
+//! implements optional authentication of clients with `auth <user> <token>`,
+//! and per-tube access control lists checked before each command runs.
+//!
+//! Users, roles, and the role given to clients that haven't authenticated are
+//! defined in a YAML file:
+//!
+//! ```yaml
+//! default_role: legacy
+//! roles:
+//!   legacy:
+//!     - tubes: ["default"]
+//!       permissions: [put, reserve]
+//!   producer:
+//!     - tubes: ["orders", "emails-*"]
+//!       permissions: [put, peek]
+//!   admin:
+//!     - tubes: ["*"]
+//!       permissions: [put, reserve, peek, admin]
+//! users:
+//!   alice:
+//!     token_sha256: "<hex digest of alice's token>"
+//!     role: admin
+//! ```
+//!
+//! Tube patterns are either an exact tube name or a prefix followed by `*`.
+//! Tokens are stored as SHA-256 digests, such as those output by
+//! `printf %s "$token" | sha256sum`. Without a `default_role`, clients can do
+//! little more than `auth` until they authenticate.
+//!
+//! Clients watch `default` until they `ignore` it, so those whose role can't
+//! reserve from `default` must ignore it before they can reserve at all.
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::types::protocol::BeanstalkCommand;
+
+/// Something a client may be permitted to do to the jobs in a tube.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Create jobs with `put`.
+    Put,
+    /// Watch a tube, and reserve and then release, bury, touch, or delete its
+    /// jobs. Reserving needs this on every watched tube.
+    Reserve,
+    /// Inspect jobs and their stats without changing them, including by
+    /// subscribing to their events.
+    Peek,
+    /// Kick, pause, and change jobs in bulk.
+    Admin,
+}
+
+/// The tube a command needs a permission on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Scope<'a> {
+    /// A tube named by the command.
+    Tube(&'a [u8]),
+    /// The tube the client is `use`ing.
+    UsedTube,
+    /// The tube containing a job, named by its ID.
+    JobTube(u64),
+    /// Every tube the client is watching.
+    WatchedTubes,
+    /// Every tube, including those not yet created.
+    AllTubes,
+}
+
+/// Returns the permissions a client needs to run `cmd`, and the tubes it
+/// needs them on. Commands that don't touch any jobs need no permissions.
+pub fn requirements(cmd: &BeanstalkCommand) -> Vec<(Permission, Scope<'_>)> {
+    use BeanstalkCommand::*;
+    use Scope::*;
+
+    match cmd {
+        Put { .. } => vec![(Permission::Put, UsedTube)],
+        Watch { tube } => vec![(Permission::Reserve, Tube(tube))],
+        ReserveJob { id }
+        | Release { id, .. }
+        | Delete { id }
+        | Bury { id, .. }
+        | Touch { id } => vec![(Permission::Reserve, JobTube(*id))],
+        Peek { id } | StatsJob { id } => vec![(Permission::Peek, JobTube(*id))],
+        PeekReady | PeekDelayed | PeekBuried => {
+            vec![(Permission::Peek, UsedTube)]
+        },
+        StatsTube { tube } | QueryJobs { tube, .. } | Subscribe { tube } => {
+            vec![(Permission::Peek, Tube(tube))]
+        },
+        SubscribeAll => vec![(Permission::Peek, AllTubes)],
+        Kick { .. } => vec![(Permission::Admin, UsedTube)],
+        KickJob { id } => vec![(Permission::Admin, JobTube(*id))],
+        PauseTube { tube, .. }
+        | ReprioritiseJobs { tube, .. }
+        | DeleteJobs { tube, .. }
+        | KickJobs { tube, .. } => vec![(Permission::Admin, Tube(tube))],
+        MoveJobs { from, to, .. } | MoveJobsById { from, to, .. } => {
+            vec![
+                (Permission::Admin, Tube(from)),
+                (Permission::Admin, Tube(to)),
+            ]
+        },
+        // Checked against the tubes watched at the time, rather than only on
+        // `watch`, as every client starts off watching `default` and roles
+        // can change on reload.
+        Reserve | ReserveWithTimeout { .. } => {
+            vec![(Permission::Reserve, WatchedTubes)]
+        },
+        Use { .. } | Ignore { .. } | Quit | Auth { .. } | Unsubscribe => vec![],
+        StatsServer | StatsLatency | ListTubes | ListTubeUsed
+        | ListTubesWatched => vec![],
+    }
+}
+
+/// Permissions on a set of tubes.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Grant {
+    tubes: Vec<String>,
+    permissions: Vec<Permission>,
+}
+
+impl Grant {
+    fn allows(&self, permission: Permission, scope: &[u8]) -> bool {
+        self.permissions.contains(&permission)
+            && self.tubes.iter().any(|pattern| {
+                match pattern.as_bytes().strip_suffix(b"*") {
+                    Some(prefix) => scope.starts_with(prefix),
+                    None => pattern.as_bytes() == scope,
+                }
+            })
+    }
+
+    fn allows_all(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+            && self.tubes.iter().any(|pattern| pattern == "*")
+    }
+}
+
+/// A named set of grants given to users.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct Role {
+    grants: Vec<Grant>,
+}
+
+impl Role {
+    /// Returns whether this role may run `cmd`, given the tube the client is
+    /// using, and ways to find the tubes it's watching and the tube containing
+    /// a job.
+    ///
+    /// Commands naming jobs that can't be found are permitted, as they'll fail
+    /// with `NOT_FOUND` regardless.
+    pub fn permits(
+        &self,
+        cmd: &BeanstalkCommand,
+        used_tube: &[u8],
+        watched_tubes: impl Fn() -> Vec<Vec<u8>>,
+        job_tube: impl Fn(u64) -> Option<Vec<u8>>,
+    ) -> bool {
+        self.meets(&requirements(cmd), used_tube, watched_tubes, job_tube)
+    }
+
+    /// As `permits`, but for requirements other than those of a command, such
+    /// as for the admin API.
+    pub fn meets(
+        &self,
+        requirements: &[(Permission, Scope)],
+        used_tube: &[u8],
+        watched_tubes: impl Fn() -> Vec<Vec<u8>>,
+        job_tube: impl Fn(u64) -> Option<Vec<u8>>,
+    ) -> bool {
+        requirements.iter().all(|&(permission, scope)| {
+            let allows = |tube: &[u8]| {
+                self.grants.iter().any(|g| g.allows(permission, tube))
+            };
+
+            match scope {
+                Scope::Tube(tube) => allows(tube),
+                Scope::UsedTube => allows(used_tube),
+                Scope::JobTube(id) => job_tube(id).is_none_or(|t| allows(&t)),
+                Scope::WatchedTubes => {
+                    watched_tubes().iter().all(|tube| allows(tube))
+                },
+                Scope::AllTubes => {
+                    self.grants.iter().any(|g| g.allows_all(permission))
+                },
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    /// Hex-encoded SHA-256 digest of the user's token.
+    token_sha256: String,
+    role: String,
+}
+
+/// Users and roles, as loaded from the auth config file.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// The role of clients that haven't authenticated, if any.
+    #[serde(default)]
+    default_role: Option<String>,
+    #[serde(default)]
+    roles: BTreeMap<String, Role>,
+    #[serde(default)]
+    users: BTreeMap<String, User>,
+}
+
+impl AuthConfig {
+    /// Parses and validates a YAML auth config.
+    pub fn from_yaml(yaml: &str) -> Result<Self, String> {
+        let config: Self =
+            serde_yaml::from_str(yaml).map_err(|e| e.to_string())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Checks that every role referred to is defined, and that every token
+    /// digest is well-formed. Must be called on any config not loaded with
+    /// `from_yaml`.
+    pub fn validate(&self) -> Result<(), String> {
+        let roles = self.users.values().map(|user| &user.role);
+        for role in roles.chain(&self.default_role) {
+            if !self.roles.contains_key(role) {
+                return Err(format!("undefined role: {role}"));
+            }
+        }
+
+        for (name, user) in &self.users {
+            if user.token_sha256.len() != 64
+                || !user.token_sha256.bytes().all(|b| b.is_ascii_hexdigit())
+            {
+                return Err(format!("invalid token_sha256 for user: {name}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the role given to clients that haven't authenticated.
+    pub fn default_role(&self) -> Option<&Role> {
+        self.default_role.as_ref().map(|role| &self.roles[role])
+    }
+
+    /// Returns the role of `user`, or that of clients that haven't
+    /// authenticated if there's no user. Users that no longer exist have no
+    /// role.
+    pub fn role(&self, user: Option<&str>) -> Option<&Role> {
+        match user {
+            Some(user) => self.users.get(user).map(|u| &self.roles[&u.role]),
+            None => self.default_role(),
+        }
+    }
+
+    /// Checks a user's token, returning their role if it's correct.
+    pub fn authenticate(&self, user: &[u8], token: &[u8]) -> Option<&Role> {
+        let user = std::str::from_utf8(user)
+            .ok()
+            .and_then(|user| self.users.get(user));
+
+        let digest: String = Sha256::digest(token)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+
+        // Compare in constant time, so the digest can't be guessed byte by
+        // byte. Tokens of users that don't exist are compared all the same,
+        // so the time taken doesn't reveal which users do.
+        let expected = match user {
+            Some(user) => user.token_sha256.to_ascii_lowercase(),
+            None => "-".repeat(digest.len()),
+        };
+        let differences = expected
+            .bytes()
+            .zip(digest.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+
+        let user = user.filter(|_| differences == 0)?;
+        Some(&self.roles[&user.role])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::states::JobStateKind;
+
+    const CONFIG: &str = r#"
+default_role: legacy
+roles:
+  legacy:
+    - tubes: [default]
+      permissions: [put, reserve]
+  producer:
+    - tubes: [orders, emails-*]
+      permissions: [put, peek]
+  admin:
+    - tubes: ["*"]
+      permissions: [put, reserve, peek, admin]
+users:
+  alice:
+    # sha256("secret")
+    token_sha256: 2BB80D537B1DA3E38BD30361AA855686BDE0EACD7162FEF6A25FE97BF527A25B
+    role: admin
+  bob:
+    token_sha256: 2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b
+    role: producer
+"#;
+
+    #[test]
+    fn test_auth_config() {
+        let config = AuthConfig::from_yaml(CONFIG).unwrap();
+
+        assert!(config.authenticate(b"alice", b"secret").is_some());
+        assert!(config.authenticate(b"alice", b"secrets").is_none());
+        assert!(config.authenticate(b"carol", b"secret").is_none());
+        assert!(config.default_role().is_some());
+        assert_eq!(config.role(None), config.default_role());
+        assert_eq!(
+            config.role(Some("alice")),
+            config.authenticate(b"alice", b"secret")
+        );
+        assert!(config.role(Some("carol")).is_none());
+
+        assert_eq!(
+            AuthConfig::from_yaml("default_role: missing").unwrap_err(),
+            "undefined role: missing"
+        );
+        assert!(AuthConfig::from_yaml(
+            "users: {a: {token_sha256: abc, role: r}}\nroles: {r: []}"
+        )
+        .is_err());
+        assert!(AuthConfig::from_yaml("unknown: true").is_err());
+    }
+
+    #[test]
+    fn test_permits() {
+        use BeanstalkCommand::*;
+
+        let config = AuthConfig::from_yaml(CONFIG).unwrap();
+        let producer = config.authenticate(b"bob", b"secret").unwrap();
+        let admin = config.authenticate(b"alice", b"secret").unwrap();
+        let legacy = config.default_role().unwrap();
+
+        let put = Put {
+            pri: 0,
+            delay: 0,
+            ttr: 1,
+            n_bytes: 0,
+        };
+        let job_tube = |id| (id == 1).then(|| b"orders".to_vec());
+        let watched = || vec![b"default".to_vec()];
+        let permits = |role: &Role, cmd: &BeanstalkCommand, used: &[u8]| {
+            role.permits(cmd, used, watched, job_tube)
+        };
+
+        assert!(permits(producer, &put, b"orders"));
+        assert!(permits(producer, &put, b"emails-daily"));
+        assert!(!permits(producer, &put, b"emails"));
+        assert!(!permits(producer, &put, b"default"));
+        assert!(permits(legacy, &put, b"default"));
+
+        // Job IDs are resolved to their tubes, and unknown jobs are left to
+        // fail later.
+        assert!(permits(producer, &Peek { id: 1 }, b"default"));
+        assert!(!permits(producer, &Delete { id: 1 }, b"default"));
+        assert!(permits(producer, &Delete { id: 2 }, b"default"));
+
+        let watch = Watch {
+            tube: b"orders".to_vec(),
+        };
+        assert!(!permits(producer, &watch, b"default"));
+        assert!(permits(admin, &watch, b"default"));
+
+        let move_jobs = MoveJobsById {
+            from: b"orders".to_vec(),
+            to: b"default".to_vec(),
+            state: JobStateKind::Ready,
+            first: 1,
+            last: 2,
+        };
+        assert!(permits(admin, &move_jobs, b"default"));
+        assert!(!permits(legacy, &move_jobs, b"default"));
+
+        assert!(permits(admin, &SubscribeAll, b"default"));
+        assert!(!permits(producer, &SubscribeAll, b"default"));
+
+        // Reserving needs the permission on every watched tube, including
+        // `default`, which clients watch without asking.
+        assert!(!permits(producer, &Reserve, b"orders"));
+        assert!(permits(legacy, &Reserve, b"orders"));
+        let watched = || vec![b"default".to_vec(), b"orders".to_vec()];
+        assert!(!legacy.permits(&Reserve, b"default", watched, job_tube));
+        assert!(admin.permits(&Reserve, b"default", watched, job_tube));
+
+        // Commands that don't touch jobs need no permissions.
+        assert!(permits(producer, &StatsServer, b"default"));
+    }
+}
@@ -0,0 +1,377 @@
+//! Writes an access log: a JSON record of every command handled, separate to
+//! the server's own logs, for auditing who did what to which jobs.
+//!
+//! Records are emitted as `tracing` events with the [`TARGET`] target, and
+//! written out by [`AccessLogLayer`] along with the fields of the spans they
+//! occur in, such as the connection ID and peer address recorded by the
+//! connection handler. Each record looks like:
+//!
+//! ```json
+//! {"command":"delete","conn_id":3,"id":42,"latency_us":31,
+//!  "peer":"127.0.0.1:50912","response":"DELETED","timestamp":"..."}
+//! ```
+//!
+//! Commands are logged with all of their arguments, but job bodies and
+//! predicates never are. Lines that fail to parse are logged without a
+//! command.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use enchanted_beans::types::protocol::BeanstalkCommand;
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{info, Event, Metadata, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// The target of every access log event.
+pub(crate) const TARGET: &str = "ebeans::access";
+
+/// Returns whether the access log should see `metadata`: its own events, and
+/// every span so that their fields can be included in records.
+pub(crate) fn is_access_log(metadata: &Metadata) -> bool {
+    metadata.is_span() || metadata.target() == TARGET
+}
+
+/// Records that a command was handled, given the raw response sent and the
+/// time taken. `cmd` is `None` if the command line couldn't be parsed.
+pub(crate) fn record(
+    cmd: Option<&BeanstalkCommand>,
+    resp: &[u8],
+    latency: Duration,
+) {
+    // The response's kind is its first word, such as `INSERTED` or `OK`.
+    let response = resp
+        .split(|&b| b == b' ' || b == b'\r')
+        .next()
+        .unwrap_or_default();
+    let response = String::from_utf8_lossy(response);
+    let latency_us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+
+    match cmd {
+        Some(cmd) => {
+            let command = serde_json::to_string(cmd).unwrap_or_default();
+            info!(
+                target: TARGET,
+                command = command.as_str(),
+                response = &*response,
+                latency_us
+            );
+        },
+        None => info!(target: TARGET, response = &*response, latency_us),
+    }
+}
+
+/// A file that's rotated once it grows beyond a given size. On rotation,
+/// `<path>` is renamed to `<path>.1`, `<path>.1` to `<path>.2`, and so on,
+/// with the oldest file beyond `max_files` removed.
+///
+/// Files are only rotated between writes, so each write should be a whole
+/// record.
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Opens `path` for appending, creating it if it doesn't exist.
+    pub(crate) fn open(
+        path: PathBuf,
+        max_size: u64,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    /// Returns the path of the `n`th most recently rotated file.
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        *self = Self::open(self.path.clone(), self.max_size, self.max_files)?;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    /// Writes all of `buf`, first rotating the file if it would otherwise grow
+    /// beyond its maximum size.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Collects the fields of a span or event as JSON values.
+#[derive(Default)]
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        // Commands are recorded as JSON objects holding their name and
+        // arguments, which are flattened into the record.
+        if field.name() == "command" {
+            if let Ok(Value::Object(command)) = serde_json::from_str(value) {
+                self.0.extend(command);
+                return;
+            }
+        }
+
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
+
+/// A `tracing` layer writing access log events to `W` as JSON lines.
+pub(crate) struct AccessLogLayer<W> {
+    writer: Mutex<W>,
+}
+
+impl<W> AccessLogLayer<W> {
+    pub(crate) fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<S, W> Layer<S> for AccessLogLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: Write + Send + 'static,
+{
+    fn on_new_span(
+        &self,
+        attrs: &Attributes<'_>,
+        id: &Id,
+        ctx: Context<'_, S>,
+    ) {
+        let mut fields = JsonFields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<JsonFields>()
+            {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if event.metadata().target() != TARGET {
+            return;
+        }
+
+        let mut timestamp = String::new();
+        // Formatting into a String can't fail.
+        let _ = SystemTime.format_time(&mut Writer::new(&mut timestamp));
+
+        let mut record = JsonFields::default();
+        record.0.insert("timestamp".into(), timestamp.into());
+        for span in ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|s| s.from_root())
+        {
+            if let Some(fields) = span.extensions().get::<JsonFields>() {
+                record.0.extend(fields.0.clone());
+            }
+        }
+        event.record(&mut record);
+
+        let mut line = Value::Object(record.0).to_string();
+        line.push('\n');
+
+        // There's nowhere to log a failure to write the log, other than
+        // stderr.
+        if let Err(error) =
+            self.writer.lock().unwrap().write_all(line.as_bytes())
+        {
+            eprintln!("failed to write access log: {error}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    /// A writer that can be inspected after being given to a layer.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_access_log_layer() {
+        let buf = SharedBuf::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(AccessLogLayer::new(buf.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span =
+                info_span!("handle", conn_id = 7u64, peer = %"127.0.0.1:5");
+            let _guard = span.enter();
+
+            record(
+                Some(&BeanstalkCommand::Put {
+                    pri: 1,
+                    delay: 2,
+                    ttr: 3,
+                    n_bytes: 4,
+                }),
+                b"INSERTED 9\r\n",
+                Duration::from_micros(12),
+            );
+            record(
+                Some(&BeanstalkCommand::Watch {
+                    tube: b"\xffoo".to_vec(),
+                }),
+                b"WATCHING 2\r\n",
+                Duration::ZERO,
+            );
+            record(None, b"BAD_FORMAT\r\n", Duration::ZERO);
+            info!("not an access log event");
+        });
+
+        let buf = buf.0.lock().unwrap();
+        let records: Vec<Value> = buf
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+
+        let mut put = records[0].clone();
+        assert!(put["timestamp"].is_string());
+        put.as_object_mut().unwrap().remove("timestamp");
+        assert_eq!(
+            put,
+            serde_json::json!({
+                "conn_id": 7,
+                "peer": "127.0.0.1:5",
+                "command": "put",
+                "pri": 1,
+                "delay": 2,
+                "ttr": 3,
+                "n_bytes": 4,
+                "response": "INSERTED",
+                "latency_us": 12,
+            })
+        );
+
+        assert_eq!(records[1]["command"], "watch");
+        assert_eq!(records[1]["tube"], "\u{fffd}oo");
+        assert_eq!(records[1]["response"], "WATCHING");
+
+        assert!(records[2].get("command").is_none());
+        assert_eq!(records[2]["response"], "BAD_FORMAT");
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir()
+            .join(format!("ebeans-test-rotating-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for record in ["aaaa\n", "bbbb\n", "cccc\n", "dddd\n", "eeeeeeeeeeee\n"]
+        {
+            file.write_all(record.as_bytes()).unwrap();
+        }
+
+        // Oversized records are written to their own file rather than split.
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("access.log"), "eeeeeeeeeeee\n");
+        assert_eq!(read("access.log.1"), "cccc\ndddd\n");
+        assert_eq!(read("access.log.2"), "aaaa\nbbbb\n");
+        assert!(!dir.join("access.log.3").exists());
+
+        // Reopening carries on from the existing file's size.
+        let mut file = RotatingFile::open(path, 10, 2).unwrap();
+        file.write_all(b"f\n").unwrap();
+        assert_eq!(read("access.log"), "f\n");
+        assert_eq!(read("access.log.1"), "eeeeeeeeeeee\n");
+        assert_eq!(read("access.log.2"), "cccc\ndddd\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
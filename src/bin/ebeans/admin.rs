@@ -0,0 +1,563 @@
+//! Serves an optional HTTP API exposing stats, introspection, and a subset of
+//! management commands as JSON, for tools that can't speak the beanstalkd
+//! protocol.
+//!
+//! Endpoints:
+//!
+//! * `GET /stats`: as `stats`.
+//! * `GET /tubes`: as `list-tubes`.
+//! * `GET /tubes/{tube}/stats`: as `stats-tube <tube>`.
+//! * `GET /tubes/{tube}/jobs?state=<state>`: lists the IDs of all jobs in
+//!   the tube and state.
+//! * `POST /tubes/{tube}/query?state=<state>&kind=<kind>`: as `query-jobs`,
+//!   with the predicate as the request body.
+//! * `POST /tubes/{tube}/pause?delay=<seconds>`: as `pause-tube`.
+//! * `POST /tubes/{tube}/kick?bound=<n>`: as `kick <n>` on the tube.
+//! * `GET /jobs/{id}/stats`: as `stats-job <id>`.
+//! * `POST /jobs/{id}/kick`: as `kick-job <id>`.
+//! * `POST /jobs/{id}/delete`: as `delete <id>`.
+//! * `POST /drain`: enters drain mode, in which `put` is refused with
+//!   `DRAINING`, as on SIGUSR1.
+//! * `DELETE /drain`: leaves drain mode.
+//! * `GET /metrics`: server metrics in the Prometheus text format.
+//!
+//! With access control enabled, requests are permitted as the equivalent
+//! commands would be, with `/drain` needing the admin permission on every
+//! tube. Users authenticate with an `Authorization: Bearer <user>:<token>`
+//! header, and requests without one are given the default role. Tokens are
+//! sent in the clear, so only serve the API on a trusted interface, such as
+//! loopback.
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::body::Bytes;
+use axum::extract::{FromRequestParts, Path, Query, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use enchanted_beans::auth::{Permission, Scope};
+use enchanted_beans::metrics;
+use enchanted_beans::parser::parse_name;
+use enchanted_beans::query::{JobQuery, Predicate, PredicateKind, Selector};
+use enchanted_beans::queue;
+use enchanted_beans::types::states::JobStateKind;
+use enchanted_beans::util::bytes_to_human_str;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::state::ServerState;
+
+/// Serves the admin API on `listener` until `cancel` is triggered.
+pub(crate) async fn serve(
+    cancel: CancellationToken,
+    state: Arc<ServerState>,
+    listener: TcpListener,
+) -> Result<()> {
+    info!(addr = %listener.local_addr()?, "admin API listening");
+
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(cancel.cancelled_owned())
+        .await?;
+
+    Ok(())
+}
+
+fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/stats", get(stats))
+        .route("/tubes", get(list_tubes))
+        .route("/tubes/{tube}/stats", get(stats_tube))
+        .route("/tubes/{tube}/jobs", get(list_jobs))
+        .route("/tubes/{tube}/query", post(query_jobs))
+        .route("/tubes/{tube}/pause", post(pause_tube))
+        .route("/tubes/{tube}/kick", post(kick))
+        .route("/jobs/{id}/stats", get(stats_job))
+        .route("/jobs/{id}/kick", post(kick_job))
+        .route("/jobs/{id}/delete", post(delete))
+        .route("/drain", post(drain).delete(undrain))
+        .route("/metrics", get(metrics))
+        .with_state(state)
+}
+
+/// The body of any unsuccessful response.
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+}
+
+fn error(status: StatusCode, error: &'static str) -> Response {
+    (status, Json(ErrorBody { error })).into_response()
+}
+
+/// Responds with a command's result as JSON, or 404 if the job or tube
+/// wasn't found.
+fn respond<T: Serialize>(result: Result<T, queue::Error>) -> Response {
+    match result {
+        Ok(body) => Json(body).into_response(),
+        Err(queue::Error::NotFound) => {
+            error(StatusCode::NOT_FOUND, "not found")
+        },
+        Err(_) => error(StatusCode::CONFLICT, "conflict"),
+    }
+}
+
+/// Checks that the request's credentials meet `requirements`, returning a
+/// 401 response if they're invalid, or 403 if they fall short.
+fn refusal(
+    state: &ServerState,
+    headers: &HeaderMap,
+    requirements: &[(Permission, Scope)],
+) -> Option<Response> {
+    let settings = state.settings();
+    let auth = settings.auth.as_ref()?;
+
+    let role = match headers.get(header::AUTHORIZATION) {
+        Some(value) => {
+            let role = value
+                .as_bytes()
+                .strip_prefix(b"Bearer ")
+                .and_then(|creds| {
+                    let colon = creds.iter().position(|&b| b == b':')?;
+                    Some((&creds[..colon], &creds[colon + 1..]))
+                })
+                .and_then(|(user, token)| auth.authenticate(user, token));
+            match role {
+                Some(role) => Some(role),
+                None => {
+                    return Some(error(
+                        StatusCode::UNAUTHORIZED,
+                        "invalid credentials",
+                    ))
+                },
+            }
+        },
+        None => auth.default_role(),
+    };
+
+    // Requirements here always name their tubes, rather than relying on a
+    // client's used or watched tubes.
+    let permitted = requirements.is_empty()
+        || role.is_some_and(|role| {
+            role.meets(requirements, b"", Vec::new, |id| {
+                state.queue.job_tube(id)
+            })
+        });
+    (!permitted).then(|| error(StatusCode::FORBIDDEN, "not permitted"))
+}
+
+/// Selects jobs in a state, by a predicate if there is one.
+fn query(
+    state: &ServerState,
+    job_state: JobStateKind,
+    selector: Selector,
+) -> JobQuery {
+    JobQuery {
+        state: job_state,
+        selector,
+        scan_limit: state.settings().query_scan_limit as usize,
+    }
+}
+
+/// A tube name taken from the request path, validated as for the beanstalkd
+/// protocol.
+struct TubeName(Vec<u8>);
+
+impl<S: Send + Sync> FromRequestParts<S> for TubeName {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let Path(tube) = Path::<String>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        parse_name(tube.as_bytes())
+            .map(TubeName)
+            .map_err(|_| error(StatusCode::BAD_REQUEST, "invalid tube name"))
+    }
+}
+
+async fn stats(State(state): State<Arc<ServerState>>) -> Response {
+    Json(state.stats()).into_response()
+}
+
+async fn list_tubes(State(state): State<Arc<ServerState>>) -> Response {
+    let tubes = state.queue.connect().list_tubes().await;
+    let tubes: Vec<String> =
+        tubes.iter().map(|t| bytes_to_human_str(t)).collect();
+    Json(tubes).into_response()
+}
+
+async fn stats_tube(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    TubeName(tube): TubeName,
+) -> Response {
+    if let Some(refused) =
+        refusal(&state, &headers, &[(Permission::Peek, Scope::Tube(&tube))])
+    {
+        return refused;
+    }
+
+    respond(state.queue.connect().stats_tube(&tube).await)
+}
+
+#[derive(Deserialize)]
+struct ListJobsParams {
+    state: JobStateKind,
+}
+
+async fn list_jobs(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    TubeName(tube): TubeName,
+    Query(params): Query<ListJobsParams>,
+) -> Response {
+    if let Some(refused) =
+        refusal(&state, &headers, &[(Permission::Peek, Scope::Tube(&tube))])
+    {
+        return refused;
+    }
+
+    let query = query(&state, params.state, Selector::Ids(0..=u64::MAX));
+    Json(state.queue.connect().query_jobs(&tube, &query).await).into_response()
+}
+
+#[derive(Deserialize)]
+struct QueryJobsParams {
+    state: JobStateKind,
+    kind: PredicateKind,
+}
+
+async fn query_jobs(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    TubeName(tube): TubeName,
+    Query(params): Query<QueryJobsParams>,
+    body: Bytes,
+) -> Response {
+    if let Some(refused) =
+        refusal(&state, &headers, &[(Permission::Peek, Scope::Tube(&tube))])
+    {
+        return refused;
+    }
+
+    let Ok(predicate) = Predicate::parse(params.kind, &body) else {
+        return error(StatusCode::BAD_REQUEST, "invalid predicate");
+    };
+
+    let query = query(&state, params.state, Selector::Predicate(predicate));
+    Json(state.queue.connect().query_jobs(&tube, &query).await).into_response()
+}
+
+#[derive(Deserialize)]
+struct PauseTubeParams {
+    delay: u32,
+}
+
+/// The body of responses to commands that succeed without a result.
+#[derive(Serialize)]
+struct DoneBody {
+    done: bool,
+}
+
+async fn pause_tube(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    TubeName(tube): TubeName,
+    Query(params): Query<PauseTubeParams>,
+) -> Response {
+    if let Some(refused) =
+        refusal(&state, &headers, &[(Permission::Admin, Scope::Tube(&tube))])
+    {
+        return refused;
+    }
+
+    let paused = state.queue.connect().pause_tube(&tube, params.delay).await;
+    respond(paused.map(|()| DoneBody { done: true }))
+}
+
+#[derive(Deserialize)]
+struct KickParams {
+    bound: u64,
+}
+
+/// The body of responses to `/tubes/{tube}/kick`.
+#[derive(Serialize)]
+struct KickedBody {
+    kicked: u64,
+}
+
+async fn kick(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    TubeName(tube): TubeName,
+    Query(params): Query<KickParams>,
+) -> Response {
+    if let Some(refused) =
+        refusal(&state, &headers, &[(Permission::Admin, Scope::Tube(&tube))])
+    {
+        return refused;
+    }
+
+    let queue = state.queue.connect();
+    queue.use_tube(&tube).await;
+    let kicked = queue.kick(params.bound).await;
+    Json(KickedBody { kicked }).into_response()
+}
+
+async fn stats_job(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Response {
+    if let Some(refused) =
+        refusal(&state, &headers, &[(Permission::Peek, Scope::JobTube(id))])
+    {
+        return refused;
+    }
+
+    respond(state.queue.connect().stats_job(id).await)
+}
+
+async fn kick_job(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Response {
+    if let Some(refused) =
+        refusal(&state, &headers, &[(Permission::Admin, Scope::JobTube(id))])
+    {
+        return refused;
+    }
+
+    let kicked = state.queue.connect().kick_job(id).await;
+    respond(kicked.map(|()| DoneBody { done: true }))
+}
+
+async fn delete(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Response {
+    if let Some(refused) = refusal(
+        &state,
+        &headers,
+        &[(Permission::Reserve, Scope::JobTube(id))],
+    ) {
+        return refused;
+    }
+
+    let deleted = state.queue.connect().delete(id).await;
+    respond(deleted.map(|()| DoneBody { done: true }))
+}
+
+/// The body of responses to `/drain`.
+#[derive(Serialize)]
+struct DrainBody {
+    draining: bool,
+}
+
+async fn drain(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(refused) =
+        refusal(&state, &headers, &[(Permission::Admin, Scope::AllTubes)])
+    {
+        return refused;
+    }
+
+    state.set_draining(true);
+    Json(DrainBody { draining: true }).into_response()
+}
+
+async fn undrain(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(refused) =
+        refusal(&state, &headers, &[(Permission::Admin, Scope::AllTubes)])
+    {
+        return refused;
+    }
+
+    state.set_draining(false);
+    Json(DrainBody { draining: false }).into_response()
+}
+
+async fn metrics(State(state): State<Arc<ServerState>>) -> Response {
+    let mut body = metrics::render(&state.stats(), &state.queue.tube_stats());
+    body.push_str(&metrics::render_listeners(&state.listeners.lock().unwrap()));
+    body.push_str(&metrics::render_latencies(&state.latencies.lock().unwrap()));
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Method, Request};
+    use clap::Parser;
+    use enchanted_beans::auth::AuthConfig;
+    use enchanted_beans::queue::Queue;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::args::Args;
+    use crate::state::Settings;
+
+    fn state() -> Arc<ServerState> {
+        let settings = Settings::load(&Args::parse_from(["ebeans"])).unwrap();
+        Arc::new(ServerState::new(settings, Queue::new(16)))
+    }
+
+    /// Sends a request to the API, returning the status and body.
+    async fn send(
+        state: &Arc<ServerState>,
+        method: Method,
+        uri: &str,
+        body: &'static str,
+    ) -> (StatusCode, String) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body))
+            .unwrap();
+        send_request(state, req).await
+    }
+
+    async fn send_request(
+        state: &Arc<ServerState>,
+        req: Request<Body>,
+    ) -> (StatusCode, String) {
+        let resp = router(state.clone()).oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_query_jobs() {
+        let state = state();
+        for id in [42, 7, 42] {
+            let body = format!(r#"{{"customer_id": {id}}}"#);
+            state.queue.put(0, 0, 60, body.into()).await.unwrap();
+        }
+
+        let uri = "/tubes/default/query?state=ready&kind=json";
+        let (status, body) =
+            send(&state, Method::POST, uri, "$.customer_id == 42").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "[1,3]"));
+
+        let (status, body) = send(&state, Method::POST, uri, "$.").await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::BAD_REQUEST, r#"{"error":"invalid predicate"}"#)
+        );
+
+        let uri = "/tubes/default/query?state=ready&kind=xml";
+        let (status, _) = send(&state, Method::POST, uri, "$.a").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let state = state();
+        let id = state.queue.put(0, 0, 60, vec![]).await.unwrap();
+
+        let uri = format!("/jobs/{id}/delete");
+        let (status, body) = send(&state, Method::POST, &uri, "").await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::OK, r#"{"done":true}"#)
+        );
+        assert_eq!(state.queue.peek(id).await, Err(queue::Error::NotFound));
+
+        let (status, _) = send(&state, Method::POST, &uri, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let state = state();
+
+        let (status, body) = send(&state, Method::POST, "/drain", "").await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::OK, r#"{"draining":true}"#)
+        );
+        let put = state.queue.put(0, 0, 60, vec![]).await;
+        assert_eq!(put, Err(queue::Error::Draining));
+
+        let (status, body) = send(&state, Method::DELETE, "/drain", "").await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::OK, r#"{"draining":false}"#)
+        );
+        assert!(!state.queue.draining());
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let state = state();
+        let mut settings =
+            Settings::load(&Args::parse_from(["ebeans"])).unwrap();
+        settings.auth = Some(
+            AuthConfig::from_yaml(
+                r#"
+default_role: viewer
+roles:
+  viewer: [{tubes: [default], permissions: [peek]}]
+  admin: [{tubes: ["*"], permissions: [admin]}]
+users:
+  alice:
+    # sha256("secret")
+    token_sha256: 2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b
+    role: admin
+"#,
+            )
+            .unwrap(),
+        );
+        state.set_settings(settings);
+        let drain = |auth: &str| {
+            let req = Request::builder().method(Method::POST).uri("/drain");
+            match auth {
+                "" => req,
+                auth => req.header(header::AUTHORIZATION, auth),
+            }
+            .body(Body::empty())
+            .unwrap()
+        };
+
+        // Requests without credentials are given the default role.
+        let (status, _) =
+            send(&state, Method::GET, "/tubes/default/stats", "").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send_request(&state, drain("")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        for auth in ["Bearer alice:wrong", "Bearer bob:secret", "alice:secret"]
+        {
+            let (status, _) = send_request(&state, drain(auth)).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{auth}");
+        }
+        let (status, _) =
+            send_request(&state, drain("Bearer alice:secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(state.queue.draining());
+    }
+}
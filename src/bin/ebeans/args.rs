@@ -1,24 +1,250 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{ArgAction, ArgGroup, Parser};
+use enchanted_beans::auth::AuthConfig;
+use tracing::Level;
 
-#[derive(Parser, Debug)]
-#[command(about, long_about = None, version)]
+use crate::config::TubeConfig;
+
+#[derive(Clone, Debug, Parser)]
+#[command(about, long_about = None, version, disable_version_flag = true)]
+#[command(group(
+    ArgGroup::new("other_listeners")
+        .args(["unix_socket", "tls_listen"])
+        .multiple(true)
+))]
 pub(crate) struct Args {
-    /// Address to listen on.
-    #[arg(short, long, default_value_t = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))]
-    pub(crate) listen: IpAddr,
-    /// (TCP) port to listen on.
+    /// Prints version.
+    #[arg(short = 'v', short_alias = 'V', long, action = ArgAction::Version)]
+    version: (),
+    /// Accepts beanstalkd's flags where they clash with ebeans', so `-V`
+    /// increases the log level and `-d` is rejected. Also enabled by invoking
+    /// ebeans as `beanstalkd`.
+    #[arg(long)]
+    pub(crate) beanstalkd_compat: bool,
+    /// Reads settings from this TOML or YAML file, named after their long
+    /// flags, such as `max_job_size`. Flags take precedence over the file.
+    #[arg(long)]
+    pub(crate) config: Option<PathBuf>,
+    /// Address to listen on, as `addr:port`, or just `addr` to use `--port`.
+    /// May be given more than once, such as to listen on IPv4 and IPv6
+    /// interfaces. Defaults to 0.0.0.0.
+    #[arg(short, long, value_parser = parse_listen_addr)]
+    pub(crate) listen: Vec<ListenAddr>,
+    /// (TCP) port to listen on, for addresses given without one.
     #[arg(short, long, default_value_t = 11300)]
     pub(crate) port: u16,
-    /// Enables write-ahead logging and set the directory to store WAL files in.
+    /// Disables the plain TCP listener, for use with `--unix-socket` or
+    /// `--tls-listen`.
+    #[arg(long, requires = "other_listeners")]
+    pub(crate) no_tcp: bool,
+    /// Listens for connections on a Unix domain socket at this path, as well
+    /// as on TCP unless `--no-tcp` is given.
+    #[arg(long)]
+    pub(crate) unix_socket: Option<PathBuf>,
+    /// Sets the permissions of the Unix domain socket, in octal, such as 660.
+    #[arg(long, value_parser = parse_mode, requires = "unix_socket")]
+    pub(crate) unix_socket_mode: Option<u32>,
+    /// Enables a TLS listener and sets the address and port to serve it on,
+    /// alongside the plain TCP listener unless `--no-tcp` is given.
+    #[arg(long, requires_all = ["tls_cert", "tls_key"])]
+    pub(crate) tls_listen: Option<SocketAddr>,
+    /// Sets the path to the TLS listener's certificate chain, in PEM format.
+    #[arg(long, requires = "tls_listen")]
+    pub(crate) tls_cert: Option<PathBuf>,
+    /// Sets the path to the TLS listener's private key, in PEM format.
+    #[arg(long, requires = "tls_listen")]
+    pub(crate) tls_key: Option<PathBuf>,
+    /// Requires TLS clients to present a certificate signed by a CA in this PEM
+    /// file. The common name of each client certificate's subject is logged as
+    /// the client's identity.
+    #[arg(long, requires = "tls_listen")]
+    pub(crate) tls_client_ca: Option<PathBuf>,
+    /// Listens on a Unix domain socket at this path for a new process to hand
+    /// over listening sockets to, for restarts without refusing connections.
+    /// On startup, takes over the sockets of any process listening here.
+    #[arg(long)]
+    pub(crate) handoff_socket: Option<PathBuf>,
+    /// Enables write-ahead logging and sets the directory to store WAL files
+    /// in, creating it if need be. Jobs are recovered from it on startup.
     #[arg(short = 'b', long)]
     pub(crate) wal_dir: Option<PathBuf>,
+    /// Syncs the WAL to disk at most once in this many milliseconds, so a
+    /// crash of the machine may lose the jobs of the last period. By default,
+    /// it's synced before each put is answered.
+    #[arg(short = 'f', long, overrides_with = "no_fsync")]
+    pub(crate) fsync_period: Option<u64>,
+    /// Never explicitly syncs the WAL to disk, leaving it to the OS.
+    #[arg(short = 'F', long, overrides_with = "fsync_period")]
+    pub(crate) no_fsync: bool,
+    /// Sets the size of each WAL file, in bytes.
+    #[arg(short = 's', long, default_value_t = 10 * 1024 * 1024)]
+    pub(crate) wal_file_size: u64,
+    /// Stores jobs in a SQLite database at this path, creating it if need be,
+    /// and recovers them from it on startup.
+    #[arg(long, conflicts_with = "wal_dir")]
+    pub(crate) sqlite_db: Option<PathBuf>,
+    /// Switches to this user and their primary group once listening.
+    #[arg(short = 'u', long)]
+    pub(crate) user: Option<String>,
+    /// Switches to this group once listening, in place of the user's primary
+    /// group.
+    #[arg(long, requires = "user")]
+    pub(crate) group: Option<String>,
+    /// Allows running as root, which is otherwise refused.
+    #[arg(long)]
+    pub(crate) allow_root: bool,
     /// Sets the maximum allowed job size.
     #[arg(short = 'z', long, default_value_t = 65535)]
     pub(crate) max_job_size: u32,
+    /// Sets the maximum number of bytes of each job body scanned when
+    /// selecting jobs by content.
+    #[arg(long, default_value_t = 65535)]
+    pub(crate) query_scan_limit: u32,
+    /// Handles connections on this many threads, defaulting to one per CPU
+    /// core.
+    #[arg(long)]
+    pub(crate) threads: Option<NonZeroUsize>,
+    /// Gives workers this many seconds to finish their reserved jobs when
+    /// shutting down, before closing their connections regardless.
+    #[arg(long, default_value_t = 30)]
+    pub(crate) shutdown_timeout: u64,
+    /// Enables the admin HTTP API and sets the address and port to serve it on.
+    /// Tokens are sent to it in the clear, so only bind it to a trusted
+    /// interface, such as loopback.
+    #[arg(long)]
+    pub(crate) admin_listen: Option<SocketAddr>,
+    /// Logs any command taking longer than this many milliseconds to handle.
+    #[arg(long)]
+    pub(crate) slow_command_threshold: Option<u64>,
+    /// Enables access control, with users and roles defined in this YAML
+    /// file.
+    #[arg(long)]
+    pub(crate) auth_config: Option<PathBuf>,
+    /// Sets the number of events buffered for each subscribed client, beyond
+    /// which the oldest are dropped.
+    #[arg(long, default_value_t = 1024)]
+    pub(crate) event_buffer: usize,
+    /// Writes a JSON record of every command handled to this file.
+    #[arg(long)]
+    pub(crate) access_log: Option<PathBuf>,
+    /// Rotates the access log once it would grow beyond this many bytes.
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    pub(crate) access_log_max_size: u64,
+    /// Sets the number of rotated access logs kept alongside the current one.
+    #[arg(long, default_value_t = 5)]
+    pub(crate) access_log_max_files: usize,
+    /// Sets the most verbose level of logs written: trace, debug, info, warn,
+    /// or error. Defaults to trace with `--debug`, and info otherwise.
+    #[arg(long)]
+    pub(crate) log_level: Option<Level>,
     /// Enables human-friendly logging.
     #[arg(short, long, default_value_t)]
     pub(crate) debug: bool,
+    /// Users and roles given in the config file, in place of
+    /// `--auth-config`.
+    #[arg(skip)]
+    pub(crate) auth: Option<AuthConfig>,
+    /// Settings for individual tubes, from the config file.
+    #[arg(skip)]
+    pub(crate) tubes: BTreeMap<Vec<u8>, TubeConfig>,
+}
+
+impl Args {
+    /// Returns each address to listen on for plain TCP connections.
+    pub(crate) fn listen_addrs(&self) -> Vec<SocketAddr> {
+        if self.listen.is_empty() {
+            return vec![(Ipv4Addr::UNSPECIFIED, self.port).into()];
+        }
+
+        self.listen
+            .iter()
+            .map(|addr| (addr.ip, addr.port.unwrap_or(self.port)).into())
+            .collect()
+    }
+
+    /// Returns the most verbose level of the server's own logs.
+    pub(crate) fn max_log_level(&self) -> Level {
+        match (self.log_level, self.debug) {
+            (Some(level), _) => level,
+            (None, true) => Level::TRACE,
+            (None, false) => Level::INFO,
+        }
+    }
+}
+
+/// An address to listen on, which may leave the port to `--port`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ListenAddr {
+    ip: IpAddr,
+    port: Option<u16>,
+}
+
+/// Parses an address to listen on, such as `127.0.0.1:11300`, `[::1]:11300`,
+/// `::1`, or `0.0.0.0`.
+fn parse_listen_addr(s: &str) -> Result<ListenAddr, String> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(ListenAddr {
+            ip: addr.ip(),
+            port: Some(addr.port()),
+        });
+    }
+
+    let ip = s.strip_prefix('[').and_then(|s| s.strip_suffix(']'));
+    match ip.unwrap_or(s).parse() {
+        Ok(ip) => Ok(ListenAddr { ip, port: None }),
+        Err(_) => Err("expected an address such as 127.0.0.1:11300, \
+            [::1]:11300, or 0.0.0.0"
+            .into()),
+    }
+}
+
+/// Parses file permissions given in octal.
+fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err("expected octal permissions such as 660".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_addrs() {
+        let addrs = |args: &[&str]| {
+            let argv = ["ebeans"].iter().chain(args);
+            Args::try_parse_from(argv).unwrap().listen_addrs()
+        };
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+
+        assert_eq!(addrs(&[]), [addr("0.0.0.0:11300")]);
+        assert_eq!(addrs(&["-p", "11301"]), [addr("0.0.0.0:11301")]);
+        assert_eq!(
+            addrs(&[
+                "-l",
+                "127.0.0.1",
+                "--listen=[::1]:11302",
+                "-l",
+                "::",
+                "-l",
+                "[fe80::1]",
+                "-p",
+                "11301",
+            ]),
+            [
+                addr("127.0.0.1:11301"),
+                addr("[::1]:11302"),
+                addr("[::]:11301"),
+                addr("[fe80::1]:11301"),
+            ]
+        );
+
+        assert!(parse_listen_addr("localhost:11300").is_err());
+        assert!(parse_listen_addr("127.0.0.1:").is_err());
+    }
 }
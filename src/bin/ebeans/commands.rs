@@ -0,0 +1,208 @@
+//! Runs commands from a connection against its client of the queue.
+use bytes::Bytes;
+use enchanted_beans::query::{JobQuery, Predicate, Selector};
+use enchanted_beans::queue::{JobData, Queue};
+use enchanted_beans::types::protocol::{BeanstalkCommand, BeanstalkResponse};
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+
+use crate::state::{ServerState, Settings};
+
+/// Runs a command, given the data following its line for those with any.
+/// Commands that act on the connection itself, such as `auth` and
+/// `subscribe`, are left to the caller.
+///
+/// Reserves wait until `closing` is cancelled at most, and are then refused
+/// with `SHUTTING_DOWN`.
+pub(crate) async fn execute(
+    queue: &Queue,
+    server: &ServerState,
+    settings: &Settings,
+    closing: &CancellationToken,
+    cmd: &BeanstalkCommand,
+    data: Option<Bytes>,
+) -> BeanstalkResponse {
+    use BeanstalkCommand::*;
+    use BeanstalkResponse as R;
+
+    let found = |job: JobData| R::Found {
+        id: job.id,
+        data: job.data,
+    };
+    let reserved = |job: JobData| R::Reserved {
+        id: job.id,
+        data: job.data,
+    };
+    let data = data.unwrap_or_default();
+    let query = |state, selector| JobQuery {
+        state,
+        selector,
+        scan_limit: settings.query_scan_limit as usize,
+    };
+    let predicate = |state, kind| {
+        Predicate::parse(kind, &data)
+            .map(|pred| query(state, Selector::Predicate(pred)))
+    };
+
+    let resp = match cmd {
+        Put {
+            pri, delay, ttr, ..
+        } => {
+            let min_ttr = settings
+                .tubes
+                .get(&queue.used_tube())
+                .and_then(|tube| tube.min_ttr)
+                .unwrap_or(0);
+            queue
+                .put(*pri, *delay, (*ttr).max(min_ttr), data.into())
+                .await
+                .map(|id| R::Inserted { id })
+        },
+        Use { tube } => Ok(R::Using {
+            tube: queue.use_tube(tube).await,
+        }),
+        Reserve => select! {
+            job = queue.reserve() => job.map(reserved),
+            _ = closing.cancelled() => Ok(R::ShuttingDown),
+        },
+        ReserveWithTimeout { timeout } => select! {
+            job = queue.reserve_with_timeout(*timeout) => job.map(reserved),
+            _ = closing.cancelled() => Ok(R::ShuttingDown),
+        },
+        ReserveJob { id } => queue.reserve_job(*id).await.map(reserved),
+        Release { id, pri, delay } => {
+            queue.release(*id, *pri, *delay).await.map(|()| R::Released)
+        },
+        Delete { id } => queue.delete(*id).await.map(|()| R::Deleted),
+        Bury { id, pri } => queue.bury(*id, *pri).await.map(|()| R::Buried),
+        Touch { id } => queue.touch(*id).await.map(|()| R::Touched),
+        Watch { tube } => Ok(R::Watching {
+            count: queue.watch(tube).await,
+        }),
+        Ignore { tube } => {
+            queue.ignore(tube).await.map(|count| R::Watching { count })
+        },
+        Peek { id } => queue.peek(*id).await.map(found),
+        PeekReady => queue.peek_ready().await.map(found),
+        PeekDelayed => queue.peek_delayed().await.map(found),
+        PeekBuried => queue.peek_buried().await.map(found),
+        Kick { bound } => Ok(R::KickedCount {
+            count: queue.kick(*bound).await,
+        }),
+        KickJob { id } => queue.kick_job(*id).await.map(|()| R::Kicked),
+        StatsJob { id } => queue
+            .stats_job(*id)
+            .await
+            .map(|data| R::OkStatsJob { data }),
+        StatsTube { tube } => queue
+            .stats_tube(tube)
+            .await
+            .map(|data| R::OkStatsTube { data }),
+        StatsServer => Ok(R::OkStats {
+            data: Box::new(server.complete_stats(queue.stats().await)),
+        }),
+        ListTubes => Ok(R::OkListTubes {
+            tubes: queue.list_tubes().await,
+        }),
+        ListTubeUsed => Ok(R::Using {
+            tube: queue.list_tube_used().await,
+        }),
+        ListTubesWatched => Ok(R::OkListTubes {
+            tubes: queue.list_tubes_watched().await,
+        }),
+        PauseTube { tube, delay } => {
+            queue.pause_tube(tube, *delay).await.map(|()| R::Paused)
+        },
+        QueryJobs {
+            tube, state, kind, ..
+        } => match predicate(*state, *kind) {
+            Ok(query) => Ok(R::OkJobIds {
+                ids: queue.query_jobs(tube, &query).await,
+            }),
+            Err(_) => Ok(R::BadFormat),
+        },
+        ReprioritiseJobs {
+            tube,
+            state,
+            kind,
+            pri,
+            ..
+        } => match predicate(*state, *kind) {
+            Ok(query) => Ok(R::ReprioritisedCount {
+                count: queue.reprioritise_jobs(tube, &query, *pri).await,
+            }),
+            Err(_) => Ok(R::BadFormat),
+        },
+        MoveJobs {
+            from,
+            to,
+            state,
+            kind,
+            ..
+        } => match predicate(*state, *kind) {
+            Ok(query) => Ok(R::MovedCount {
+                count: queue.move_jobs(from, to, &query).await,
+            }),
+            Err(_) => Ok(R::BadFormat),
+        },
+        MoveJobsById {
+            from,
+            to,
+            state,
+            first,
+            last,
+        } => Ok(R::MovedCount {
+            count: queue
+                .move_jobs(
+                    from,
+                    to,
+                    &query(*state, Selector::Ids(*first..=*last)),
+                )
+                .await,
+        }),
+        DeleteJobs {
+            tube,
+            state,
+            kind,
+            dry_run,
+            ..
+        } => match predicate(*state, *kind) {
+            Ok(query) => {
+                let ids = queue.delete_jobs(tube, &query, *dry_run).await;
+                Ok(match dry_run {
+                    true => R::OkDryRun { data: ids.into() },
+                    false => R::DeletedCount {
+                        count: ids.len() as u64,
+                    },
+                })
+            },
+            Err(_) => Ok(R::BadFormat),
+        },
+        KickJobs {
+            tube,
+            state,
+            kind,
+            dry_run,
+            ..
+        } => match predicate(*state, *kind) {
+            Ok(query) => {
+                let ids = queue.kick_jobs(tube, &query, *dry_run).await;
+                Ok(match dry_run {
+                    true => R::OkDryRun { data: ids.into() },
+                    false => R::KickedCount {
+                        count: ids.len() as u64,
+                    },
+                })
+            },
+            Err(_) => Ok(R::BadFormat),
+        },
+        StatsLatency
+        | Quit
+        | Subscribe { .. }
+        | SubscribeAll
+        | Unsubscribe
+        | Auth { .. } => unreachable!("handled by the connection"),
+    };
+
+    resp.unwrap_or_else(R::from)
+}
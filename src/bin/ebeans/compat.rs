@@ -0,0 +1,113 @@
+//! Accepts beanstalkd's command line, so ebeans can replace it in existing
+//! service definitions.
+//!
+//! Most of beanstalkd's flags mean the same to ebeans: `-l`, `-p`, `-b`,
+//! `-f`, `-F`, `-s`, `-z`, `-u`, and `-v`. The rest clash with ebeans' own
+//! flags, so are only accepted in compatibility mode, enabled by invoking
+//! ebeans as `beanstalkd`, such as through a symlink, or by passing
+//! `--beanstalkd-compat`. In compatibility mode:
+//!
+//! * `-V` increases the log level to debug, or to trace if repeated, rather
+//!   than printing the version.
+//! * `-d`, which once detached beanstalkd from its terminal, is rejected
+//!   rather than enabling debug logging, as ebeans always runs in the
+//!   foreground.
+//!
+//! Any of ebeans' long flags can still be used in compatibility mode.
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Enables compatibility mode.
+const FLAG: &str = "--beanstalkd-compat";
+
+/// Rewrites a beanstalkd command line in compatibility mode as the
+/// equivalent ebeans command line, or returns any other command line as is.
+pub(crate) fn translate(argv: Vec<OsString>) -> Result<Vec<OsString>, String> {
+    let invoked_as_beanstalkd = argv
+        .first()
+        .and_then(|arg0| Path::new(arg0).file_name())
+        .is_some_and(|name| name == "beanstalkd");
+    if !invoked_as_beanstalkd && !argv.iter().any(|arg| arg == FLAG) {
+        return Ok(argv);
+    }
+
+    let mut args = argv.into_iter();
+    let mut translated: Vec<OsString> = args.next().into_iter().collect();
+    let mut verbosity = 0;
+    let mut log_level_given = false;
+
+    for arg in args {
+        match arg.to_str() {
+            Some(flag) if is_verbose(flag) => verbosity += flag.len() - 1,
+            Some("-d") => {
+                return Err("-d (detach) isn't supported in beanstalkd \
+                    compatibility mode: ebeans runs in the foreground"
+                    .into())
+            },
+            Some(flag) => {
+                log_level_given |= flag.starts_with("--log-level");
+                translated.push(arg);
+            },
+            None => translated.push(arg),
+        }
+    }
+
+    // An explicit log level takes precedence.
+    if !log_level_given {
+        match verbosity {
+            0 => {},
+            1 => translated.push("--log-level=debug".into()),
+            _ => translated.push("--log-level=trace".into()),
+        }
+    }
+
+    Ok(translated)
+}
+
+/// Returns whether `flag` is one or more of beanstalkd's `-V`, such as `-VV`.
+fn is_verbose(flag: &str) -> bool {
+    flag.strip_prefix('-')
+        .is_some_and(|vs| !vs.is_empty() && vs.bytes().all(|b| b == b'V'))
+}
+
+#[cfg(test)]
+mod tests {
+    fn translate(argv: &[&str]) -> Result<Vec<String>, String> {
+        let argv = argv.iter().map(Into::into).collect();
+        super::translate(argv).map(|argv| {
+            argv.into_iter().map(|a| a.into_string().unwrap()).collect()
+        })
+    }
+
+    #[test]
+    fn test_translate() {
+        // Outside compatibility mode, nothing changes.
+        assert_eq!(
+            translate(&["ebeans", "-V", "-d"]).unwrap(),
+            ["ebeans", "-V", "-d"]
+        );
+
+        assert_eq!(
+            translate(&["/usr/bin/beanstalkd", "-l", "::1", "-V", "-z", "9"])
+                .unwrap(),
+            [
+                "/usr/bin/beanstalkd",
+                "-l",
+                "::1",
+                "-z",
+                "9",
+                "--log-level=debug"
+            ]
+        );
+        assert_eq!(
+            translate(&["ebeans", "-V", "--beanstalkd-compat", "-VV"]).unwrap(),
+            ["ebeans", "--beanstalkd-compat", "--log-level=trace"]
+        );
+        assert_eq!(
+            translate(&["beanstalkd", "-V", "--log-level=warn"]).unwrap(),
+            ["beanstalkd", "--log-level=warn"]
+        );
+
+        assert!(translate(&["beanstalkd", "-d"]).is_err());
+    }
+}
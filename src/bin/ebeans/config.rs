@@ -0,0 +1,365 @@
+//! Loads settings from a TOML or YAML config file given with `--config`, with
+//! any flags on the command line taking precedence.
+//!
+//! Settings are named after their long flags, with underscores in place of
+//! hyphens, and are validated in the same way as flags:
+//!
+//! ```toml
+//! port = 11300
+//! unix_socket = "/run/ebeans.sock"
+//! max_job_size = 1048576
+//! access_log = "/var/log/ebeans/access.log"
+//! log_level = "info"
+//!
+//! # As in the file given by --auth-config.
+//! [auth]
+//! default_role = "legacy"
+//! # ...
+//!
+//! # Settings for individual tubes.
+//! [tubes.emails]
+//! max_job_size = 65535
+//! min_ttr = 30
+//! ```
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, Command, CommandFactory, FromArgMatches, Parser};
+use enchanted_beans::auth::AuthConfig;
+use enchanted_beans::parser::parse_name;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::args::Args;
+use crate::compat;
+
+/// Settings for a single tube, overriding the server-wide ones.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TubeConfig {
+    /// The largest job accepted into the tube, in place of `max_job_size`.
+    pub(crate) max_job_size: Option<u32>,
+    /// The smallest TTR given to jobs put into the tube. Jobs put with a
+    /// smaller TTR are given this one instead.
+    pub(crate) min_ttr: Option<u32>,
+}
+
+/// The contents of a config file.
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    auth: Option<AuthConfig>,
+    #[serde(default)]
+    tubes: BTreeMap<String, TubeConfig>,
+    /// Everything else, which should correspond to flags.
+    #[serde(flatten)]
+    settings: BTreeMap<String, Value>,
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => {
+                toml::from_str(&contents).map_err(|e| e.to_string())
+            },
+            Some("yaml" | "yml") => {
+                serde_yaml::from_str(&contents).map_err(|e| e.to_string())
+            },
+            _ => Err("expected a .toml, .yaml, or .yml file".into()),
+        }
+    }
+}
+
+/// Parses the command line, along with any config file it gives.
+pub(crate) fn load() -> Result<Args, clap::Error> {
+    load_from(std::env::args_os().collect())
+}
+
+fn load_from(argv: Vec<OsString>) -> Result<Args, clap::Error> {
+    let mut cmd = Args::command();
+    let argv = compat::translate(argv)
+        .map_err(|e| cmd.error(ErrorKind::ArgumentConflict, e))?;
+
+    // Find the config file, and which settings the command line overrides,
+    // without yet requiring the command line to be valid by itself.
+    let Ok(cli) = cmd.clone().ignore_errors(true).try_get_matches_from(&argv)
+    else {
+        return Args::try_parse_from(argv);
+    };
+    let Some(path) = cli.get_one::<std::path::PathBuf>("config") else {
+        return Args::try_parse_from(argv);
+    };
+    let from_cli =
+        |id: &str| cli.value_source(id) == Some(ValueSource::CommandLine);
+
+    let error = |cmd: &mut Command, e: String| {
+        cmd.error(
+            ErrorKind::ValueValidation,
+            format!("{}: {e}", path.display()),
+        )
+    };
+
+    let file = ConfigFile::read(path).map_err(|e| error(&mut cmd, e))?;
+
+    // Settings from the file are given to clap as flags ahead of those on the
+    // command line, so they're parsed and validated in exactly the same way.
+    let mut full_argv = argv[..1].to_vec();
+    for (key, value) in &file.settings {
+        let flag = key.replace('_', "-");
+        let arg = cmd
+            .get_arguments()
+            .find(|a| {
+                a.get_long() == Some(&flag)
+                    && !["beanstalkd-compat", "config", "version"]
+                        .contains(&flag.as_str())
+            })
+            .ok_or_else(|| format!("unknown setting: {key}"));
+        let arg = arg.map_err(|e| error(&mut cmd.clone(), e))?;
+
+        if from_cli(arg.get_id().as_str()) {
+            continue;
+        }
+
+        let values = match value {
+            Value::Array(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        if values.len() > 1 && !matches!(arg.get_action(), ArgAction::Append) {
+            return Err(error(&mut cmd, format!("{key} takes a single value")));
+        }
+
+        for value in values {
+            let value = match (arg.get_action(), value) {
+                (ArgAction::SetTrue, Value::Bool(true)) => None,
+                (ArgAction::SetTrue, Value::Bool(false)) => continue,
+                (ArgAction::SetTrue, _) => {
+                    return Err(error(
+                        &mut cmd,
+                        format!("{key} must be a boolean"),
+                    ))
+                },
+                (_, Value::String(s)) => Some(s.clone()),
+                (_, Value::Number(n)) => Some(n.to_string()),
+                (_, Value::Bool(b)) => Some(b.to_string()),
+                _ => {
+                    return Err(error(
+                        &mut cmd,
+                        format!("invalid value for {key}"),
+                    ))
+                },
+            };
+
+            full_argv.push(match value {
+                Some(value) => format!("--{flag}={value}").into(),
+                None => format!("--{flag}").into(),
+            });
+        }
+    }
+    full_argv.extend_from_slice(&argv[1..]);
+
+    let matches = cmd.clone().try_get_matches_from(full_argv)?;
+    let mut args = Args::from_arg_matches(&matches)?;
+
+    // An auth config on the command line replaces any in the file.
+    if let Some(auth) = file.auth {
+        if args.auth_config.is_none() {
+            auth.validate()
+                .map_err(|e| error(&mut cmd, format!("auth: {e}")))?;
+            args.auth = Some(auth);
+        } else if !from_cli("auth_config") {
+            return Err(error(
+                &mut cmd,
+                "auth and auth_config can't both be set".into(),
+            ));
+        }
+    }
+
+    for (name, tube) in file.tubes {
+        let name = parse_name(name.as_bytes()).map_err(|_| {
+            error(&mut cmd, format!("invalid tube name in tubes: {name}"))
+        })?;
+        args.tubes.insert(name, tube);
+    }
+
+    Ok(args)
+}
+
+/// Returns the names of any settings that differ in `new` but are only read
+/// at startup, so can't be changed by reloading the config.
+pub(crate) fn immutable_changes(old: &Args, new: &Args) -> Vec<&'static str> {
+    let settings = [
+        ("listen", old.listen != new.listen),
+        ("port", old.port != new.port),
+        ("no_tcp", old.no_tcp != new.no_tcp),
+        ("unix_socket", old.unix_socket != new.unix_socket),
+        (
+            "unix_socket_mode",
+            old.unix_socket_mode != new.unix_socket_mode,
+        ),
+        ("tls_listen", old.tls_listen != new.tls_listen),
+        ("handoff_socket", old.handoff_socket != new.handoff_socket),
+        ("wal_dir", old.wal_dir != new.wal_dir),
+        ("fsync_period", old.fsync_period != new.fsync_period),
+        ("no_fsync", old.no_fsync != new.no_fsync),
+        ("wal_file_size", old.wal_file_size != new.wal_file_size),
+        ("sqlite_db", old.sqlite_db != new.sqlite_db),
+        ("user", old.user != new.user),
+        ("group", old.group != new.group),
+        ("allow_root", old.allow_root != new.allow_root),
+        ("threads", old.threads != new.threads),
+        (
+            "shutdown_timeout",
+            old.shutdown_timeout != new.shutdown_timeout,
+        ),
+        ("admin_listen", old.admin_listen != new.admin_listen),
+        ("event_buffer", old.event_buffer != new.event_buffer),
+        ("access_log", old.access_log != new.access_log),
+        (
+            "access_log_max_size",
+            old.access_log_max_size != new.access_log_max_size,
+        ),
+        (
+            "access_log_max_files",
+            old.access_log_max_files != new.access_log_max_files,
+        ),
+        ("debug", old.debug != new.debug),
+    ];
+
+    settings
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Writes a config file into a directory unique to `test`.
+    fn write(test: &str, name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("ebeans-test-{test}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn load(path: &Path, flags: &[&str]) -> Result<Args, clap::Error> {
+        let mut argv: Vec<OsString> = vec!["ebeans".into(), "--config".into()];
+        argv.push(path.into());
+        argv.extend(flags.iter().map(Into::into));
+        load_from(argv)
+    }
+
+    #[test]
+    fn test_load() {
+        let toml = write(
+            "load",
+            "ebeans.toml",
+            r#"
+port = 11301
+debug = true
+max_job_size = 100
+unix_socket = "/tmp/ebeans.sock"
+no_tcp = true
+
+[tubes.emails]
+min_ttr = 30
+"#,
+        );
+
+        let args = load(&toml, &[]).unwrap();
+        assert_eq!(args.port, 11301);
+        assert!(args.debug);
+        assert!(args.no_tcp);
+        assert_eq!(args.max_job_size, 100);
+        assert_eq!(
+            args.tubes[&b"emails"[..]],
+            TubeConfig {
+                max_job_size: None,
+                min_ttr: Some(30),
+            }
+        );
+
+        // Flags override the file.
+        let args = load(&toml, &["-p", "11302", "--max-job-size=200"]).unwrap();
+        assert_eq!(args.port, 11302);
+        assert_eq!(args.max_job_size, 200);
+
+        let yaml = write(
+            "load",
+            "ebeans.yaml",
+            "port: 11303\nauth:\n  roles: {r: []}\n  default_role: r\n",
+        );
+        let args = load(&yaml, &[]).unwrap();
+        assert_eq!(args.port, 11303);
+        assert!(args.auth.is_some());
+
+        // An auth config file given as a flag replaces the inline config.
+        let args = load(&yaml, &["--auth-config", "/etc/auth.yaml"]).unwrap();
+        assert!(args.auth.is_none());
+
+        fs::remove_dir_all(toml.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_immutable_changes() {
+        let path = write("immutable", "ebeans.toml", "port = 11301");
+        let old = load(&path, &[]).unwrap();
+
+        let new = load(&path, &["-z", "10", "--log-level=warn"]).unwrap();
+        assert!(immutable_changes(&old, &new).is_empty());
+
+        let new = load(&path, &["-p", "11302", "-b", "/tmp"]).unwrap();
+        assert_eq!(immutable_changes(&old, &new), ["port", "wal_dir"]);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let error = |name: &str, contents: &str| {
+            let path = write("load-errors", name, contents);
+            load(&path, &[]).unwrap_err().to_string()
+        };
+
+        assert!(error("a.toml", "prot = 1").contains("unknown setting: prot"));
+        assert!(error("b.toml", "port = 100000").contains("--port"));
+        assert!(
+            error("c.toml", "debug = 1").contains("debug must be a boolean")
+        );
+        assert!(error("d.toml", "port = [1, 2]").contains("single value"));
+        assert!(
+            error("e.toml", "config = 'x.toml'").contains("unknown setting")
+        );
+        assert!(error("f.toml", "port = ").contains("f.toml"));
+        assert!(error("g.json", "{}").contains("expected a .toml"));
+        assert!(
+            error("h.yaml", "tubes: {-bad: {}}").contains("invalid tube name")
+        );
+        assert!(error("i.yaml", "auth: {default_role: r}")
+            .contains("undefined role"));
+        assert!(
+            error("j.yaml", "tubes: {a: {max: 1}}").contains("unknown field")
+        );
+        assert!(error("k.yaml", "auth_config: a.yaml\nauth: {}")
+            .contains("can't both be set"));
+
+        fs::remove_dir_all(
+            std::env::temp_dir().join(format!(
+                "ebeans-test-load-errors-{}",
+                std::process::id()
+            )),
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+//! Takes over listening sockets from systemd socket activation, or from a
+//! running ebeans process, so the server can be restarted without refusing
+//! any connections.
+//!
+//! For restarts, a process started with `--handoff-socket` listens on that
+//! Unix socket once it's serving. A new process started with the same flag
+//! connects to it and is sent every listening socket, along with its name.
+//! Once the new process is listening on them it acknowledges, and the old
+//! process stops accepting connections, closes each of its connections once
+//! it's idle, and exits.
+use std::env;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::cmsg_space;
+use nix::sys::socket::{
+    recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags, UnixAddr,
+};
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::listener::{Listener, UnixSocket};
+
+/// The first file descriptor passed by systemd.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// The most sockets that can be handed over at once.
+const MAX_SOCKETS: usize = 32;
+
+/// How long either process waits for the other during a handoff.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A listening socket taken over from systemd or another process.
+#[derive(Debug)]
+pub(crate) struct InheritedSocket {
+    /// The socket's name, such as `tls`, or empty if it wasn't given one.
+    pub(crate) name: String,
+    pub(crate) fd: OwnedFd,
+}
+
+/// An inherited socket, ready to accept connections.
+pub(crate) enum Inherited {
+    Tcp(TcpListener),
+    Unix(UnixSocket),
+}
+
+impl InheritedSocket {
+    /// Works out what kind of socket this is, returning a listener for it.
+    pub(crate) fn into_listener(self) -> io::Result<Inherited> {
+        // Each kind of listener checks the socket's address family.
+        let listener = std::net::TcpListener::from(self.fd);
+        if listener.local_addr().is_ok() {
+            listener.set_nonblocking(true)?;
+            return Ok(Inherited::Tcp(TcpListener::from_std(listener)?));
+        }
+
+        let listener = StdUnixListener::from(OwnedFd::from(listener));
+        listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        Ok(Inherited::Unix(UnixSocket::from_std(listener)?))
+    }
+}
+
+/// Takes any sockets passed by systemd socket activation, named as in
+/// `LISTEN_FDNAMES`.
+///
+/// The variables describing them are removed from the environment, so
+/// they're not passed on to any child processes. This must be called before
+/// any other threads are started.
+pub(crate) fn from_systemd() -> io::Result<Vec<InheritedSocket>> {
+    let [pid, count, names] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"]
+        .map(|name| {
+            let value = env::var(name);
+            env::remove_var(name);
+            value
+        });
+
+    // Sockets are only meant for the process systemd started, and not any
+    // that it then starts itself.
+    let (Ok(pid), Ok(count)) = (pid, count) else {
+        return Ok(vec![]);
+    };
+    if pid.parse() != Ok(std::process::id()) {
+        return Ok(vec![]);
+    }
+
+    let count: RawFd = count.parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "invalid LISTEN_FDS")
+    })?;
+    let names = names.unwrap_or_default();
+    let mut names = names.split(':');
+
+    Ok((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| InheritedSocket {
+            name: names.next().unwrap_or_default().into(),
+            // SAFETY: systemd passes ownership of these descriptors to this
+            // process, and nothing else here uses them.
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+        .collect())
+}
+
+/// Asks any process listening on the handoff socket at `path` to hand over
+/// its listening sockets, returning them along with the connection to that
+/// process, which should be passed to `listen` once they're in use.
+///
+/// Returns `None` if there's no process to take over from.
+pub(crate) fn request(
+    path: &Path,
+) -> io::Result<Option<(Vec<InheritedSocket>, UnixStream)>> {
+    let conn = match UnixStream::connect(path) {
+        Ok(conn) => conn,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None)
+        },
+        Err(e) => return Err(e),
+    };
+    conn.set_read_timeout(Some(TIMEOUT))?;
+
+    let mut buf = [0; 4096];
+    let mut cmsg_buf = cmsg_space!([RawFd; MAX_SOCKETS]);
+    let (len, fds) = {
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let msg = recvmsg::<UnixAddr>(
+            conn.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buf),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+
+        let mut fds = vec![];
+        for cmsg in msg.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                // SAFETY: the descriptors were just received, so nothing
+                // else owns them.
+                fds.extend(
+                    received
+                        .into_iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                );
+            }
+        }
+        (msg.bytes, fds)
+    };
+
+    let names: Vec<String> = serde_json::from_slice(&buf[..len])?;
+    if names.len() != fds.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "handoff names don't match the sockets sent",
+        ));
+    }
+
+    let sockets = names
+        .into_iter()
+        .zip(fds)
+        .map(|(name, fd)| InheritedSocket { name, fd })
+        .collect();
+
+    Ok(Some((sockets, conn)))
+}
+
+/// Tells the previous process that its sockets are in use, then waits for
+/// it to stop listening for handoffs. Blocks.
+fn complete(mut conn: UnixStream) -> io::Result<()> {
+    conn.write_all(b"\n")?;
+
+    // The previous process closes the connection once it's stopped listening
+    // on the handoff socket.
+    let mut buf = [0];
+    match conn.read(&mut buf)? {
+        0 => Ok(()),
+        _ => Err(io::ErrorKind::InvalidData.into()),
+    }
+}
+
+/// Completes any handoff from the `previous` process, then listens for
+/// handoffs on `path`. Once `sockets` have been handed over, `closing` is
+/// cancelled.
+pub(crate) async fn listen(
+    path: PathBuf,
+    previous: Option<UnixStream>,
+    sockets: Vec<InheritedSocket>,
+    closing: CancellationToken,
+) -> io::Result<()> {
+    if let Some(conn) = previous {
+        tokio::task::spawn_blocking(move || complete(conn)).await??;
+        info!(count = sockets.len(), "took over listening sockets");
+    }
+
+    // Only the same user should be able to take over the sockets.
+    let socket = UnixSocket::bind(path, Some(0o600))?;
+
+    select! {
+        x = serve(socket, sockets) => x?,
+        _ = closing.cancelled() => return Ok(()),
+    }
+
+    info!("closing connections once idle");
+    closing.cancel();
+
+    Ok(())
+}
+
+/// Hands over `sockets` to the first process to connect to `socket` and
+/// acknowledge them, returning once it has. Failed handoffs are logged, and
+/// leave this process listening as before.
+async fn serve(
+    socket: UnixSocket,
+    sockets: Vec<InheritedSocket>,
+) -> io::Result<()> {
+    info!(addr = %socket.local_addr()?, "listening for handoffs");
+
+    loop {
+        let (handshake, peer) = match socket.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                warn!(%error, "failed to accept handoff connection");
+                continue;
+            },
+        };
+
+        match hand_over(handshake, &sockets).await {
+            Ok(conn) => {
+                // Stop listening before closing the connection, so the new
+                // process can take over the handoff socket.
+                drop(socket);
+                drop(conn);
+                info!(%peer, "handed over listening sockets");
+                return Ok(());
+            },
+            Err(error) => warn!(%peer, %error, "handoff failed"),
+        }
+    }
+}
+
+/// Sends `sockets` over a connection to the handoff socket, returning the
+/// connection once the other process has acknowledged them.
+async fn hand_over(
+    handshake: <UnixSocket as Listener>::Handshake,
+    sockets: &[InheritedSocket],
+) -> io::Result<UnixStream> {
+    let conn = handshake.await?.0.into_std()?;
+    conn.set_nonblocking(false)?;
+
+    let names: Vec<&str> =
+        sockets.iter().map(|socket| socket.name.as_str()).collect();
+    let fds: Vec<RawFd> =
+        sockets.iter().map(|socket| socket.fd.as_raw_fd()).collect();
+    let payload = serde_json::to_vec(&names)?;
+
+    tokio::task::spawn_blocking(move || {
+        conn.set_read_timeout(Some(TIMEOUT))?;
+        sendmsg::<UnixAddr>(
+            conn.as_raw_fd(),
+            &[IoSlice::new(&payload)],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )?;
+
+        let mut buf = [0];
+        match (&conn).read(&mut buf)? {
+            1 => Ok(conn),
+            _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        }
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsFd;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handoff() {
+        let dir = std::env::temp_dir()
+            .join(format!("ebeans-test-handoff-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("handoff.sock");
+
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unix = UnixSocket::bind(dir.join("unix.sock"), None).unwrap();
+        let sockets = vec![
+            InheritedSocket {
+                name: "tcp".into(),
+                fd: tcp.as_fd().try_clone_to_owned().unwrap(),
+            },
+            InheritedSocket {
+                name: "unix".into(),
+                fd: unix.as_fd().try_clone_to_owned().unwrap(),
+            },
+        ];
+
+        let closing = CancellationToken::new();
+        let old =
+            tokio::spawn(listen(path.clone(), None, sockets, closing.clone()));
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+
+        // Peers that disconnect without taking the sockets don't stop others
+        // from taking them.
+        drop(UnixStream::connect(&path).unwrap());
+
+        let (sockets, conn) = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || request(&path)
+        })
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+        assert!(!closing.is_cancelled());
+
+        let mut sockets = sockets.into_iter();
+        let socket = sockets.next().unwrap();
+        assert_eq!(socket.name, "tcp");
+        let Ok(Inherited::Tcp(listener)) = socket.into_listener() else {
+            panic!("expected a TCP listener");
+        };
+        assert_eq!(listener.local_addr().unwrap(), tcp.local_addr().unwrap());
+
+        let socket = sockets.next().unwrap();
+        assert_eq!(socket.name, "unix");
+        let Ok(Inherited::Unix(listener)) = socket.into_listener() else {
+            panic!("expected a Unix listener");
+        };
+        assert_eq!(
+            listener.local_addr().unwrap(),
+            format!("unix:{}", dir.join("unix.sock").display())
+        );
+
+        // Taking over the handoff socket completes the handoff.
+        let new = tokio::spawn(listen(
+            path.clone(),
+            Some(conn),
+            vec![],
+            CancellationToken::new(),
+        ));
+        old.await.unwrap().unwrap();
+        assert!(closing.is_cancelled());
+
+        new.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_from_systemd() {
+        env::set_var("LISTEN_PID", std::process::id().to_string());
+        env::set_var("LISTEN_FDS", "0");
+        env::set_var("LISTEN_FDNAMES", "");
+        assert!(from_systemd().unwrap().is_empty());
+
+        // The variables aren't left for child processes to misread.
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            assert!(env::var_os(name).is_none(), "{name} is still set");
+        }
+
+        env::set_var("LISTEN_PID", std::process::id().to_string());
+        env::set_var("LISTEN_FDS", "many");
+        assert!(from_systemd().is_err());
+        assert!(env::var_os("LISTEN_FDS").is_none());
+    }
+}
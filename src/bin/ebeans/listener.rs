@@ -0,0 +1,229 @@
+//! Accepts client connections over each supported transport, so the rest of
+//! the server can handle them without caring how they arrived.
+use std::fs;
+use std::future::{ready, Future, Ready};
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+
+use nix::sys::socket::{setsockopt, sockopt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixListener, UnixStream};
+
+/// A source of client connections.
+pub(crate) trait Listener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+    /// Completes any handshake needed before a connection can be used,
+    /// returning the connection along with the client's identity, if it
+    /// authenticated as part of the handshake.
+    type Handshake: Future<Output = io::Result<(Self::Stream, Option<String>)>>
+        + Send
+        + 'static;
+
+    /// Accepts a connection, returning its handshake along with a description
+    /// of the peer for logging. Handshakes are left to the connection's own
+    /// task, so slow clients can't hold up others from connecting.
+    fn accept(
+        &self,
+    ) -> impl Future<Output = io::Result<(Self::Handshake, String)>> + Send;
+
+    /// Describes the address being listened on, for logging.
+    fn local_addr(&self) -> io::Result<String>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+    type Handshake = Ready<io::Result<(TcpStream, Option<String>)>>;
+
+    async fn accept(&self) -> io::Result<(Self::Handshake, String)> {
+        let (conn, peer) = TcpListener::accept(self).await?;
+        conn.set_nodelay(true)?;
+        Ok((ready(Ok((conn, None))), peer.to_string()))
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        TcpListener::local_addr(self).map(|addr| addr.to_string())
+    }
+}
+
+/// Binds a TCP listener to `addr`. IPv6 listeners accept IPv4 connections
+/// too, unless `v6_only` is set, such as when an IPv4 listener shares the
+/// port.
+pub(crate) fn bind_tcp(
+    addr: SocketAddr,
+    v6_only: bool,
+) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            setsockopt(&socket, sockopt::Ipv6V6Only, &v6_only)?;
+            socket
+        },
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// A listening Unix domain socket, removed from the filesystem when dropped
+/// if this process created it.
+pub(crate) struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    remove_on_drop: bool,
+}
+
+impl UnixSocket {
+    /// Binds a socket at `path`, optionally setting its permissions to
+    /// `mode`.
+    ///
+    /// A socket left behind at `path` by a server that's no longer running is
+    /// replaced, but binding fails if another server is still listening on
+    /// it, or if `path` is anything other than a socket.
+    pub(crate) fn bind(path: PathBuf, mode: Option<u32>) -> io::Result<Self> {
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "path exists and is not a socket",
+                ));
+            }
+            if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                return Err(io::ErrorKind::AddrInUse.into());
+            }
+            fs::remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        let socket = Self {
+            listener,
+            path,
+            remove_on_drop: true,
+        };
+
+        if let Some(mode) = mode {
+            fs::set_permissions(
+                &socket.path,
+                fs::Permissions::from_mode(mode),
+            )?;
+        }
+
+        Ok(socket)
+    }
+
+    /// Wraps a socket bound elsewhere, such as by systemd, which is left in
+    /// place when dropped.
+    pub(crate) fn from_std(
+        listener: std::os::unix::net::UnixListener,
+    ) -> io::Result<Self> {
+        let path = listener
+            .local_addr()?
+            .as_pathname()
+            .map(Into::into)
+            .unwrap_or_default();
+
+        Ok(Self {
+            listener: UnixListener::from_std(listener)?,
+            path,
+            remove_on_drop: false,
+        })
+    }
+
+    /// Leaves the socket in place when dropped, such as when another process
+    /// may still be listening on it.
+    pub(crate) fn keep_on_drop(&mut self) {
+        self.remove_on_drop = false;
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if self.remove_on_drop {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl AsFd for UnixSocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
+
+impl Listener for UnixSocket {
+    type Stream = UnixStream;
+    type Handshake = Ready<io::Result<(UnixStream, Option<String>)>>;
+
+    async fn accept(&self) -> io::Result<(Self::Handshake, String)> {
+        let (conn, _) = self.listener.accept().await?;
+
+        // Peers are rarely bound to a path, so describe them by who they are
+        // instead.
+        let peer = match conn.peer_cred() {
+            Ok(cred) => match cred.pid() {
+                Some(pid) => format!("unix:uid={},pid={pid}", cred.uid()),
+                None => format!("unix:uid={}", cred.uid()),
+            },
+            Err(_) => "unix".into(),
+        };
+
+        Ok((ready(Ok((conn, None))), peer))
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        Ok(format!("unix:{}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn bind_err(path: &Path) -> io::ErrorKind {
+        match UnixSocket::bind(path.to_path_buf(), None) {
+            Ok(_) => panic!("bound over an existing path"),
+            Err(error) => error.kind(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let dir = std::env::temp_dir()
+            .join(format!("ebeans-test-unix-socket-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ebeans.sock");
+
+        let mut socket = UnixSocket::bind(path.clone(), Some(0o600)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Sockets still being listened on aren't replaced...
+        assert_eq!(bind_err(&path), io::ErrorKind::AddrInUse);
+
+        // ...but those left behind are.
+        socket.keep_on_drop();
+        drop(socket);
+        assert!(path.exists());
+        let socket = UnixSocket::bind(path.clone(), None).unwrap();
+
+        let client = UnixStream::connect(&path).await.unwrap();
+        let (handshake, peer) = socket.accept().await.unwrap();
+        let (_, identity) = handshake.await.unwrap();
+        assert!(peer.starts_with("unix:uid="), "{peer}");
+        assert_eq!(identity, None);
+        drop(client);
+
+        // Nothing but sockets is ever replaced.
+        drop(socket);
+        assert!(!path.exists());
+        fs::write(&path, "").unwrap();
+        assert_eq!(bind_err(&path), io::ErrorKind::AlreadyExists);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
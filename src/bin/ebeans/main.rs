@@ -1,38 +1,151 @@
+mod access_log;
+mod admin;
 mod args;
+mod commands;
+mod compat;
+mod config;
+mod handoff;
+mod listener;
+mod privileges;
+mod reload;
+mod shutdown;
+mod state;
+mod tls;
 
+use std::future::Future;
+use std::os::fd::AsFd;
 use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
-use clap::Parser;
+use anyhow::{anyhow, Context, Result};
+use enchanted_beans::auth;
+use enchanted_beans::events::{Event, Subscription};
 use enchanted_beans::line_reader::LineReader;
 use enchanted_beans::parser::ParsingError;
-use enchanted_beans::types::protocol::BeanstalkCommand;
+#[cfg(feature = "sqlite")]
+use enchanted_beans::queue::SqliteStore;
+use enchanted_beans::queue::{Queue, SyncPolicy, WalStore};
+use enchanted_beans::types::protocol::{BeanstalkCommand, BeanstalkResponse};
 use enchanted_beans::types::serialisable::BeanstalkSerialisable;
 use enchanted_beans::util::bytes_to_human_str;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
-use tokio::{select, signal};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::signal::unix;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info, instrument, trace, warn, Level};
+use tracing::field::Empty;
+use tracing::{debug, error, info, instrument, trace, warn, Level, Span};
+use tracing_subscriber::filter::{FilterExt, LevelFilter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{filter, Layer};
 
+use crate::access_log::{AccessLogLayer, RotatingFile};
 use crate::args::Args;
+use crate::handoff::{Inherited, InheritedSocket};
+use crate::listener::{Listener, UnixSocket};
+use crate::privileges::Target;
+use crate::reload::Reloader;
+use crate::state::{ServerState, Settings};
+use crate::tls::TlsListener;
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> ExitCode {
-    let args = Args::parse();
+fn main() -> ExitCode {
+    let args = config::load().unwrap_or_else(|e| e.exit());
 
+    // Taken while this is the only thread, as it changes the environment.
+    let systemd = handoff::from_systemd();
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = args.threads {
+        runtime.worker_threads(threads.get());
+    }
+    match runtime.enable_all().build() {
+        Ok(runtime) => runtime.block_on(serve(args, systemd)),
+        Err(error) => {
+            eprintln!("failed to start runtime: {error}");
+            ExitCode::FAILURE
+        },
+    }
+}
+
+async fn serve(
+    args: Args,
+    systemd: io::Result<Vec<handoff::InheritedSocket>>,
+) -> ExitCode {
     // Logging
+    let access_log = match args
+        .access_log
+        .clone()
+        .map(|path| {
+            RotatingFile::open(
+                path,
+                args.access_log_max_size,
+                args.access_log_max_files,
+            )
+        })
+        .transpose()
+    {
+        Ok(file) => file.map(|file| {
+            AccessLogLayer::new(file)
+                .with_filter(filter::filter_fn(access_log::is_access_log))
+        }),
+        Err(error) => {
+            eprintln!("failed to open access log: {error}");
+            return ExitCode::from(2);
+        },
+    };
+
+    // Access log events are kept out of the server's own logs, whose level
+    // can be changed by reloading the config.
+    let (log_level, log_level_handle) = tracing_subscriber::reload::Layer::new(
+        LevelFilter::from_level(args.max_log_level()),
+    );
+    let server_log =
+        filter::filter_fn(|metadata| metadata.target() != access_log::TARGET)
+            .and(log_level);
+    let registry = tracing_subscriber::registry().with(access_log);
     if args.debug {
-        tracing_subscriber::fmt()
-            .with_max_level(Level::TRACE)
+        registry
+            .with(tracing_subscriber::fmt::layer().with_filter(server_log))
             .init();
     } else {
-        tracing_subscriber::fmt().json().init();
+        registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_filter(server_log),
+            )
+            .init();
+    }
+
+    if cfg!(not(feature = "sqlite")) && args.sqlite_db.is_some() {
+        error!("unsupported configuration: built without SQLite support");
+        return ExitCode::from(2);
     }
 
-    if let Some(_wal_dir) = args.wal_dir {
-        error!("unsupported configuration: WAL not yet implemented");
+    // Resolve the user now, so a typo fails before anything's bound.
+    let target = match args
+        .user
+        .as_deref()
+        .map(|user| Target::resolve(user, args.group.as_deref()))
+        .transpose()
+    {
+        Ok(target) => target,
+        Err(error) => {
+            error!(error = format!("{error:#}"), "invalid user");
+            return ExitCode::from(2);
+        },
+    };
+    let runs_as_root = match &target {
+        Some(target) => target.is_root(),
+        None => privileges::is_root(),
+    };
+    if runs_as_root && !args.allow_root {
+        error!("refusing to run as root: use --user, or --allow-root");
         return ExitCode::from(2);
     }
 
@@ -40,77 +153,428 @@ async fn main() -> ExitCode {
     // TODO: this termination channel is a mpsc - so could be used when
     // implementing durability as a stream of events.
     let cancel = CancellationToken::new();
+
+    // Cancelled to stop accepting connections, refuse further reserves, and
+    // close each connection once it's idle and holds no reservations, without
+    // interrupting any commands.
+    let closing = cancel.child_token();
+
     {
+        let closing = closing.clone();
         let cancel = cancel.clone();
+        let timeout = Duration::from_secs(args.shutdown_timeout);
         tokio::spawn(async move {
-            if let Err(error) = signal::ctrl_c().await {
-                warn!(%error, "something strange with ctrl-c handling!");
-            };
-            cancel.cancel();
+            if let Err(error) = shutdown::run(closing, cancel, timeout).await {
+                error!(%error, "failed to handle shutdown signals");
+            }
         });
     }
 
-    let listener = match TcpListener::bind((args.listen, args.port)).await {
-        Ok(l) => l,
+    // Listening sockets inherited from systemd or a previous process are
+    // used in place of any configured.
+    let mut inherited = match systemd {
+        Ok(sockets) => sockets,
         Err(error) => {
-            error!(%error, "failed to listen for connections");
+            error!(%error, "failed to take sockets from systemd");
             return ExitCode::from(111);
         },
     };
+    let mut previous = None;
+    if let (true, Some(path)) = (inherited.is_empty(), &args.handoff_socket) {
+        match handoff::request(path) {
+            Ok(Some((sockets, conn))) => {
+                inherited = sockets;
+                previous = Some(conn);
+            },
+            Ok(None) => {},
+            Err(error) => {
+                error!(%error, "failed to take over listening sockets");
+                return ExitCode::from(111);
+            },
+        }
+    }
 
-    let (shutdown_hold, mut shutdown_wait) = mpsc::channel::<()>(1);
+    // Both are required by clap alongside --tls-listen.
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            match tls::server_config(cert, key, args.tls_client_ca.as_deref()) {
+                Ok(config) => Some(watch::channel(Arc::new(config))),
+                Err(error) => {
+                    error!(
+                        error = format!("{error:#}"),
+                        "invalid TLS configuration"
+                    );
+                    return ExitCode::from(2);
+                },
+            }
+        },
+        _ => None,
+    };
+
+    let mut tcp_listeners = vec![];
+    let mut unix_listeners = vec![];
+    let mut tls_listeners = vec![];
+    if inherited.is_empty() {
+        // IPv6 listeners only accept IPv6 connections if an IPv4 listener
+        // shares their port, so the two can listen on the same port.
+        let addrs = if args.no_tcp {
+            vec![]
+        } else {
+            args.listen_addrs()
+        };
+        for &addr in &addrs {
+            let v6_only = addrs
+                .iter()
+                .any(|other| other.is_ipv4() && other.port() == addr.port());
+            match listener::bind_tcp(addr, v6_only) {
+                Ok(l) => tcp_listeners.push(l),
+                Err(error) => {
+                    error!(%addr, %error, "failed to listen for connections");
+                    return ExitCode::from(111);
+                },
+            }
+        }
+
+        if let Some(path) = &args.unix_socket {
+            match UnixSocket::bind(path.clone(), args.unix_socket_mode) {
+                Ok(l) => unix_listeners.push(l),
+                Err(error) => {
+                    error!(%error, "failed to listen on Unix socket");
+                    return ExitCode::from(111);
+                },
+            }
+        }
+
+        if let (Some(addr), Some((_, config))) = (args.tls_listen, &tls_config)
+        {
+            match TlsListener::bind(addr, config.clone()).await {
+                Ok(l) => tls_listeners.push(l),
+                Err(error) => {
+                    error!(%error, "failed to listen for TLS connections");
+                    return ExitCode::from(111);
+                },
+            }
+        }
+    } else {
+        // TCP sockets are served with TLS if named "tls", or if they're bound
+        // to the TLS listener's address.
+        for socket in inherited {
+            let named_tls = socket.name == "tls";
+            match socket.into_listener() {
+                Ok(Inherited::Tcp(l))
+                    if named_tls || l.local_addr().ok() == args.tls_listen =>
+                {
+                    let Some((_, config)) = &tls_config else {
+                        error!("inherited a TLS socket without --tls-listen");
+                        return ExitCode::from(2);
+                    };
+                    tls_listeners.push(TlsListener::new(l, config.clone()));
+                },
+                Ok(Inherited::Tcp(l)) => tcp_listeners.push(l),
+                Ok(Inherited::Unix(l)) => unix_listeners.push(l),
+                Err(error) => {
+                    error!(%error, "failed to use inherited socket");
+                    return ExitCode::from(111);
+                },
+            }
+        }
+    }
+
+    // Copies of each listening socket, to hand over to a new process.
+    let handoff_sockets = match args.handoff_socket.as_ref().map(|_| {
+        let tcp = tcp_listeners.iter().map(|l| ("tcp", l.as_fd()));
+        let unix = unix_listeners.iter().map(|l| ("unix", l.as_fd()));
+        let tls = tls_listeners.iter().map(|l| ("tls", l.as_fd()));
+        tcp.chain(unix)
+            .chain(tls)
+            .map(|(name, fd)| {
+                Ok(InheritedSocket {
+                    name: name.into(),
+                    fd: fd.try_clone_to_owned()?,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()
+    }) {
+        None => None,
+        Some(Ok(sockets)) => {
+            // Once handed over, they're still in use.
+            for l in &mut unix_listeners {
+                l.keep_on_drop();
+            }
+            Some(sockets)
+        },
+        Some(Err(error)) => {
+            error!(%error, "failed to copy listening sockets");
+            return ExitCode::from(111);
+        },
+    };
 
-    let exit_code = match begin(cancel, shutdown_hold, listener).await {
-        Ok(()) => ExitCode::SUCCESS,
+    let settings = match Settings::load(&args) {
+        Ok(settings) => settings,
         Err(error) => {
-            error!(%error, "encountered runtime error");
-            ExitCode::FAILURE
+            error!(error = format!("{error:#}"), "invalid auth config");
+            return ExitCode::from(2);
+        },
+    };
+
+    let admin_listener = match args.admin_listen {
+        Some(addr) => match TcpListener::bind(addr).await {
+            Ok(l) => Some(l),
+            Err(error) => {
+                error!(%error, "failed to listen for admin connections");
+                return ExitCode::from(111);
+            },
+        },
+        None => None,
+    };
+
+    // Everything's bound, so root is no longer needed.
+    if let Some(target) = &target {
+        if let Err(error) = target.switch() {
+            error!(error = format!("{error:#}"), "failed to drop privileges");
+            return ExitCode::from(111);
+        }
+        info!(user = args.user, group = args.group, "dropped privileges");
+    }
+
+    let queue = match open_queue(&args) {
+        Ok(queue) => queue,
+        Err(error) => {
+            error!(error = format!("{error:#}"), "failed to recover jobs");
+            return ExitCode::from(111);
         },
     };
+    let state = Arc::new(ServerState::new(settings, queue));
+
+    if let Some(admin_listener) = admin_listener {
+        let cancel = cancel.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(error) =
+                admin::serve(cancel, state, admin_listener).await
+            {
+                error!(%error, "admin API failed");
+            }
+        });
+    }
+
+    {
+        let cancel = cancel.clone();
+        let reloader = Reloader {
+            args: args.clone(),
+            state: state.clone(),
+            set_log_level: Box::new(move |level| {
+                Ok(log_level_handle.reload(LevelFilter::from_level(level))?)
+            }),
+            tls_config: tls_config.map(|(sender, _)| sender),
+        };
+        tokio::spawn(async move {
+            if let Err(error) = reloader.run(cancel).await {
+                error!(%error, "failed to handle SIGHUP");
+            }
+        });
+    }
+
+    // As with beanstalkd, SIGUSR1 enters drain mode, which lasts until it's
+    // left through the admin API.
+    {
+        let cancel = cancel.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut signals =
+                match unix::signal(unix::SignalKind::user_defined1()) {
+                    Ok(signals) => signals,
+                    Err(error) => {
+                        error!(%error, "failed to handle SIGUSR1");
+                        return;
+                    },
+                };
+            loop {
+                select! {
+                    Some(()) = signals.recv() => state.set_draining(true),
+                    _ = cancel.cancelled() => return,
+                };
+            }
+        });
+    }
+
+    let (shutdown_hold, mut shutdown_wait) = mpsc::channel::<()>(1);
+
+    let mut listeners = JoinSet::new();
+    for listener in tcp_listeners {
+        listeners.spawn(begin(
+            cancel.clone(),
+            closing.clone(),
+            state.clone(),
+            shutdown_hold.clone(),
+            listener,
+        ));
+    }
+    for listener in unix_listeners {
+        listeners.spawn(begin(
+            cancel.clone(),
+            closing.clone(),
+            state.clone(),
+            shutdown_hold.clone(),
+            listener,
+        ));
+    }
+    for listener in tls_listeners {
+        listeners.spawn(begin(
+            cancel.clone(),
+            closing.clone(),
+            state.clone(),
+            shutdown_hold.clone(),
+            listener,
+        ));
+    }
+    drop(shutdown_hold);
+
+    if let (Some(path), Some(sockets)) = (args.handoff_socket, handoff_sockets)
+    {
+        let closing = closing.clone();
+        tokio::spawn(async move {
+            if let Err(error) =
+                handoff::listen(path, previous, sockets, closing).await
+            {
+                error!(%error, "failed to listen for handoffs");
+            }
+        });
+    }
+
+    // If any listener fails, stop the others too.
+    let mut exit_code = ExitCode::SUCCESS;
+    while let Some(ret) = listeners.join_next().await {
+        if let Err(error) = ret.map_err(anyhow::Error::from).and_then(|r| r) {
+            error!(%error, "encountered runtime error");
+            exit_code = ExitCode::FAILURE;
+            cancel.cancel();
+        }
+    }
 
     shutdown_wait.recv().await;
 
+    // Every connection's closed, so nothing more will change. Checkpointing
+    // the store means the next start needn't replay its log.
+    state.queue.checkpoint().await;
+    info!("shut down");
+
     exit_code
 }
 
-async fn begin(
+/// Creates the queue, recovering its jobs from the configured store.
+fn open_queue(args: &Args) -> Result<Queue> {
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &args.sqlite_db {
+        let store = SqliteStore::open(path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        let queue = Queue::with_store(args.event_buffer, store)?;
+        info!(path = %path.display(), "recovered jobs from SQLite");
+        return Ok(queue);
+    }
+
+    if let Some(dir) = &args.wal_dir {
+        let sync = match (args.no_fsync, args.fsync_period) {
+            (true, _) => SyncPolicy::Never,
+            (false, period) => {
+                SyncPolicy::Every(Duration::from_millis(period.unwrap_or(0)))
+            },
+        };
+        let store = WalStore::open(dir, args.wal_file_size, sync)
+            .with_context(|| format!("opening {}", dir.display()))?;
+        let queue = Queue::with_store(args.event_buffer, store)?;
+        info!(path = %dir.display(), "recovered jobs from WAL");
+        return Ok(queue);
+    }
+
+    Ok(Queue::new(args.event_buffer))
+}
+
+async fn begin<L: Listener>(
     cancel: CancellationToken,
+    closing: CancellationToken,
+    state: Arc<ServerState>,
     shutdown_hold: mpsc::Sender<()>,
-    listener: TcpListener,
+    listener: L,
 ) -> Result<()> {
-    info!(addr = %listener.local_addr()?, "listening");
+    // Listeners are labelled by their addresses in logs and metrics.
+    let label: Arc<str> = listener.local_addr()?.into();
+    state
+        .listeners
+        .lock()
+        .unwrap()
+        .entry(label.to_string())
+        .or_default();
+    info!(listener = %label, "listening");
 
     // Accept incoming connections until an exit signal is sent, and handle each
     // connection as its own task.
     loop {
-        let conn = match select! {
+        let (handshake, peer) = match select! {
             accept = listener.accept() => accept,
-            _ = cancel.cancelled() => break,
+            _ = closing.cancelled() => break,
         } {
-            Ok((conn, _)) => conn,
+            Ok(accepted) => accepted,
             Err(error) => {
-                warn!(%error, "failed to accept connection");
+                warn!(listener = %label, %error, "failed to accept connection");
                 continue;
             },
         };
 
-        tokio::spawn(begin_handle(cancel.clone(), shutdown_hold.clone(), conn));
+        tokio::spawn(begin_handle(
+            cancel.clone(),
+            closing.clone(),
+            state.clone(),
+            shutdown_hold.clone(),
+            handshake,
+            label.clone(),
+            peer,
+        ));
     }
 
     Ok(())
 }
 
-#[instrument(name = "handle", err, fields(peer = %conn.peer_addr()?), skip_all)]
-async fn begin_handle(
+#[instrument(
+    name = "handle",
+    err,
+    fields(
+        conn_id = state.next_connection_id(),
+        %listener,
+        %peer,
+        identity = Empty,
+        user = Empty,
+    ),
+    skip_all
+)]
+async fn begin_handle<S, H>(
     cancel: CancellationToken,
+    closing: CancellationToken,
+    state: Arc<ServerState>,
     _shutdown_hold: mpsc::Sender<()>,
-    mut conn: TcpStream,
-) -> Result<()> {
+    handshake: H,
+    listener: Arc<str>,
+    peer: String,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    H: Future<Output = io::Result<(S, Option<String>)>>,
+{
+    let (mut conn, identity) = select! {
+        x = handshake => x.map_err(|e| anyhow!("during handshake: {e}"))?,
+        _ = closing.cancelled() => return Ok(()),
+    };
+    if let Some(identity) = identity {
+        Span::current().record("identity", identity);
+    }
+
     debug!("accepted connection");
 
-    conn.set_nodelay(true).context("setting NODELAY")?;
+    state.connection_opened(&listener);
 
-    let ret = handle_conn(cancel, &mut conn).await;
+    let ret = handle_conn(cancel, closing, &state, &mut conn).await;
+
+    state.connection_closed(&listener);
 
     conn.shutdown().await.context("during shutdown")?;
 
@@ -119,53 +583,547 @@ async fn begin_handle(
     ret
 }
 
-async fn handle_conn(
+async fn handle_conn<S: AsyncRead + AsyncWrite + Unpin>(
     cancel: CancellationToken,
-    conn: &mut TcpStream,
+    closing: CancellationToken,
+    state: &ServerState,
+    conn: &mut S,
 ) -> Result<()> {
     // Split conn into read and write halves, where the read half uses our
     // LineReader.
-    let (r, mut w) = conn.split();
+    let (r, mut w) = io::split(conn);
     let mut r: LineReader<_> = r.into();
 
-    // Keep taking lines and parsing and processing them.
+    // Events this client has subscribed to, if any.
+    let mut subscription = Subscription::default();
+    let mut events: Option<broadcast::Receiver<Event>> = None;
+
+    // This connection's client of the queue, releasing any jobs it reserved
+    // once dropped.
+    let queue = state.queue.connect();
+
+    // The user this client is authenticated as, if any. Roles are looked up
+    // for every command, so changes to the auth config apply to existing
+    // connections as soon as it's reloaded.
+    let mut user: Option<String> = None;
+
+    // Keep taking lines and parsing and processing them, sending any events
+    // subscribed to in between.
     loop {
         let line = select!(
-           x = r.read_line() => match x? {
-                Some(x) => x,
-                None => return Ok(()),
+           x = r.read_line() => match x {
+                Ok(Some(x)) => x,
+                Ok(None) => return Ok(()),
+                // TLS clients often close connections without notifying us
+                // first, which isn't worth treating as an error.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(())
+                },
+                Err(e) => return Err(e.into()),
+           },
+           event = next_event(&mut events) => {
+                let resp = match event {
+                    Ok(event) if subscription.matches(&event) => {
+                        BeanstalkResponse::Event { event }
+                    },
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(count)) => {
+                        BeanstalkResponse::EventsDropped { count }
+                    },
+                    Err(RecvError::Closed) => {
+                        events = None;
+                        continue;
+                    },
+                };
+
+                if !send(&cancel, &mut w, &resp.serialise_beanstalk()).await? {
+                    return Ok(());
+                }
+                continue;
+           },
+           // Connections waiting for a command are idle, so can be closed
+           // once the jobs they've reserved are finished.
+           _ = closing.cancelled(), if !queue.has_reservations() => {
+                return Ok(())
            },
            _ = cancel.cancelled() => return Ok(()),
         );
 
-        trace!(line = bytes_to_human_str(&line), "processing command");
+        // Keep tokens out of the logs.
+        if !line.starts_with(b"auth ") {
+            trace!(line = bytes_to_human_str(&line), "processing command");
+        }
+
+        let started = Instant::now();
+        let settings = state.settings();
 
         let cmd: Result<BeanstalkCommand, ParsingError> =
             (&line as &[u8]).try_into();
 
-        // Slightly convoluted, but ensures we write out the buffer properly
-        // with cancel safety.
-        match cmd {
-            Ok(_cmd) => select! {
-                x = w.write_all(b"CMD_OK\r\n") => x,
-                _ = cancel.cancelled() => return Ok(()),
+        // Any data following the line is read before the command is run, so
+        // a refused command's data isn't taken for the next command.
+        let data = match cmd.as_ref().ok().and_then(BeanstalkCommand::data_len)
+        {
+            None => Ok(None),
+            Some(len) => {
+                let limit = match &cmd {
+                    Ok(BeanstalkCommand::Put { .. }) => settings
+                        .tubes
+                        .get(&queue.used_tube())
+                        .and_then(|tube| tube.max_job_size)
+                        .unwrap_or(settings.max_job_size),
+                    _ => settings.max_job_size,
+                };
+                // Followed by a CRLF.
+                let len = len as usize + 2;
+
+                if len - 2 > limit as usize {
+                    let skipped = select! {
+                        x = r.skip(len) => x?,
+                        _ = cancel.cancelled() => return Ok(()),
+                    };
+                    if !skipped {
+                        return Ok(());
+                    }
+                    Err(BeanstalkResponse::JobTooBig)
+                } else {
+                    let data = select! {
+                        x = r.read_exact(len) => match x? {
+                            Some(data) => data,
+                            None => return Ok(()),
+                        },
+                        _ = cancel.cancelled() => return Ok(()),
+                    };
+                    match data.ends_with(b"\r\n") {
+                        true => Ok(Some(data.slice(..len - 2))),
+                        false => Err(BeanstalkResponse::ExpectedCRLF),
+                    }
+                }
             },
-            Err(error) => {
-                let resp = error.serialise_beanstalk();
-                select! {
-                    x = w.write_all(&resp) => x,
-                    _ = cancel.cancelled() => return Ok(()),
+        };
+
+        let permitted = |cmd: &BeanstalkCommand| match &settings.auth {
+            // Commands needing no permissions, such as `auth`, are open even
+            // to clients without a role.
+            Some(_) if auth::requirements(cmd).is_empty() => true,
+            Some(auth) => auth.role(user.as_deref()).is_some_and(|role| {
+                role.permits(
+                    cmd,
+                    &queue.used_tube(),
+                    || queue.watched_tubes(),
+                    |id| queue.job_tube(id),
+                )
+            }),
+            None => true,
+        };
+
+        let resp = match (&cmd, data) {
+            (Ok(_), Err(resp)) => resp.serialise_beanstalk(),
+            (Ok(cmd), _) if !permitted(cmd) => {
+                BeanstalkResponse::NotPermitted.serialise_beanstalk()
+            },
+            (Ok(BeanstalkCommand::Quit), _) => return Ok(()),
+            (
+                Ok(
+                    BeanstalkCommand::Reserve
+                    | BeanstalkCommand::ReserveWithTimeout { .. }
+                    | BeanstalkCommand::ReserveJob { .. },
+                ),
+                _,
+            ) if closing.is_cancelled() => {
+                BeanstalkResponse::ShuttingDown.serialise_beanstalk()
+            },
+            (Ok(BeanstalkCommand::Auth { user: name, token }), _) => {
+                let name = String::from_utf8_lossy(name);
+                match settings
+                    .auth
+                    .as_ref()
+                    .and_then(|auth| auth.authenticate(name.as_bytes(), token))
+                {
+                    Some(_) => {
+                        Span::current().record("user", &*name);
+                        user = Some(name.into_owned());
+                        BeanstalkResponse::Authenticated.serialise_beanstalk()
+                    },
+                    None => {
+                        warn!(user = %name, "authentication failed");
+                        BeanstalkResponse::NotPermitted.serialise_beanstalk()
+                    },
+                }
+            },
+            (Ok(BeanstalkCommand::StatsLatency), _) => {
+                BeanstalkResponse::OkStatsLatency {
+                    data: state.latencies.lock().unwrap().summary(),
                 }
+                .serialise_beanstalk()
+            },
+            (Ok(BeanstalkCommand::Subscribe { tube }), _) => {
+                subscription.subscribe(tube.clone());
+                events.get_or_insert_with(|| queue.subscribe());
+                BeanstalkResponse::Subscribed.serialise_beanstalk()
+            },
+            (Ok(BeanstalkCommand::SubscribeAll), _) => {
+                subscription.subscribe_all();
+                events.get_or_insert_with(|| queue.subscribe());
+                BeanstalkResponse::Subscribed.serialise_beanstalk()
             },
-        }?;
-
-        // Flush any buffered packets once we've written out the one or more
-        // responses. This provides a pipelined response to a pipelined request.
-        // NB: flush() appears not to be implemented for TCPStreams, but this
-        // should provide forward-compatibility for other transports.
-        select! {
-            x = w.flush() => x?,
-            _ = cancel.cancelled() => return Ok(()),
+            (Ok(BeanstalkCommand::Unsubscribe), _) => {
+                subscription = Subscription::default();
+                events = None;
+                BeanstalkResponse::Unsubscribed.serialise_beanstalk()
+            },
+            (Ok(cmd), Ok(data)) => {
+                commands::execute(&queue, state, &settings, &closing, cmd, data)
+                    .await
+                    .serialise_beanstalk()
+            },
+            (Err(error), _) => error.serialise_beanstalk(),
         };
+
+        if !send(&cancel, &mut w, &resp).await? {
+            return Ok(());
+        }
+
+        let elapsed = started.elapsed();
+
+        // The connection ID and peer address are recorded on the enclosing
+        // span.
+        if tracing::enabled!(target: access_log::TARGET, Level::INFO) {
+            access_log::record(cmd.as_ref().ok(), &resp, elapsed);
+        }
+
+        if let Ok(cmd) = &cmd {
+            state.latencies.lock().unwrap().record(cmd.name(), elapsed);
+
+            if settings.slow_command_threshold.is_some_and(|t| elapsed > t) {
+                warn!(command = cmd.name(), ?elapsed, "slow command");
+            }
+        }
+    }
+}
+
+/// Writes and flushes a response, returning false if cancelled first.
+async fn send(
+    cancel: &CancellationToken,
+    w: &mut (impl AsyncWrite + Unpin),
+    resp: &[u8],
+) -> Result<bool> {
+    // Slightly convoluted, but ensures we write out the buffer properly with
+    // cancel safety.
+    select! {
+        x = w.write_all(resp) => x?,
+        _ = cancel.cancelled() => return Ok(false),
+    };
+
+    // Flush any buffered packets once we've written out the one or more
+    // responses. This provides a pipelined response to a pipelined request.
+    // NB: flush() appears not to be implemented for TCPStreams, but this
+    // should provide forward-compatibility for other transports.
+    select! {
+        x = w.flush() => x?,
+        _ = cancel.cancelled() => return Ok(false),
+    };
+
+    Ok(true)
+}
+
+/// Receives the next event from `events`, or waits forever if there's no
+/// subscription. Cancel-safe.
+async fn next_event(
+    events: &mut Option<broadcast::Receiver<Event>>,
+) -> Result<Event, RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use enchanted_beans::auth::AuthConfig;
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    fn state(args: &[&str]) -> Arc<ServerState> {
+        let args = Args::parse_from([&["ebeans"], args].concat());
+        let settings = Settings::load(&args).unwrap();
+        Arc::new(ServerState::new(settings, Queue::new(16)))
+    }
+
+    /// Returns the default settings, with access control as in `yaml`.
+    fn with_auth(yaml: &str) -> Settings {
+        let mut settings =
+            Settings::load(&Args::parse_from(["ebeans"])).unwrap();
+        settings.auth = Some(AuthConfig::from_yaml(yaml).unwrap());
+        settings
+    }
+
+    /// Opens a connection handled in its own task.
+    fn connect(
+        state: &Arc<ServerState>,
+    ) -> (DuplexStream, JoinHandle<Result<()>>) {
+        let state = state.clone();
+        let (client, mut server) = duplex(4096);
+        let conn = tokio::spawn(async move {
+            let (cancel, closing) =
+                (CancellationToken::new(), CancellationToken::new());
+            handle_conn(cancel, closing, &state, &mut server).await
+        });
+        (client, conn)
+    }
+
+    /// Sends `commands` over a new connection, followed by `quit`, and
+    /// returns everything sent back.
+    async fn converse(state: &Arc<ServerState>, commands: &[u8]) -> String {
+        let (mut client, conn) = connect(state);
+        client.write_all(commands).await.unwrap();
+        client.write_all(b"quit\r\n").await.unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).await.unwrap();
+        conn.await.unwrap().unwrap();
+        responses
+    }
+
+    #[tokio::test]
+    async fn test_handle_conn() {
+        // A job too big is skipped over, leaving the next command intact.
+        let responses = converse(
+            &state(&["--max-job-size=5"]),
+            b"put 0 0 60 6\r\ntoobig\r\n\
+            put 0 0 60 5\r\nhello\r\n\
+            reserve\r\n\
+            delete 1\r\n",
+        )
+        .await;
+        assert_eq!(
+            responses,
+            "JOB_TOO_BIG\r\nINSERTED 1\r\nRESERVED 1 5\r\nhello\r\nDELETED\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_jobs() {
+        let state = state(&[]);
+        for body in [
+            &b"{\"customer_id\": 42}"[..],
+            b"{\"customer_id\": 7}",
+            b"42",
+        ] {
+            state.queue.put(0, 0, 60, body.to_vec()).await.unwrap();
+        }
+
+        // Bodies that aren't JSON objects don't match, rather than failing.
+        let responses = converse(
+            &state,
+            b"query-jobs default ready json 19\r\n$.customer_id == 42\r\n\
+            query-jobs default ready json 13\r\n$.customer_id\r\n\
+            query-jobs default buried json 13\r\n$.customer_id\r\n\
+            query-jobs default ready json 2\r\n$.\r\n",
+        )
+        .await;
+        assert_eq!(
+            responses,
+            "OK 4\r\n- 1\n\r\nOK 8\r\n- 1\n- 2\n\r\nOK 3\r\n[]\n\r\n\
+            BAD_FORMAT\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_scan_limit() {
+        let state = state(&["--query-scan-limit=16"]);
+        for body in [
+            &b"region: eu\nid: 1"[..],
+            b"region: us\nid: 2",
+            b"id: 30\nregion: eu",
+            b"ERROR: disk full",
+        ] {
+            state.queue.put(0, 0, 60, body.to_vec()).await.unwrap();
+        }
+
+        // YAML and regex predicates only see the first 16 bytes of each body,
+        // which cuts the third job's region short.
+        let responses = converse(
+            &state,
+            b"query-jobs default ready yaml 16\r\n$.region == \"eu\"\r\n\
+            query-jobs default ready regex 6\r\n^ERROR\r\n\
+            query-jobs default ready regex 2\r\neu\r\n\
+            query-jobs default ready regex 1\r\n(\r\n",
+        )
+        .await;
+        assert_eq!(
+            responses,
+            "OK 4\r\n- 1\n\r\nOK 4\r\n- 4\n\r\nOK 4\r\n- 1\n\r\n\
+            BAD_FORMAT\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bulk_delete_and_kick() {
+        let state = state(&[]);
+        for body in ["fail", "fail", "ok", "fail"] {
+            state.queue.put(0, 0, 60, body.into()).await.unwrap();
+        }
+        for _ in 0..3 {
+            let job = state.queue.reserve().await.unwrap();
+            state.queue.bury(job.id, 0).await.unwrap();
+        }
+
+        // Dry runs list the jobs without touching them.
+        let responses = converse(
+            &state,
+            b"delete-jobs default buried regex 4 dry-run\r\nfail\r\n\
+            kick-jobs default buried regex 2\r\nok\r\n\
+            delete-jobs default buried regex 4\r\nfail\r\n\
+            query-jobs default ready regex 0\r\n\r\n",
+        )
+        .await;
+        assert_eq!(
+            responses,
+            "OK 22\r\ncount: 2\nids:\n- 1\n- 2\n\r\nKICKED 1\r\nDELETED 2\r\n\
+            OK 8\r\n- 3\n- 4\n\r\n"
+        );
+    }
+
+    /// Reads exactly `expected` from `client`.
+    async fn read_expected(client: &mut DuplexStream, expected: &str) {
+        let mut buf = vec![0; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let state = state(&[]);
+        let (mut client, conn) = connect(&state);
+
+        // Events are streamed only for subscribed tubes.
+        client.write_all(b"subscribe default\r\n").await.unwrap();
+        read_expected(&mut client, "SUBSCRIBED\r\n").await;
+        state.queue.use_tube(b"other").await;
+        state.queue.put(0, 0, 60, vec![]).await.unwrap();
+        state.queue.use_tube(b"default").await;
+        state.queue.put(0, 0, 60, vec![]).await.unwrap();
+        read_expected(
+            &mut client,
+            "EVENT 39\r\n{\"event\":\"put\",\"tube\":\"default\",\"id\":2}\r\n",
+        )
+        .await;
+
+        // Nothing more is sent once unsubscribed.
+        client.write_all(b"unsubscribe\r\n").await.unwrap();
+        read_expected(&mut client, "UNSUBSCRIBED\r\n").await;
+        state.queue.put(0, 0, 60, vec![]).await.unwrap();
+        client.write_all(b"quit\r\n").await.unwrap();
+        let mut rest = String::new();
+        client.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "");
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let state = state(&[]);
+        state.set_settings(with_auth(
+            r#"
+roles: {producer: [{tubes: [default], permissions: [put]}]}
+users:
+  alice:
+    # sha256("secret")
+    token_sha256: 2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b
+    role: producer
+"#,
+        ));
+
+        // Without a default role, clients can only authenticate or quit.
+        let responses = converse(
+            &state,
+            b"put 0 0 60 0\r\n\r\n\
+            auth alice wrong\r\n\
+            auth alice secret\r\n\
+            put 0 0 60 0\r\n\r\n",
+        )
+        .await;
+        assert_eq!(
+            responses,
+            "NOT_PERMITTED\r\nNOT_PERMITTED\r\nAUTHENTICATED\r\nINSERTED 1\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reserve_permissions() {
+        let state = state(&[]);
+        let grant = |permissions: &str| {
+            with_auth(&format!(
+                "default_role: worker\n\
+                roles: {{worker: [{{tubes: [a], permissions: [{permissions}]}}]}}"
+            ))
+        };
+        state.set_settings(grant("put, reserve"));
+        let (mut client, conn) = connect(&state);
+
+        // Clients can only reserve once they've ignored `default`, which they
+        // watch from the start.
+        client
+            .write_all(
+                b"use a\r\nput 0 0 60 0\r\n\r\nreserve-with-timeout 0\r\n",
+            )
+            .await
+            .unwrap();
+        read_expected(
+            &mut client,
+            "USING a\r\nINSERTED 1\r\nNOT_PERMITTED\r\n",
+        )
+        .await;
+        client
+            .write_all(
+                b"watch a\r\nignore default\r\nreserve-with-timeout 0\r\n\
+                release 1 0 0\r\n",
+            )
+            .await
+            .unwrap();
+        read_expected(
+            &mut client,
+            "WATCHING 2\r\nWATCHING 1\r\nRESERVED 1 0\r\n\r\nRELEASED\r\n",
+        )
+        .await;
+
+        // Revoking the permission stops further reserves.
+        state.set_settings(grant("put"));
+        client
+            .write_all(b"reserve-with-timeout 0\r\nquit\r\n")
+            .await
+            .unwrap();
+        let mut rest = String::new();
+        client.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "NOT_PERMITTED\r\n");
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handle_conn_cancelled() {
+        let state = state(&["--max-job-size=5"]);
+        let (mut client, mut server) = duplex(4096);
+        let cancel = CancellationToken::new();
+        let conn = tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                let closing = cancel.child_token();
+                handle_conn(cancel, closing, &state, &mut server).await
+            }
+        });
+
+        // Stopping doesn't wait for the rest of a job too big to arrive.
+        client.write_all(b"put 0 0 60 100\r\nabc").await.unwrap();
+        tokio::task::yield_now().await;
+        cancel.cancel();
+        timeout(Duration::from_secs(5), conn)
+            .await
+            .expect("connection wasn't closed")
+            .unwrap()
+            .unwrap();
     }
 }
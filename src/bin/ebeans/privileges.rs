@@ -0,0 +1,98 @@
+//! Drops root privileges once listening, as with beanstalkd's `-u`, so ports
+//! and socket paths only root can use can still be listened on.
+//!
+//! Privileges are dropped after every listener is bound, and before any
+//! connection is accepted or the job store opened. Files read again on
+//! reload, such as the config and TLS keys, must then be readable by the new
+//! user, and any handoff socket's directory writable by them.
+use std::ffi::CString;
+
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::{self, Gid, Group, Uid, User};
+
+/// The user and group to switch to.
+#[derive(Debug)]
+pub(crate) struct Target {
+    name: CString,
+    uid: Uid,
+    gid: Gid,
+}
+
+impl Target {
+    /// Looks up `user`, and `group` if given, otherwise using the user's
+    /// primary group. Either may be a name or a numeric ID.
+    pub(crate) fn resolve(user: &str, group: Option<&str>) -> Result<Self> {
+        let user = match User::from_name(user)? {
+            Some(u) => u,
+            None => user
+                .parse()
+                .ok()
+                .and_then(|uid| User::from_uid(Uid::from_raw(uid)).transpose())
+                .transpose()?
+                .ok_or_else(|| anyhow!("no such user: {user}"))?,
+        };
+
+        let gid = match group {
+            None => user.gid,
+            Some(group) => match Group::from_name(group)? {
+                Some(g) => g.gid,
+                None => group
+                    .parse()
+                    .map(Gid::from_raw)
+                    .map_err(|_| anyhow!("no such group: {group}"))?,
+            },
+        };
+
+        Ok(Self {
+            name: CString::new(user.name)?,
+            uid: user.uid,
+            gid,
+        })
+    }
+
+    /// Returns whether this is the root user.
+    pub(crate) fn is_root(&self) -> bool {
+        self.uid.is_root()
+    }
+
+    /// Switches the process to this user and group, along with the user's
+    /// supplementary groups, leaving no way back to root.
+    pub(crate) fn switch(&self) -> Result<()> {
+        // Nothing to do, and only root may change groups.
+        if unistd::geteuid() == self.uid && unistd::getegid() == self.gid {
+            return Ok(());
+        }
+
+        // Groups first, while still permitted to change them.
+        unistd::initgroups(&self.name, self.gid)
+            .context("failed to set supplementary groups")?;
+        unistd::setgid(self.gid).context("failed to set group")?;
+        unistd::setuid(self.uid).context("failed to set user")?;
+
+        Ok(())
+    }
+}
+
+/// Returns whether the process is running as root.
+pub(crate) fn is_root() -> bool {
+    unistd::geteuid().is_root()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let root = Target::resolve("root", None).unwrap();
+        assert!(root.is_root());
+        assert_eq!(root.gid, Gid::from_raw(0));
+
+        let by_id = Target::resolve("0", Some("0")).unwrap();
+        assert_eq!(by_id.name.to_str().unwrap(), "root");
+        assert!(by_id.is_root());
+
+        assert!(Target::resolve("ebeans-no-such-user", None).is_err());
+        assert!(Target::resolve("root", Some("ebeans-no-such-group")).is_err());
+    }
+}
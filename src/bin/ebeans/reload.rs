@@ -0,0 +1,99 @@
+//! Reloads the config on SIGHUP, applying changes to the settings that can
+//! safely change while running, without dropping any connections.
+//!
+//! The config file and any auth config file are read again, along with the
+//! TLS certificates and keys, with flags still taking precedence. The log
+//! level, job size and scan limits, slow command threshold, access control,
+//! and per-tube settings can all change. Reloads changing the addresses
+//! listened on, the WAL directory, or other settings only read at startup are
+//! rejected as a whole, leaving the previous config in place.
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use rustls::ServerConfig;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, Level};
+
+use crate::args::Args;
+use crate::config;
+use crate::state::{ServerState, Settings};
+use crate::tls;
+
+/// Everything that reloading the config can change.
+pub(crate) struct Reloader {
+    /// The settings currently in effect.
+    pub(crate) args: Args,
+    pub(crate) state: Arc<ServerState>,
+    /// Changes the most verbose level of the server's own logs.
+    pub(crate) set_log_level: Box<dyn Fn(Level) -> Result<()> + Send>,
+    /// The configuration of the TLS listener, if enabled.
+    pub(crate) tls_config: Option<watch::Sender<Arc<ServerConfig>>>,
+}
+
+impl Reloader {
+    /// Reloads the config each time SIGHUP is received, until `cancel` is
+    /// triggered.
+    pub(crate) async fn run(mut self, cancel: CancellationToken) -> Result<()> {
+        let mut hangups = signal(SignalKind::hangup())?;
+
+        loop {
+            select! {
+                Some(()) = hangups.recv() => {},
+                _ = cancel.cancelled() => return Ok(()),
+            }
+
+            info!("reloading config");
+            if let Err(error) = self.reload() {
+                error!(error = format!("{error:#}"), "failed to reload config");
+            }
+        }
+    }
+
+    /// Reloads the config, applying every change or, if any are invalid or
+    /// can't be made while running, none of them.
+    fn reload(&mut self) -> Result<()> {
+        // Only the first line of clap's errors describes the problem, the
+        // rest being usage for the command line.
+        let args = config::load().map_err(|e| {
+            let e = e.to_string();
+            let line = e.lines().next().unwrap_or_default();
+            anyhow!("{}", line.trim_start_matches("error: "))
+        })?;
+
+        let immutable = config::immutable_changes(&self.args, &args);
+        if !immutable.is_empty() {
+            bail!("can't change {} without restarting", immutable.join(", "));
+        }
+
+        let settings = Settings::load(&args)?;
+        let tls_config = match (&self.tls_config, &args.tls_cert, &args.tls_key)
+        {
+            (Some(_), Some(cert), Some(key)) => Some(tls::server_config(
+                cert,
+                key,
+                args.tls_client_ca.as_deref(),
+            )?),
+            _ => None,
+        };
+
+        // Everything's valid, so apply it all.
+        let changes = self.state.settings().changes(&settings);
+        (self.set_log_level)(settings.log_level)?;
+        self.state.set_settings(settings);
+        if let (Some(sender), Some(config)) = (&self.tls_config, tls_config) {
+            sender.send_replace(Arc::new(config));
+        }
+        self.args = args;
+
+        info!(
+            ?changes,
+            tls_reloaded = self.tls_config.is_some(),
+            "reloaded config"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! Shuts down gracefully on SIGINT or SIGTERM, giving workers time to finish
+//! the jobs they've reserved.
+//!
+//! On the first signal, ebeans stops accepting connections and refuses any
+//! further reserves with `SHUTTING_DOWN`, closing each connection once it's
+//! idle and holds no reservations. Connections still open once the timeout
+//! passes, or on a second signal, are closed immediately.
+use std::io;
+use std::time::Duration;
+
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Waits for a signal to shut down, then cancels `closing`, and once
+/// `timeout` passes or another signal is received, `cancel`.
+pub(crate) async fn run(
+    closing: CancellationToken,
+    cancel: CancellationToken,
+    timeout: Duration,
+) -> io::Result<()> {
+    let mut interrupts = signal(SignalKind::interrupt())?;
+    let mut terminates = signal(SignalKind::terminate())?;
+
+    select! {
+        Some(()) = interrupts.recv() => {},
+        Some(()) = terminates.recv() => {},
+        _ = cancel.cancelled() => return Ok(()),
+    }
+
+    info!(?timeout, "shutting down once reserved jobs are finished");
+    closing.cancel();
+
+    select! {
+        Some(()) = interrupts.recv() => {
+            warn!("shutting down immediately");
+        },
+        Some(()) = terminates.recv() => {
+            warn!("shutting down immediately");
+        },
+        () = sleep(timeout) => {
+            warn!("timed out waiting for reserved jobs to be finished");
+        },
+        _ = cancel.cancelled() => return Ok(()),
+    }
+    cancel.cancel();
+
+    Ok(())
+}
@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use enchanted_beans::auth::AuthConfig;
+use enchanted_beans::latency::CommandLatencies;
+use enchanted_beans::metrics::ListenerStats;
+use enchanted_beans::queue::Queue;
+use enchanted_beans::types::protocol::ServerStats;
+use enchanted_beans::util::bytes_to_human_str;
+use tracing::{info, Level};
+
+use crate::args::Args;
+use crate::config::TubeConfig;
+
+/// Settings that can be changed without restarting, by reloading the config.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Settings {
+    /// The most verbose level of the server's own logs.
+    pub(crate) log_level: Level,
+    /// The largest job accepted into tubes without their own limit.
+    pub(crate) max_job_size: u32,
+    /// The number of bytes of each job body scanned by predicates.
+    pub(crate) query_scan_limit: u32,
+    /// Commands taking longer than this to handle are logged.
+    pub(crate) slow_command_threshold: Option<Duration>,
+    /// Users and roles, if access control is enabled.
+    pub(crate) auth: Option<AuthConfig>,
+    /// Settings for individual tubes.
+    pub(crate) tubes: BTreeMap<Vec<u8>, TubeConfig>,
+}
+
+impl Settings {
+    /// Gathers the settings from `args`, loading the auth config file if one
+    /// is given.
+    pub(crate) fn load(args: &Args) -> Result<Self> {
+        // Any auth config in the config file has already been validated.
+        let auth = match &args.auth_config {
+            Some(path) => Some(load_auth_config(path)?),
+            None => args.auth.clone(),
+        };
+
+        Ok(Self {
+            log_level: args.max_log_level(),
+            max_job_size: args.max_job_size,
+            query_scan_limit: args.query_scan_limit,
+            slow_command_threshold: args
+                .slow_command_threshold
+                .map(Duration::from_millis),
+            auth,
+            tubes: args.tubes.clone(),
+        })
+    }
+
+    /// Describes each setting that differs in `new`, for logging. The auth
+    /// config is described only as having changed, to keep tokens out of the
+    /// logs.
+    pub(crate) fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = vec![];
+        let mut compare = |name: &str, old: String, new: String| {
+            if old != new {
+                changes.push(format!("{name}: {old} -> {new}"));
+            }
+        };
+
+        compare(
+            "log_level",
+            self.log_level.to_string(),
+            new.log_level.to_string(),
+        );
+        compare(
+            "max_job_size",
+            self.max_job_size.to_string(),
+            new.max_job_size.to_string(),
+        );
+        compare(
+            "query_scan_limit",
+            self.query_scan_limit.to_string(),
+            new.query_scan_limit.to_string(),
+        );
+        compare(
+            "slow_command_threshold",
+            format!("{:?}", self.slow_command_threshold),
+            format!("{:?}", new.slow_command_threshold),
+        );
+        let tubes: BTreeSet<_> =
+            self.tubes.keys().chain(new.tubes.keys()).collect();
+        for tube in tubes {
+            compare(
+                &format!("tubes.{}", bytes_to_human_str(tube)),
+                format!("{:?}", self.tubes.get(tube)),
+                format!("{:?}", new.tubes.get(tube)),
+            );
+        }
+        if self.auth != new.auth {
+            changes.push("auth".into());
+        }
+
+        changes
+    }
+}
+
+fn load_auth_config(path: &Path) -> Result<AuthConfig> {
+    let yaml = fs::read_to_string(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+
+    AuthConfig::from_yaml(&yaml).map_err(|e| anyhow!("{}: {e}", path.display()))
+}
+
+/// State shared between every listener and connection in this process.
+pub(crate) struct ServerState {
+    /// A client of the queue, from which each connection opens its own.
+    pub(crate) queue: Queue,
+    /// Connection counts by listener, labelled with its address.
+    pub(crate) listeners: Mutex<BTreeMap<String, ListenerStats>>,
+    /// Time taken to handle each command, by command.
+    pub(crate) latencies: Mutex<CommandLatencies>,
+    /// Settings that can be changed by reloading the config.
+    settings: Mutex<Arc<Settings>>,
+    /// The ID of the most recently accepted connection.
+    last_connection_id: AtomicU64,
+}
+
+impl ServerState {
+    pub(crate) fn new(settings: Settings, queue: Queue) -> Self {
+        Self {
+            queue,
+            listeners: Mutex::new(BTreeMap::new()),
+            latencies: Mutex::new(CommandLatencies::default()),
+            settings: Mutex::new(Arc::new(settings)),
+            last_connection_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the current settings. Commands should use the same settings
+    /// throughout, even if the config is reloaded while they run.
+    pub(crate) fn settings(&self) -> Arc<Settings> {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// Replaces the settings, such as after reloading the config.
+    pub(crate) fn set_settings(&self, settings: Settings) {
+        *self.settings.lock().unwrap() = Arc::new(settings);
+    }
+
+    /// Records a connection accepted by `listener`.
+    pub(crate) fn connection_opened(&self, listener: &str) {
+        let mut listeners = self.listeners.lock().unwrap();
+        let stats = listeners.entry(listener.into()).or_default();
+        stats.current_connections += 1;
+        stats.total_connections += 1;
+    }
+
+    /// Records a connection accepted by `listener` closing.
+    pub(crate) fn connection_closed(&self, listener: &str) {
+        let mut listeners = self.listeners.lock().unwrap();
+        let stats = listeners.entry(listener.into()).or_default();
+        stats.current_connections = stats.current_connections.saturating_sub(1);
+    }
+
+    /// Allocates an ID for a newly accepted connection, unique for the life of
+    /// this process.
+    pub(crate) fn next_connection_id(&self) -> u64 {
+        self.last_connection_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Enters or leaves drain mode, in which `put` is refused while every
+    /// other command works as usual.
+    pub(crate) fn set_draining(&self, draining: bool) {
+        if self.queue.draining() != draining {
+            info!(draining, "drain mode changed");
+        }
+        self.queue.set_draining(draining);
+    }
+
+    /// Returns a copy of the current server stats.
+    pub(crate) fn stats(&self) -> ServerStats {
+        self.complete_stats(self.queue.server_stats())
+    }
+
+    /// Fills in the parts of the queue's stats known only to the server.
+    pub(crate) fn complete_stats(&self, mut stats: ServerStats) -> ServerStats {
+        let (current, total) = self.listeners.lock().unwrap().values().fold(
+            (0, 0),
+            |(current, total), l| {
+                (current + l.current_connections, total + l.total_connections)
+            },
+        );
+        stats.set_connections(current, total);
+        stats.set_max_job_size(self.settings().max_job_size);
+        stats
+    }
+}
@@ -0,0 +1,252 @@
+//! Accepts connections over TLS, optionally authenticating clients by their
+//! certificates.
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::listener::Listener;
+
+/// How long clients have to complete the TLS handshake before being
+/// disconnected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builds the TLS configuration from a PEM certificate chain and private key.
+///
+/// If `client_ca` is given, clients must present a certificate signed by one
+/// of the CAs in that PEM file.
+pub(crate) fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<ServerConfig> {
+    let provider = Arc::new(ring::default_provider());
+
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(Iterator::collect)
+        .with_context(|| format!("reading {}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("reading {}", key.display()))?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path)
+                .with_context(|| format!("reading {}", path.display()))?
+            {
+                roots.add(cert.with_context(|| {
+                    format!("reading {}", path.display())
+                })?)?;
+            }
+
+            builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder_with_provider(
+                    roots.into(),
+                    provider,
+                )
+                .build()?,
+            )
+        },
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(builder.with_single_cert(certs, key)?)
+}
+
+/// Maps a client certificate to an identity: the common name of its
+/// subject, or the whole subject if it has no common name.
+fn identity(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let subject = cert.subject();
+
+    let identity = match subject.iter_common_name().next() {
+        Some(cn) => cn.as_str().ok().map(Into::into),
+        None => Some(subject.to_string()),
+    };
+    identity
+}
+
+/// A TCP listener accepting only TLS connections.
+pub(crate) struct TlsListener {
+    listener: TcpListener,
+    config: watch::Receiver<Arc<ServerConfig>>,
+}
+
+impl TlsListener {
+    /// Binds to `addr`, using the latest TLS configuration sent to `config`
+    /// for each new connection, so certificates can be replaced without
+    /// restarting. Established connections are unaffected.
+    pub(crate) async fn bind(
+        addr: SocketAddr,
+        config: watch::Receiver<Arc<ServerConfig>>,
+    ) -> io::Result<Self> {
+        Ok(Self::new(TcpListener::bind(addr).await?, config))
+    }
+
+    /// Serves TLS on an already bound listener, such as one inherited from
+    /// systemd.
+    pub(crate) fn new(
+        listener: TcpListener,
+        config: watch::Receiver<Arc<ServerConfig>>,
+    ) -> Self {
+        Self { listener, config }
+    }
+}
+
+impl AsFd for TlsListener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
+
+type TlsHandshake = Pin<
+    Box<
+        dyn Future<Output = io::Result<(TlsStream<TcpStream>, Option<String>)>>
+            + Send,
+    >,
+>;
+
+impl Listener for TlsListener {
+    type Stream = TlsStream<TcpStream>;
+    type Handshake = TlsHandshake;
+
+    async fn accept(&self) -> io::Result<(Self::Handshake, String)> {
+        let (conn, peer) = self.listener.accept().await?;
+        conn.set_nodelay(true)?;
+
+        let acceptor = TlsAcceptor::from(self.config.borrow().clone());
+        let handshake = async move {
+            let conn = timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+            let identity = conn
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(identity);
+
+            Ok((conn, identity))
+        };
+
+        Ok((Box::pin(handshake), peer.to_string()))
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        self.listener.local_addr().map(|addr| format!("tls:{addr}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConfig;
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    /// Returns the path to a test certificate or key. The certificates are
+    /// all signed by `ca.pem`, whose key was discarded, and last until 2126.
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/bin/ebeans/testdata")
+            .join(name)
+    }
+
+    fn cert(name: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_file(testdata(&format!("{name}.pem"))).unwrap()
+    }
+
+    async fn listen() -> TlsListener {
+        let config = server_config(
+            &testdata("server.pem"),
+            &testdata("server.key"),
+            Some(&testdata("ca.pem")),
+        )
+        .unwrap();
+        let (_, config) = watch::channel(Arc::new(config));
+        TlsListener::bind("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_identity() {
+        assert_eq!(identity(&cert("worker")).as_deref(), Some("worker"));
+        assert_eq!(
+            identity(&cert("anonymous")).as_deref(),
+            Some("O=Acme, OU=Workers")
+        );
+        assert_eq!(identity(&CertificateDer::from(vec![1, 2, 3])), None);
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate() {
+        let listener = listen().await;
+        let addr = listener.listener.local_addr().unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert("ca")).unwrap();
+        let key = PrivateKeyDer::from_pem_file(testdata("worker.key")).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(
+            ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(vec![cert("worker")], key)
+        .unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let (client, accepted) = tokio::join!(
+            async {
+                let conn = TcpStream::connect(addr).await.unwrap();
+                let name = ServerName::try_from("localhost").unwrap();
+                connector.connect(name, conn).await
+            },
+            async {
+                let (handshake, _) = listener.accept().await.unwrap();
+                handshake.await
+            },
+        );
+        client.unwrap();
+        let (_, identity) = accepted.unwrap();
+        assert_eq!(identity.as_deref(), Some("worker"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        let listener = listen().await;
+        let addr = listener.listener.local_addr().unwrap();
+
+        // Clients that never start the handshake are disconnected.
+        let _conn = TcpStream::connect(addr).await.unwrap();
+        let (handshake, _) = listener.accept().await.unwrap();
+        let started = tokio::time::Instant::now();
+        let error = handshake.await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(started.elapsed(), HANDSHAKE_TIMEOUT);
+    }
+}
@@ -0,0 +1,161 @@
+//! A command-line client for ebeans' extensions to the protocol.
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::ExitCode;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand};
+
+#[derive(Debug, Parser)]
+#[command(about, long_about = None, version)]
+struct Args {
+    /// Server to connect to, as `addr:port`.
+    #[arg(short, long, default_value = "127.0.0.1:11300")]
+    server: String,
+    /// Authenticates as this user, given as `user:token`, before running the
+    /// command.
+    #[arg(long)]
+    auth: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Lists the IDs of the jobs in a tube and state whose bodies satisfy a
+    /// predicate, one per line, as with `query-jobs`.
+    Query {
+        tube: String,
+        #[arg(value_parser = ["ready", "delayed", "reserved", "buried"])]
+        state: String,
+        /// How the predicate's evaluated: a JSONPath expression against the
+        /// body as JSON or YAML, or a regex searched for in the raw body.
+        #[arg(value_parser = ["json", "yaml", "regex"])]
+        kind: String,
+        predicate: String,
+    },
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let result = TcpStream::connect(&args.server)
+        .with_context(|| format!("connecting to {}", args.server))
+        .and_then(|stream| run(stream, &args));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("ebeansctl: {error:#}");
+            ExitCode::FAILURE
+        },
+    }
+}
+
+fn run(stream: TcpStream, args: &Args) -> Result<()> {
+    let mut conn = Conn::new(stream.try_clone()?, stream);
+    if let Some(auth) = &args.auth {
+        let (user, token) = auth
+            .split_once(':')
+            .ok_or_else(|| anyhow!("--auth must be given as user:token"))?;
+        conn.auth(user, token)?;
+    }
+
+    match &args.command {
+        Command::Query {
+            tube,
+            state,
+            kind,
+            predicate,
+        } => {
+            for id in conn.query(tube, state, kind, predicate)? {
+                println!("{id}");
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// A connection to the server, sending one command at a time.
+struct Conn<R, W> {
+    reader: BufReader<R>,
+    writer: W,
+}
+
+impl<R: Read, W: Write> Conn<R, W> {
+    fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer,
+        }
+    }
+
+    /// Sends a command line, followed by `data` if there is any, and returns
+    /// the response line without its `\r\n`.
+    fn send(&mut self, line: &str, data: Option<&[u8]>) -> Result<String> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\r\n")?;
+        if let Some(data) = data {
+            self.writer.write_all(data)?;
+            self.writer.write_all(b"\r\n")?;
+        }
+        self.writer.flush()?;
+
+        let mut response = String::new();
+        if self.reader.read_line(&mut response)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(response.trim_end_matches("\r\n").to_owned())
+    }
+
+    fn auth(&mut self, user: &str, token: &str) -> Result<()> {
+        match self.send(&format!("auth {user} {token}"), None)?.as_str() {
+            "AUTHENTICATED" => Ok(()),
+            other => bail!("auth failed: {other}"),
+        }
+    }
+
+    fn query(
+        &mut self,
+        tube: &str,
+        state: &str,
+        kind: &str,
+        predicate: &str,
+    ) -> Result<Vec<u64>> {
+        let line =
+            format!("query-jobs {tube} {state} {kind} {}", predicate.len());
+        let response = self.send(&line, Some(predicate.as_bytes()))?;
+        let Some(n_bytes) = response.strip_prefix("OK ") else {
+            bail!("query-jobs failed: {response}");
+        };
+
+        // The data's followed by `\r\n`, which YAML ignores.
+        let mut data = vec![0; n_bytes.parse::<usize>()? + 2];
+        self.reader.read_exact(&mut data)?;
+        serde_yaml::from_slice(&data).context("parsing job IDs")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let ids = "---\n- 3\n- 7\n";
+        let responses =
+            format!("AUTHENTICATED\r\nOK {}\r\n{ids}\r\n", ids.len());
+        let mut sent = vec![];
+        let mut conn = Conn::new(responses.as_bytes(), &mut sent);
+        conn.auth("ops", "secret").unwrap();
+        let found = conn.query("default", "ready", "regex", "^id: ").unwrap();
+        assert_eq!(found, [3, 7]);
+        assert_eq!(
+            String::from_utf8(sent).unwrap(),
+            "auth ops secret\r\nquery-jobs default ready regex 5\r\n^id: \r\n",
+        );
+
+        let mut conn = Conn::new(&b"BAD_FORMAT\r\n"[..], vec![]);
+        let error = conn.query("default", "ready", "json", "$.a").unwrap_err();
+        assert_eq!(error.to_string(), "query-jobs failed: BAD_FORMAT");
+    }
+}
@@ -0,0 +1,202 @@
+//! describes job lifecycle events, which clients can `subscribe` to for some or
+//! all tubes to follow what's happening to jobs without polling.
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::util::{serialise_lossy_opt_str, serialise_lossy_str};
+
+/// What happened to a job or tube.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Put,
+    Reserve,
+    Release,
+    Bury,
+    Kick,
+    Delete,
+    Timeout,
+    Pause,
+    Move,
+}
+
+/// A single lifecycle event.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    #[serde(serialize_with = "serialise_lossy_str")]
+    pub tube: Vec<u8>,
+    /// The job affected, for all but `pause` events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// The number of seconds a tube was paused for, for `pause` events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<u32>,
+    /// The tube a job was moved out of, for `move` events.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialise_lossy_opt_str"
+    )]
+    pub from: Option<Vec<u8>>,
+}
+
+impl Event {
+    /// Creates an event affecting the job `id` in `tube`.
+    pub fn job(event: EventKind, tube: Vec<u8>, id: u64) -> Self {
+        Self {
+            event,
+            tube,
+            id: Some(id),
+            delay: None,
+            from: None,
+        }
+    }
+
+    /// Creates an event for `tube` being paused for `delay` seconds.
+    pub fn pause(tube: Vec<u8>, delay: u32) -> Self {
+        Self {
+            event: EventKind::Pause,
+            tube,
+            id: None,
+            delay: Some(delay),
+            from: None,
+        }
+    }
+
+    /// Creates an event for the job `id` being moved from `from` to `tube`.
+    pub fn moved(from: Vec<u8>, tube: Vec<u8>, id: u64) -> Self {
+        Self {
+            event: EventKind::Move,
+            tube,
+            id: Some(id),
+            delay: None,
+            from: Some(from),
+        }
+    }
+}
+
+/// Distributes events to every subscribed connection.
+///
+/// Each subscriber has a buffer of events it's yet to receive. If a
+/// subscriber falls behind by more than the buffer's capacity, the oldest
+/// events are dropped and it's told how many it missed on its next receive.
+#[derive(Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    /// Creates a bus buffering up to `capacity` events for each subscriber.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity.max(1)).0,
+        }
+    }
+
+    /// Sends `event` to every current subscriber.
+    pub fn publish(&self, event: Event) {
+        // Failing to send just means there's nobody subscribed.
+        let _ = self.sender.send(event);
+    }
+
+    /// Returns a receiver of every event published from now on. Receivers
+    /// should filter events with a `Subscription`.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+/// The tubes a single connection is subscribed to events from.
+#[derive(Debug, Default)]
+pub struct Subscription {
+    all: bool,
+    tubes: BTreeSet<Vec<u8>>,
+}
+
+impl Subscription {
+    /// Adds `tube` to the subscription.
+    pub fn subscribe(&mut self, tube: Vec<u8>) {
+        self.tubes.insert(tube);
+    }
+
+    /// Extends the subscription to every tube, including those not yet
+    /// created.
+    pub fn subscribe_all(&mut self) {
+        self.all = true;
+    }
+
+    /// Returns whether `event` should be sent to the subscriber. Moves are
+    /// sent to subscribers of either tube.
+    pub fn matches(&self, event: &Event) -> bool {
+        self.all
+            || self.tubes.contains(&event.tube)
+            || event.from.as_ref().is_some_and(|t| self.tubes.contains(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use broadcast::error::TryRecvError;
+
+    use super::*;
+
+    #[test]
+    fn test_subscription() {
+        let put = Event::job(EventKind::Put, b"a".to_vec(), 1);
+        let pause = Event::pause(b"b".to_vec(), 10);
+        let moved = Event::moved(b"c".to_vec(), b"a".to_vec(), 2);
+
+        let mut sub = Subscription::default();
+        assert!(!sub.matches(&put));
+
+        sub.subscribe(b"a".to_vec());
+        assert!(sub.matches(&put));
+        assert!(!sub.matches(&pause));
+        assert!(sub.matches(&moved));
+
+        let mut from = Subscription::default();
+        from.subscribe(b"c".to_vec());
+        assert!(from.matches(&moved));
+        assert!(!from.matches(&put));
+
+        sub.subscribe_all();
+        assert!(sub.matches(&pause));
+
+        assert_eq!(
+            serde_json::to_string(&put).unwrap(),
+            r#"{"event":"put","tube":"a","id":1}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&pause).unwrap(),
+            r#"{"event":"pause","tube":"b","delay":10}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&moved).unwrap(),
+            r#"{"event":"move","tube":"a","id":2,"from":"c"}"#
+        );
+    }
+
+    #[test]
+    fn test_event_bus() {
+        let bus = EventBus::new(2);
+        let event = |id| Event::job(EventKind::Delete, b"a".to_vec(), id);
+
+        // Events published before subscribing aren't received.
+        bus.publish(event(1));
+        let mut rx = bus.subscribe();
+
+        bus.publish(event(2));
+        assert_eq!(rx.try_recv(), Ok(event(2)));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        // Slow subscribers lose the oldest events.
+        for id in 3..=5 {
+            bus.publish(event(id));
+        }
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Lagged(1)));
+        assert_eq!(rx.try_recv(), Ok(event(4)));
+        assert_eq!(rx.try_recv(), Ok(event(5)));
+    }
+}
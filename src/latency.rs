@@ -0,0 +1,114 @@
+//! records how long the server takes to handle each command, by command.
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+use serde::Serialize;
+
+/// The largest latency tracked precisely, in microseconds. Anything slower is
+/// recorded as this value.
+const MAX_MICROS: u64 = 60 * 60 * 1_000_000;
+
+/// Per-command latency histograms, keyed by command name.
+#[derive(Debug, Default)]
+pub struct CommandLatencies {
+    by_command: BTreeMap<&'static str, Histogram<u64>>,
+}
+
+impl CommandLatencies {
+    /// Records that a command took `duration` to handle.
+    pub fn record(&mut self, command: &'static str, duration: Duration) {
+        let micros = duration.as_micros().try_into().unwrap_or(u64::MAX);
+
+        self.by_command
+            .entry(command)
+            .or_insert_with(|| {
+                Histogram::new_with_bounds(1, MAX_MICROS, 3).unwrap()
+            })
+            .saturating_record(micros.max(1));
+    }
+
+    /// Iterates over the histogram of each command seen so far, in order of
+    /// command name.
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = (&'static str, &Histogram<u64>)> {
+        self.by_command.iter().map(|(k, v)| (*k, v))
+    }
+
+    /// Summarises each command's latencies, for the `stats-latency` command.
+    pub fn summary(&self) -> LatencyStats {
+        self.iter()
+            .map(|(command, hist)| {
+                (
+                    command,
+                    CommandLatencyStats {
+                        count: hist.len(),
+                        mean: hist.mean() as u64,
+                        p50: hist.value_at_quantile(0.5),
+                        p90: hist.value_at_quantile(0.9),
+                        p99: hist.value_at_quantile(0.99),
+                        p999: hist.value_at_quantile(0.999),
+                        max: hist.max(),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+/// Latency statistics for every command seen so far, keyed by command name.
+pub type LatencyStats = BTreeMap<&'static str, CommandLatencyStats>;
+
+#[derive(Debug, Serialize)]
+pub struct CommandLatencyStats {
+    /// number of commands handled
+    pub(crate) count: u64,
+    /// mean latency in microseconds
+    pub(crate) mean: u64,
+    /// median latency in microseconds
+    pub(crate) p50: u64,
+    /// 90th percentile latency in microseconds
+    pub(crate) p90: u64,
+    /// 99th percentile latency in microseconds
+    pub(crate) p99: u64,
+    /// 99.9th percentile latency in microseconds
+    pub(crate) p999: u64,
+    /// maximum latency in microseconds
+    pub(crate) max: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latencies() {
+        let mut latencies = CommandLatencies::default();
+
+        for ms in 1..=100 {
+            latencies.record("put", Duration::from_millis(ms));
+        }
+        latencies.record("reserve", Duration::ZERO);
+        latencies.record("reserve", Duration::from_secs(24 * 60 * 60));
+
+        let summary = latencies.summary();
+        assert_eq!(
+            summary.keys().copied().collect::<Vec<_>>(),
+            ["put", "reserve"]
+        );
+
+        // Values are accurate to 3 significant figures.
+        let put = &summary["put"];
+        assert_eq!(put.count, 100);
+        assert!(put.p50.abs_diff(50_000) <= 50, "{put:?}");
+        assert!(put.p99.abs_diff(99_000) <= 99, "{put:?}");
+        assert!(put.max.abs_diff(100_000) <= 100, "{put:?}");
+
+        // Out-of-range values are clamped rather than dropped.
+        let reserve = &summary["reserve"];
+        assert_eq!(reserve.count, 2);
+        assert_eq!(reserve.p50, 1);
+        assert!(reserve.max.abs_diff(MAX_MICROS) <= MAX_MICROS / 1000);
+    }
+}
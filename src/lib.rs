@@ -1,4 +1,10 @@
+pub mod auth;
+pub mod events;
+pub mod latency;
 pub mod line_reader;
+pub mod metrics;
 pub mod parser;
+pub mod query;
+pub mod queue;
 pub mod types;
 pub mod util;
@@ -1,6 +1,6 @@
 use std::io;
 
-use bytes::{Bytes, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use itertools::Itertools;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
@@ -80,7 +80,7 @@ impl<T: AsyncRead + Unpin> LineReader<T> {
                 // to the byte before the first byte returned in the read_buf
                 // call (and 0 if buf is empty).
                 self.maybe_crlf_from =
-                    self.buf.len().checked_sub(n_bytes_read + 1).unwrap_or(0);
+                    self.buf.len().saturating_sub(n_bytes_read + 1);
 
                 // If we didn't read any bytes this time around, assume we've
                 // reached an end-of-stream condition. Return any pending error:
@@ -98,6 +98,57 @@ impl<T: AsyncRead + Unpin> LineReader<T> {
     }
 }
 
+impl<T: AsyncRead + Unpin> LineReader<T> {
+    /// Reads exactly `len` bytes, such as the data following a `put`, from
+    /// the internal buffer and/or reader, returning None on an end-of-stream
+    /// condition first. Cancel-safe, as for `read_line`.
+    pub async fn read_exact(
+        &mut self,
+        len: usize,
+    ) -> io::Result<Option<Bytes>> {
+        while self.buf.len() < len {
+            if !self.read_more().await? {
+                return Ok(None);
+            }
+        }
+
+        // Anything left may not have been scanned for a CRLF from the start.
+        self.maybe_crlf_from = 0;
+
+        Ok(Some(self.buf.split_to(len).freeze()))
+    }
+
+    /// Discards the next `len` bytes without buffering them all at once, such
+    /// as the body of a job that's too big, returning false on an
+    /// end-of-stream condition first. Not cancel-safe: if cancelled, an
+    /// unknown number of bytes will have been discarded.
+    pub async fn skip(&mut self, mut len: usize) -> io::Result<bool> {
+        loop {
+            let n = len.min(self.buf.len());
+            self.buf.advance(n);
+            len -= n;
+
+            if len == 0 {
+                self.maybe_crlf_from = 0;
+                return Ok(true);
+            }
+            if !self.read_more().await? {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Reads more into the internal buffer, returning false on an
+    /// end-of-stream condition, or any pending error from `read_line`.
+    async fn read_more(&mut self) -> io::Result<bool> {
+        if let Some(e) = self.pending_error.take() {
+            return Err(e);
+        }
+
+        Ok(self.reader.read_buf(&mut self.buf).await? > 0)
+    }
+}
+
 impl<T: AsyncRead + Unpin> From<T> for LineReader<T> {
     fn from(value: T) -> Self {
         Self {
@@ -166,4 +217,30 @@ mod tests {
 
         assert!(lr.read_line().await.unwrap().is_none());
     }
+
+    #[tokio::test]
+    async fn test_read_exact() {
+        let (mut client, server) = io::duplex(4096);
+
+        tokio::spawn(async move {
+            for buf in
+                [&b"put 6\r\nab"[..], b"\r\nd", b"e\r\nskip", b"ped\r\nx\r\n"]
+            {
+                client.write_all(buf).await.unwrap();
+                yield_now().await;
+            }
+        });
+
+        let mut lr: LineReader<_> = server.into();
+
+        // Data can contain CRLFs, and be split across reads.
+        assert_eq!(lr.read_line().await.unwrap().unwrap(), "put 6");
+        assert_eq!(lr.read_exact(8).await.unwrap().unwrap(), "ab\r\nde\r\n");
+
+        assert!(lr.skip(9).await.unwrap());
+        assert_eq!(lr.read_line().await.unwrap().unwrap(), "x");
+
+        assert!(lr.read_exact(1).await.unwrap().is_none());
+        assert!(!lr.skip(1).await.unwrap());
+    }
 }
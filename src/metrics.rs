@@ -0,0 +1,487 @@
+//! renders server and tube stats in the Prometheus text exposition format.
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::latency::CommandLatencies;
+use crate::types::protocol::{ServerStats, TubeStats};
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 16] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+    0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Converts stats into a map of their fields, keyed by the same names used in
+/// `stats` responses. This keeps metrics in step with the stats structs as
+/// fields are added.
+fn fields(stats: &impl Serialize) -> Map<String, Value> {
+    match serde_json::to_value(stats) {
+        Ok(Value::Object(map)) => map,
+        _ => Map::new(),
+    }
+}
+
+/// Escapes a label value as required by the exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Writes a metric's `HELP` and `TYPE` lines.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+/// Writes a single sample. Labels are given as `(name, value)` pairs.
+fn sample(
+    out: &mut String,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: impl fmt::Display,
+) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(k, v)| format!("{k}=\"{}\"", escape(v)))
+            .collect();
+        write!(out, "{{{}}}", labels.join(",")).unwrap();
+    }
+    writeln!(out, " {value}").unwrap();
+}
+
+/// Writes a sample for each of `fields` whose name starts with `prefix`,
+/// labelling each with the rest of the field name.
+fn samples_by_prefix(
+    out: &mut String,
+    name: &str,
+    label: &str,
+    fields: &Map<String, Value>,
+    prefix: &str,
+    extra_labels: &[(&str, &str)],
+) {
+    for (field, value) in fields {
+        if let (Some(suffix), Some(value)) =
+            (field.strip_prefix(prefix), value.as_u64())
+        {
+            let mut labels = extra_labels.to_vec();
+            labels.push((label, suffix));
+            sample(out, name, &labels, value);
+        }
+    }
+}
+
+/// Renders server-wide stats, and the stats of each tube, as Prometheus
+/// metrics.
+pub fn render(server: &ServerStats, tubes: &[TubeStats]) -> String {
+    let mut out = String::new();
+    let server_fields = fields(server);
+    let server_u64 =
+        |key: &str| server_fields.get(key).and_then(Value::as_u64).unwrap_or(0);
+
+    header(&mut out, "ebeans_jobs", "gauge", "Current jobs by state.");
+    samples_by_prefix(
+        &mut out,
+        "ebeans_jobs",
+        "state",
+        &server_fields,
+        "current-jobs-",
+        &[],
+    );
+
+    header(
+        &mut out,
+        "ebeans_commands_total",
+        "counter",
+        "Commands received by command.",
+    );
+    samples_by_prefix(
+        &mut out,
+        "ebeans_commands_total",
+        "command",
+        &server_fields,
+        "cmd-",
+        &[],
+    );
+
+    for (name, field, help) in [
+        (
+            "ebeans_connections",
+            "current-connections",
+            "Currently open connections.",
+        ),
+        (
+            "ebeans_producers",
+            "current-producers",
+            "Open connections that have issued a put.",
+        ),
+        (
+            "ebeans_workers",
+            "current-workers",
+            "Open connections that have issued a reserve.",
+        ),
+        (
+            "ebeans_waiting",
+            "current-waiting",
+            "Open connections waiting on a reserve.",
+        ),
+        ("ebeans_tubes", "current-tubes", "Currently existing tubes."),
+    ] {
+        header(&mut out, name, "gauge", help);
+        sample(&mut out, name, &[], server_u64(field));
+    }
+
+    for (name, field, help) in [
+        (
+            "ebeans_connections_total",
+            "total-connections",
+            "Connections accepted.",
+        ),
+        ("ebeans_jobs_total", "total-jobs", "Jobs created."),
+        (
+            "ebeans_job_timeouts_total",
+            "job-timeouts",
+            "Jobs timed out.",
+        ),
+        (
+            "ebeans_binlog_records_written_total",
+            "binlog-records-written",
+            "Records written to the WAL.",
+        ),
+        (
+            "ebeans_binlog_records_migrated_total",
+            "binlog-records-migrated",
+            "Records written to the WAL as part of compaction.",
+        ),
+    ] {
+        header(&mut out, name, "counter", help);
+        sample(&mut out, name, &[], server_u64(field));
+    }
+
+    header(&mut out, "ebeans_uptime_seconds", "gauge", "Server uptime.");
+    sample(&mut out, "ebeans_uptime_seconds", &[], server_u64("uptime"));
+
+    header(
+        &mut out,
+        "ebeans_draining",
+        "gauge",
+        "Whether the server is in drain mode, refusing new jobs.",
+    );
+    let draining = server_fields.get("draining").and_then(Value::as_bool);
+    sample(
+        &mut out,
+        "ebeans_draining",
+        &[],
+        u8::from(draining == Some(true)),
+    );
+
+    if tubes.is_empty() {
+        return out;
+    }
+
+    let tubes: Vec<_> = tubes
+        .iter()
+        .map(|tube| (String::from_utf8_lossy(&tube.name), fields(tube)))
+        .collect();
+
+    header(
+        &mut out,
+        "ebeans_tube_jobs",
+        "gauge",
+        "Current jobs by tube and state.",
+    );
+    for (tube, fields) in &tubes {
+        samples_by_prefix(
+            &mut out,
+            "ebeans_tube_jobs",
+            "state",
+            fields,
+            "current-jobs-",
+            &[("tube", tube)],
+        );
+    }
+
+    header(
+        &mut out,
+        "ebeans_tube_commands_total",
+        "counter",
+        "Commands issued against each tube by command.",
+    );
+    for (tube, fields) in &tubes {
+        samples_by_prefix(
+            &mut out,
+            "ebeans_tube_commands_total",
+            "command",
+            fields,
+            "cmd-",
+            &[("tube", tube)],
+        );
+    }
+
+    for (name, field, kind, help) in [
+        (
+            "ebeans_tube_jobs_total",
+            "total-jobs",
+            "counter",
+            "Jobs created in each tube.",
+        ),
+        (
+            "ebeans_tube_using",
+            "current-using",
+            "gauge",
+            "Connections using each tube.",
+        ),
+        (
+            "ebeans_tube_watching",
+            "current-watching",
+            "gauge",
+            "Connections watching each tube.",
+        ),
+        (
+            "ebeans_tube_waiting",
+            "current-waiting",
+            "gauge",
+            "Connections waiting on a reserve from each tube.",
+        ),
+        (
+            "ebeans_tube_pause_time_left_seconds",
+            "pause-time-left",
+            "gauge",
+            "Seconds until each tube is unpaused.",
+        ),
+    ] {
+        header(&mut out, name, kind, help);
+        for (tube, fields) in &tubes {
+            let value = fields.get(field).and_then(Value::as_u64).unwrap_or(0);
+            sample(&mut out, name, &[("tube", tube)], value);
+        }
+    }
+
+    out
+}
+
+/// Connection counts for a single listener.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ListenerStats {
+    pub current_connections: u64,
+    pub total_connections: u64,
+}
+
+/// Renders connection counts by listener as Prometheus metrics, labelling
+/// each listener with its address.
+pub fn render_listeners(listeners: &BTreeMap<String, ListenerStats>) -> String {
+    let mut out = String::new();
+
+    let name = "ebeans_listener_connections";
+    header(
+        &mut out,
+        name,
+        "gauge",
+        "Currently open connections by listener.",
+    );
+    for (listener, stats) in listeners {
+        sample(
+            &mut out,
+            name,
+            &[("listener", listener)],
+            stats.current_connections,
+        );
+    }
+
+    let name = "ebeans_listener_connections_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Connections accepted by listener.",
+    );
+    for (listener, stats) in listeners {
+        sample(
+            &mut out,
+            name,
+            &[("listener", listener)],
+            stats.total_connections,
+        );
+    }
+
+    out
+}
+
+/// Renders per-command latency histograms as Prometheus metrics.
+pub fn render_latencies(latencies: &CommandLatencies) -> String {
+    let mut out = String::new();
+    let name = "ebeans_command_duration_seconds";
+
+    header(
+        &mut out,
+        name,
+        "histogram",
+        "Time taken to handle each command, from parsing to flushing the \
+        response.",
+    );
+
+    let bucket = format!("{name}_bucket");
+    for (command, hist) in latencies.iter() {
+        for le in LATENCY_BUCKETS {
+            let count = hist.count_between(0, (le * 1_000_000.0) as u64);
+            let le = le.to_string();
+            sample(
+                &mut out,
+                &bucket,
+                &[("command", command), ("le", &le)],
+                count,
+            );
+        }
+        sample(
+            &mut out,
+            &bucket,
+            &[("command", command), ("le", "+Inf")],
+            hist.len(),
+        );
+
+        // Histograms don't keep an exact total, so approximate it.
+        let sum = hist.mean() * hist.len() as f64 / 1_000_000.0;
+        sample(
+            &mut out,
+            &format!("{name}_sum"),
+            &[("command", command)],
+            sum,
+        );
+        sample(
+            &mut out,
+            &format!("{name}_count"),
+            &[("command", command)],
+            hist.len(),
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::queue::Queue;
+
+    #[tokio::test]
+    async fn test_render() {
+        let queue = Queue::new(16);
+        for _ in 0..2 {
+            queue.put(0, 0, 5, vec![]).await.unwrap();
+            queue.reserve().await.unwrap();
+        }
+        queue.list_tubes().await;
+        let mut server = queue.stats().await;
+        server.set_connections(1, 2);
+
+        let out = render(&server, &[]);
+
+        assert!(out.contains("# TYPE ebeans_commands_total counter\n"));
+        assert!(out.contains("ebeans_commands_total{command=\"reserve\"} 2\n"));
+        assert!(out.contains(
+            "ebeans_commands_total{command=\"reserve-with-timeout\"} 0\n"
+        ));
+        assert!(
+            out.contains("ebeans_commands_total{command=\"list-tubes\"} 1\n")
+        );
+        assert!(out.contains("ebeans_jobs{state=\"urgent\"} 0\n"));
+        assert!(out.contains("ebeans_connections 1\n"));
+        assert!(out.contains("ebeans_connections_total 2\n"));
+        assert!(out.contains("ebeans_draining 0\n"));
+        assert!(!out.contains("ebeans_tube_jobs"));
+
+        // Every cmd-* field of ServerStats has a sample.
+        let n_cmds = fields(&server)
+            .keys()
+            .filter(|k| k.starts_with("cmd-"))
+            .count();
+        assert_eq!(
+            out.lines()
+                .filter(|l| l.starts_with("ebeans_commands_total{"))
+                .count(),
+            n_cmds
+        );
+
+        let tube = TubeStats {
+            name: b"a\"b".to_vec(),
+            current_jobs_urgent: 1,
+            current_jobs_ready: 2,
+            current_jobs_reserved: 3,
+            current_jobs_delayed: 4,
+            current_jobs_buried: 5,
+            total_jobs: 15,
+            current_using: 1,
+            current_waiting: 0,
+            current_watching: 1,
+            pause: 0,
+            cmd_delete: 6,
+            cmd_pause_tube: 0,
+            pause_time_left: 0,
+        };
+        server.set_draining(true);
+        let out = render(&server, &[tube]);
+
+        assert!(out.contains("ebeans_draining 1\n"));
+
+        assert!(out.contains(
+            "ebeans_tube_jobs{tube=\"a\\\"b\",state=\"buried\"} 5\n"
+        ));
+        assert!(out.contains(
+            "ebeans_tube_commands_total{tube=\"a\\\"b\",command=\"delete\"} 6\n"
+        ));
+        assert!(out.contains("ebeans_tube_jobs_total{tube=\"a\\\"b\"} 15\n"));
+    }
+
+    #[test]
+    fn test_render_listeners() {
+        let stats = ListenerStats {
+            current_connections: 1,
+            total_connections: 3,
+        };
+        let out = render_listeners(&BTreeMap::from([
+            ("[::1]:11300".into(), stats),
+            ("unix:/run/ebeans.sock".into(), ListenerStats::default()),
+        ]));
+
+        assert!(out.contains(
+            "ebeans_listener_connections{listener=\"[::1]:11300\"} 1\n"
+        ));
+        assert!(out.contains(
+            "ebeans_listener_connections_total{listener=\"[::1]:11300\"} 3\n"
+        ));
+        assert!(out.contains(
+            "ebeans_listener_connections{listener=\"unix:/run/ebeans.sock\"} 0\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_latencies() {
+        let mut latencies = CommandLatencies::default();
+        latencies.record("put", Duration::from_micros(300));
+        latencies.record("put", Duration::from_millis(3));
+
+        let out = render_latencies(&latencies);
+
+        let bucket = |le: &str| {
+            format!(
+                "ebeans_command_duration_seconds_bucket\
+                {{command=\"put\",le=\"{le}\"}} "
+            )
+        };
+        assert!(out.contains(&(bucket("0.0001") + "0\n")));
+        assert!(out.contains(&(bucket("0.0005") + "1\n")));
+        assert!(out.contains(&(bucket("0.005") + "2\n")));
+        assert!(out.contains(&(bucket("+Inf") + "2\n")));
+        assert!(out.contains(
+            "ebeans_command_duration_seconds_count{command=\"put\"} 2\n"
+        ));
+    }
+}
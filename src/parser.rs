@@ -1,8 +1,10 @@
 //! implements a parser for the beanstalkd TCP protocol.
 use std::fmt;
 
+use crate::query::PredicateKind;
 use crate::types::protocol::BeanstalkCommand;
 use crate::types::serialisable::BeanstalkSerialisable;
+use crate::types::states::JobStateKind;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ParsingError {
@@ -28,6 +30,33 @@ impl BeanstalkSerialisable for ParsingError {
     }
 }
 
+/// Validates a tube name, as accepted by commands such as `use` and `watch`,
+/// for use by other interfaces that accept tube names.
+pub fn parse_name(token: &[u8]) -> Result<Vec<u8>, ParsingError> {
+    fn char_is_name_safe(c: u8, is_first: bool) -> bool {
+        match c {
+            b'a'..=b'z' => true,
+            b'A'..=b'Z' => true,
+            b'0'..=b'9' => true,
+            b'+' | b'/' | b';' | b'.' | b'$' | b'_' | b'(' | b')' => true,
+            b'-' => !is_first, // - is only name safe outside first position
+            _ => false,
+        }
+    }
+
+    if token
+        .iter()
+        .enumerate()
+        .all(|(i, c)| char_is_name_safe(*c, i == 0))
+        && !token.is_empty()
+        && token.len() <= 200
+    {
+        Ok(token.to_vec())
+    } else {
+        Err(ParsingError::BadFormat)
+    }
+}
+
 /// Provides a custom, minimal, zero-copy parser of byte slices.
 struct ParseState<'a> {
     from: &'a [u8],
@@ -37,7 +66,7 @@ impl ParseState<'_> {
     /// Asserts there's no more input to take, returning `result` if so, and a
     /// `BadFormat` error otherwise.
     fn expect_done_and<R>(&self, result: R) -> Result<R, ParsingError> {
-        if self.from.len() == 0 {
+        if self.from.is_empty() {
             Ok(result)
         } else {
             Err(ParsingError::BadFormat)
@@ -48,7 +77,7 @@ impl ParseState<'_> {
     fn expect_next_token(&mut self) -> Result<&[u8], ParsingError> {
         let token = self.next_token().ok_or(ParsingError::BadFormat)?;
 
-        if token.len() == 0 {
+        if token.is_empty() {
             Err(ParsingError::BadFormat)
         } else {
             Ok(token)
@@ -105,26 +134,77 @@ impl ParseState<'_> {
     fn expect_next_name(&mut self) -> Result<Vec<u8>, ParsingError> {
         self.expect_space()?;
 
-        let token = self.expect_next_token()?;
-        let r: Vec<u8> = token.iter().map(|v| *v).collect();
-
-        fn char_is_name_safe(c: u8, is_first: bool) -> bool {
-            match c {
-                b'a'..=b'z' => true,
-                b'A'..=b'Z' => true,
-                b'0'..=b'9' => true,
-                b'+' | b'/' | b';' | b'.' | b'$' | b'_' | b'(' | b')' => true,
-                b'-' => !is_first, // - is only name safe outside first position
-                _ => false,
-            }
+        parse_name(self.expect_next_token()?)
+    }
+
+    /// Consumes from the input, expecting a space then any non-empty token.
+    fn expect_next_word(&mut self) -> Result<Vec<u8>, ParsingError> {
+        self.expect_space()?;
+
+        Ok(self.expect_next_token()?.to_vec())
+    }
+
+    /// Consumes from the input, expecting a space then a job state name.
+    fn expect_next_state(&mut self) -> Result<JobStateKind, ParsingError> {
+        self.expect_space()?;
+
+        match self.expect_next_token()? {
+            b"ready" => Ok(JobStateKind::Ready),
+            b"delayed" => Ok(JobStateKind::Delayed),
+            b"reserved" => Ok(JobStateKind::Reserved),
+            b"buried" => Ok(JobStateKind::Buried),
+            _ => Err(ParsingError::BadFormat),
+        }
+    }
+
+    /// Consumes from the input, expecting a space then the name of a state a
+    /// job can be moved between without being reserved: ready, delayed, or
+    /// buried.
+    fn expect_next_unreserved_state(
+        &mut self,
+    ) -> Result<JobStateKind, ParsingError> {
+        match self.expect_next_state()? {
+            JobStateKind::Reserved => Err(ParsingError::BadFormat),
+            state => Ok(state),
+        }
+    }
+
+    /// Consumes from the input, expecting a space then the name of a state a
+    /// job can be kicked from: buried or delayed.
+    fn expect_next_kickable_state(
+        &mut self,
+    ) -> Result<JobStateKind, ParsingError> {
+        match self.expect_next_state()? {
+            state @ (JobStateKind::Buried | JobStateKind::Delayed) => Ok(state),
+            _ => Err(ParsingError::BadFormat),
         }
+    }
 
-        if r.iter()
-            .enumerate()
-            .all(|(i, c)| char_is_name_safe(*c, i == 0))
-            && r.len() <= 200
-        {
-            Ok(r)
+    /// Consumes from the input, expecting a space then a predicate language.
+    fn expect_next_predicate_kind(
+        &mut self,
+    ) -> Result<PredicateKind, ParsingError> {
+        self.expect_space()?;
+
+        match self.expect_next_token()? {
+            b"json" => Ok(PredicateKind::Json),
+            b"yaml" => Ok(PredicateKind::Yaml),
+            b"regex" => Ok(PredicateKind::Regex),
+            _ => Err(ParsingError::BadFormat),
+        }
+    }
+
+    /// Consumes an optional trailing flag, returning whether it was present.
+    /// Any other trailing input is a `BadFormat` error.
+    fn maybe_next_flag(&mut self, flag: &[u8]) -> Result<bool, ParsingError> {
+        if self.from.is_empty() {
+            return Ok(false);
+        }
+
+        self.expect_space()?;
+
+        if self.expect_next_token()? == flag {
+            Ok(true)
         } else {
             Err(ParsingError::BadFormat)
         }
@@ -132,7 +212,7 @@ impl ParseState<'_> {
 
     /// Consumes a space.
     fn expect_space(&mut self) -> Result<(), ParsingError> {
-        match self.from.get(0) {
+        match self.from.first() {
             Some(b' ') => {
                 self.from = &self.from[1..];
                 Ok(())
@@ -145,7 +225,7 @@ impl ParseState<'_> {
     /// the input. It returns None at the end of the input. On consecutive space
     /// bytes, it returns a zero-length slice.
     fn next_token(&mut self) -> Option<&[u8]> {
-        if self.from.len() == 0 {
+        if self.from.is_empty() {
             return None;
         }
 
@@ -188,6 +268,9 @@ impl TryFrom<&[u8]> for BeanstalkCommand {
             b"quit" => Quit,
             b"reserve" => Reserve,
             b"stats" => StatsServer,
+            b"stats-latency" => StatsLatency,
+            b"subscribe-all" => SubscribeAll,
+            b"unsubscribe" => Unsubscribe,
 
             // <cmd> <id>
             b"delete" => Delete {
@@ -230,6 +313,15 @@ impl TryFrom<&[u8]> for BeanstalkCommand {
             b"stats-tube" => StatsTube {
                 tube: ps.expect_next_name()?,
             },
+            b"subscribe" => Subscribe {
+                tube: ps.expect_next_name()?,
+            },
+
+            // <cmd> <user> <token>
+            b"auth" => Auth {
+                user: ps.expect_next_word()?,
+                token: ps.expect_next_word()?,
+            },
 
             // <cmd> <id> <pri>
             b"bury" => Bury {
@@ -258,6 +350,57 @@ impl TryFrom<&[u8]> for BeanstalkCommand {
                 n_bytes: ps.expect_next_u32()?,
             },
 
+            // <cmd> <tube> <state> <kind> <n_bytes>
+            b"query-jobs" => QueryJobs {
+                tube: ps.expect_next_name()?,
+                state: ps.expect_next_state()?,
+                kind: ps.expect_next_predicate_kind()?,
+                n_bytes: ps.expect_next_u32()?,
+            },
+
+            // <cmd> <tube> <state> <kind> <pri> <n_bytes>
+            b"reprioritise-jobs" => ReprioritiseJobs {
+                tube: ps.expect_next_name()?,
+                state: ps.expect_next_unreserved_state()?,
+                kind: ps.expect_next_predicate_kind()?,
+                pri: ps.expect_next_u32()?,
+                n_bytes: ps.expect_next_u32()?,
+            },
+
+            // <cmd> <tube> <tube> <state> <kind> <n_bytes>
+            b"move-jobs" => MoveJobs {
+                from: ps.expect_next_name()?,
+                to: ps.expect_next_name()?,
+                state: ps.expect_next_unreserved_state()?,
+                kind: ps.expect_next_predicate_kind()?,
+                n_bytes: ps.expect_next_u32()?,
+            },
+
+            // <cmd> <tube> <tube> <state> <id> <id>
+            b"move-jobs-by-id" => MoveJobsById {
+                from: ps.expect_next_name()?,
+                to: ps.expect_next_name()?,
+                state: ps.expect_next_unreserved_state()?,
+                first: ps.expect_next_u64()?,
+                last: ps.expect_next_u64()?,
+            },
+
+            // <cmd> <tube> <state> <kind> <n_bytes> [dry-run]
+            b"delete-jobs" => DeleteJobs {
+                tube: ps.expect_next_name()?,
+                state: ps.expect_next_unreserved_state()?,
+                kind: ps.expect_next_predicate_kind()?,
+                n_bytes: ps.expect_next_u32()?,
+                dry_run: ps.maybe_next_flag(b"dry-run")?,
+            },
+            b"kick-jobs" => KickJobs {
+                tube: ps.expect_next_name()?,
+                state: ps.expect_next_kickable_state()?,
+                kind: ps.expect_next_predicate_kind()?,
+                n_bytes: ps.expect_next_u32()?,
+                dry_run: ps.maybe_next_flag(b"dry-run")?,
+            },
+
             _ => return Err(ParsingError::UnknownCommand),
         };
 
@@ -274,8 +417,8 @@ mod tests {
         use BeanstalkCommand::*;
         use ParsingError::*;
 
-        const U32_MAX_PLUS_1: u128 = 1 << 32 + 1;
-        const U64_MAX_PLUS_1: u128 = 1 << 64 + 1;
+        const U32_MAX_PLUS_1: u128 = (1 << 32) + 1;
+        const U64_MAX_PLUS_1: u128 = (1 << 64) + 1;
 
         // Asserts the line parses into the given command successfully.
         #[track_caller]
@@ -301,10 +444,8 @@ mod tests {
             );
         }
 
-        let name_200_bytes: String =
-            (0..200).into_iter().map(|_| 'a').collect();
-        let name_201_bytes: String =
-            (0..201).into_iter().map(|_| 'a').collect();
+        let name_200_bytes: String = (0..200).map(|_| 'a').collect();
+        let name_201_bytes: String = (0..201).map(|_| 'a').collect();
 
         // Check silly non-commands
         bf(b"");
@@ -400,6 +541,7 @@ mod tests {
             },
         );
         ok(b"stats", StatsServer);
+        ok(b"stats-latency", StatsLatency);
 
         ok(b"list-tubes", ListTubes);
         ok(b"list-tube-used", ListTubeUsed);
@@ -407,6 +549,29 @@ mod tests {
 
         ok(b"quit", Quit);
 
+        ok(
+            b"subscribe hello_world",
+            Subscribe {
+                tube: "hello_world".into(),
+            },
+        );
+        ok(b"subscribe-all", SubscribeAll);
+        ok(b"unsubscribe", Unsubscribe);
+        bf(b"subscribe");
+        bf(b"subscribe -foo");
+        bf(b"subscribe-all foo");
+
+        ok(
+            b"auth alice s3cr3t!",
+            Auth {
+                user: b"alice".to_vec(),
+                token: b"s3cr3t!".to_vec(),
+            },
+        );
+        bf(b"auth alice");
+        bf(b"auth alice  s3cr3t!");
+        bf(b"auth alice s3cr3t! extra");
+
         ok(
             b"pause-tube hello_world 62",
             PauseTube {
@@ -414,5 +579,123 @@ mod tests {
                 delay: 62,
             },
         );
+
+        ok(
+            b"query-jobs hello_world buried json 21",
+            QueryJobs {
+                tube: "hello_world".into(),
+                state: JobStateKind::Buried,
+                kind: PredicateKind::Json,
+                n_bytes: 21,
+            },
+        );
+        ok(
+            b"query-jobs hello_world ready yaml 10",
+            QueryJobs {
+                tube: "hello_world".into(),
+                state: JobStateKind::Ready,
+                kind: PredicateKind::Yaml,
+                n_bytes: 10,
+            },
+        );
+        ok(
+            b"query-jobs hello_world delayed regex 6",
+            QueryJobs {
+                tube: "hello_world".into(),
+                state: JobStateKind::Delayed,
+                kind: PredicateKind::Regex,
+                n_bytes: 6,
+            },
+        );
+        bf(b"query-jobs hello_world buried json");
+        bf(b"query-jobs hello_world sleeping json 21");
+        bf(b"query-jobs hello_world ready xml 21");
+        bf(format!("query-jobs a ready json {U32_MAX_PLUS_1}").as_bytes());
+
+        ok(
+            b"reprioritise-jobs hello_world delayed json 1 21",
+            ReprioritiseJobs {
+                tube: "hello_world".into(),
+                state: JobStateKind::Delayed,
+                kind: PredicateKind::Json,
+                pri: 1,
+                n_bytes: 21,
+            },
+        );
+        bf(b"reprioritise-jobs hello_world reserved json 1 21");
+        bf(b"reprioritise-jobs hello_world ready json 21");
+        bf(format!("reprioritise-jobs a ready json {U32_MAX_PLUS_1} 1")
+            .as_bytes());
+
+        ok(
+            b"move-jobs hello world buried regex 5",
+            MoveJobs {
+                from: "hello".into(),
+                to: "world".into(),
+                state: JobStateKind::Buried,
+                kind: PredicateKind::Regex,
+                n_bytes: 5,
+            },
+        );
+        bf(b"move-jobs hello buried regex 5");
+        bf(b"move-jobs hello world reserved regex 5");
+        ok(
+            b"move-jobs-by-id hello world ready 100 200",
+            MoveJobsById {
+                from: "hello".into(),
+                to: "world".into(),
+                state: JobStateKind::Ready,
+                first: 100,
+                last: 200,
+            },
+        );
+        bf(b"move-jobs-by-id hello world ready 100");
+        bf(format!("move-jobs-by-id a b ready 0 {U64_MAX_PLUS_1}").as_bytes());
+
+        ok(
+            b"delete-jobs hello_world buried json 21",
+            DeleteJobs {
+                tube: "hello_world".into(),
+                state: JobStateKind::Buried,
+                kind: PredicateKind::Json,
+                n_bytes: 21,
+                dry_run: false,
+            },
+        );
+        ok(
+            b"delete-jobs hello_world ready regex 3 dry-run",
+            DeleteJobs {
+                tube: "hello_world".into(),
+                state: JobStateKind::Ready,
+                kind: PredicateKind::Regex,
+                n_bytes: 3,
+                dry_run: true,
+            },
+        );
+        bf(b"delete-jobs hello_world reserved json 21");
+        bf(b"delete-jobs hello_world buried json 21 ");
+        bf(b"delete-jobs hello_world buried json 21 wet-run");
+        bf(b"delete-jobs hello_world buried json 21 dry-run dry-run");
+        ok(
+            b"kick-jobs hello_world buried yaml 12 dry-run",
+            KickJobs {
+                tube: "hello_world".into(),
+                state: JobStateKind::Buried,
+                kind: PredicateKind::Yaml,
+                n_bytes: 12,
+                dry_run: true,
+            },
+        );
+        ok(
+            b"kick-jobs hello_world delayed yaml 12",
+            KickJobs {
+                tube: "hello_world".into(),
+                state: JobStateKind::Delayed,
+                kind: PredicateKind::Yaml,
+                n_bytes: 12,
+                dry_run: false,
+            },
+        );
+        bf(b"kick-jobs hello_world ready yaml 12");
     }
 }
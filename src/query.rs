@@ -0,0 +1,452 @@
+//! implements content-based selection of jobs, matching job bodies against
+//! predicates such as `$.customer_id == 42` or `^ERROR`, or matching job IDs
+//! against a range.
+use std::cmp::Ordering;
+use std::ops::RangeInclusive;
+
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_json_path::JsonPath;
+
+use crate::parser::ParsingError;
+use crate::types::job::Job;
+use crate::types::states::JobStateKind;
+
+/// The language a predicate is written in, as named on the wire.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PredicateKind {
+    /// A JSONPath expression and optional comparison, evaluated against the
+    /// job body parsed as JSON.
+    ///
+    /// On the wire: `json`
+    Json,
+    /// As `Json`, but parsing the job body as YAML. Paths are written in the
+    /// same JSONPath syntax.
+    ///
+    /// On the wire: `yaml`
+    Yaml,
+    /// A regular expression searched for anywhere in the raw job body. Use the
+    /// `(?-u)` flag to match arbitrary bytes rather than UTF-8 characters.
+    ///
+    /// On the wire: `regex`
+    Regex,
+}
+
+/// A comparison between the nodes selected by a path and a literal value.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    /// Operators in the order they're searched for, so that two-character
+    /// operators are found before their one-character prefixes.
+    const OPERATORS: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+
+    /// Tests whether `lhs <op> rhs` holds. Values of different types are never
+    /// ordered, and so only satisfy `!=`.
+    fn holds(&self, lhs: &Value, rhs: &Value) -> bool {
+        let ord = compare_values(lhs, rhs);
+
+        match self {
+            Comparison::Eq => ord == Some(Ordering::Equal),
+            Comparison::Ne => ord != Some(Ordering::Equal),
+            Comparison::Lt => ord == Some(Ordering::Less),
+            Comparison::Le => {
+                matches!(ord, Some(Ordering::Less | Ordering::Equal))
+            },
+            Comparison::Gt => ord == Some(Ordering::Greater),
+            Comparison::Ge => {
+                matches!(ord, Some(Ordering::Greater | Ordering::Equal))
+            },
+        }
+    }
+}
+
+/// Orders two JSON values where that's meaningful: numbers numerically,
+/// strings and booleans by value, and anything else only by equality.
+fn compare_values(lhs: &Value, rhs: &Value) -> Option<Ordering> {
+    match (lhs, rhs) {
+        (Value::Number(l), Value::Number(r)) => {
+            if let (Some(l), Some(r)) = (l.as_i64(), r.as_i64()) {
+                Some(l.cmp(&r))
+            } else if let (Some(l), Some(r)) = (l.as_u64(), r.as_u64()) {
+                Some(l.cmp(&r))
+            } else {
+                l.as_f64()?.partial_cmp(&r.as_f64()?)
+            }
+        },
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
+        (l, r) if l == r => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+/// A predicate over a structured job body: a path selecting zero or more
+/// nodes, and an optional comparison at least one of those nodes must satisfy.
+/// Without a comparison, the predicate matches if the path selects any node.
+#[derive(Debug)]
+pub struct PathPredicate {
+    path: JsonPath,
+    comparison: Option<(Comparison, Value)>,
+}
+
+impl PathPredicate {
+    /// Parses an expression of the form `<path> [<op> <literal>]`, where the
+    /// literal is written as JSON, e.g. `$.customer_id == 42` or
+    /// `$.region != "eu"`.
+    fn parse(expr: &str) -> Result<Self, ParsingError> {
+        let (path, comparison) = match find_operator(expr) {
+            Some((idx, op, len)) => {
+                let literal = serde_json::from_str(expr[idx + len..].trim())
+                    .map_err(|_| ParsingError::BadFormat)?;
+                (&expr[..idx], Some((op, literal)))
+            },
+            None => (expr, None),
+        };
+
+        let path = JsonPath::parse(path.trim())
+            .map_err(|_| ParsingError::BadFormat)?;
+
+        Ok(Self { path, comparison })
+    }
+
+    /// Tests this predicate against an already-parsed document.
+    fn matches_value(&self, doc: &Value) -> bool {
+        let mut nodes = self.path.query(doc).all().into_iter();
+
+        match &self.comparison {
+            Some((op, literal)) => nodes.any(|node| op.holds(node, literal)),
+            None => nodes.next().is_some(),
+        }
+    }
+}
+
+/// Finds the first comparison operator outside of any brackets or quoted
+/// strings, so that filter expressions within the path (which may contain
+/// their own operators) are left intact. Returns the byte offset, operator,
+/// and operator length.
+fn find_operator(expr: &str) -> Option<(usize, Comparison, usize)> {
+    let bytes = expr.as_bytes();
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        match quote {
+            Some(q) => {
+                if c == b'\\' {
+                    i += 1;
+                } else if c == q {
+                    quote = None;
+                }
+            },
+            None => match c {
+                b'\'' | b'"' => quote = Some(c),
+                b'[' | b'(' => depth += 1,
+                b']' | b')' => depth = depth.saturating_sub(1),
+                _ if depth == 0 => {
+                    for (token, op) in Comparison::OPERATORS {
+                        if bytes[i..].starts_with(token.as_bytes()) {
+                            return Some((i, op, token.len()));
+                        }
+                    }
+                },
+                _ => {},
+            },
+        }
+        i += 1;
+    }
+
+    None
+}
+
+/// A predicate over the content of a job body.
+#[derive(Debug)]
+pub enum Predicate {
+    /// Matches bodies that parse as JSON and satisfy the path predicate.
+    Json(PathPredicate),
+    /// Matches bodies that parse as YAML and satisfy the path predicate.
+    Yaml(PathPredicate),
+    /// Matches bodies containing a match for the regular expression.
+    Regex(Regex),
+}
+
+impl Predicate {
+    /// Parses a predicate expression written in the given language. Invalid
+    /// expressions are reported as `BadFormat`, in common with other malformed
+    /// requests.
+    pub fn parse(
+        kind: PredicateKind,
+        expr: &[u8],
+    ) -> Result<Self, ParsingError> {
+        let expr =
+            std::str::from_utf8(expr).map_err(|_| ParsingError::BadFormat)?;
+
+        match kind {
+            PredicateKind::Json => Ok(Self::Json(PathPredicate::parse(expr)?)),
+            PredicateKind::Yaml => Ok(Self::Yaml(PathPredicate::parse(expr)?)),
+            PredicateKind::Regex => Ok(Self::Regex(
+                Regex::new(expr).map_err(|_| ParsingError::BadFormat)?,
+            )),
+        }
+    }
+
+    /// Tests whether a job body satisfies this predicate. Bodies that can't be
+    /// parsed in the predicate's format never match.
+    pub fn matches(&self, body: &[u8]) -> bool {
+        match self {
+            Self::Json(pred) => match serde_json::from_slice(body) {
+                Ok(doc) => pred.matches_value(&doc),
+                Err(_) => false,
+            },
+            // YAML is deserialised straight into a JSON value so the same path
+            // evaluation applies. Documents that can't be represented as JSON,
+            // such as those with non-string keys, don't match.
+            Self::Yaml(pred) => match serde_yaml::from_slice(body) {
+                Ok(doc) => pred.matches_value(&doc),
+                Err(_) => false,
+            },
+            Self::Regex(re) => re.is_match(body),
+        }
+    }
+}
+
+/// Describes which jobs within a state a `JobQuery` selects.
+#[derive(Debug)]
+pub enum Selector {
+    /// Selects jobs whose bodies satisfy the predicate.
+    Predicate(Predicate),
+    /// Selects jobs whose IDs fall within the range, inclusive of both ends.
+    Ids(RangeInclusive<u64>),
+}
+
+/// Selects jobs in a given state by their content or ID.
+#[derive(Debug)]
+pub struct JobQuery {
+    pub state: JobStateKind,
+    pub selector: Selector,
+    /// Only the first `scan_limit` bytes of each body are tested against a
+    /// predicate. Structured bodies longer than this will usually fail to
+    /// parse, and so won't match.
+    pub scan_limit: usize,
+}
+
+impl JobQuery {
+    /// Tests whether a single job is in the right state and is selected.
+    fn matches(&self, job: &Job) -> bool {
+        if job.state.kind() != self.state {
+            return false;
+        }
+
+        match &self.selector {
+            Selector::Predicate(pred) => {
+                let len = job.data.len().min(self.scan_limit);
+                pred.matches(&job.data[..len])
+            },
+            Selector::Ids(ids) => ids.contains(&job.id),
+        }
+    }
+
+    /// Returns the IDs of all matching jobs, in the order they're provided.
+    /// Callers are responsible for limiting `jobs` to those in a single tube.
+    pub fn select<'a>(
+        &self,
+        jobs: impl IntoIterator<Item = &'a Job>,
+    ) -> Vec<u64> {
+        jobs.into_iter()
+            .filter(|job| self.matches(job))
+            .map(|job| job.id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::types::states::JobState;
+
+    fn json(expr: &str) -> Predicate {
+        Predicate::parse(PredicateKind::Json, expr.as_bytes()).unwrap()
+    }
+
+    fn job(id: u64, state: JobState, data: &[u8]) -> Job {
+        Job {
+            id,
+            tube: b"default".to_vec(),
+            pri: 100,
+            delay: 0,
+            data: data.to_vec(),
+            state,
+            created: Instant::now(),
+            ttr: 0,
+            reserves: 0,
+            timeouts: 0,
+            releases: 0,
+            buries: 0,
+            kicks: 0,
+        }
+    }
+
+    #[test]
+    fn test_json_predicates() {
+        let body = br#"{"customer_id": 42, "region": "eu", "tags": ["a", "b"],
+            "items": [{"sku": "x", "qty": 3}, {"sku": "y", "qty": 7}]}"#;
+
+        // Check each comparison operator.
+        assert!(json("$.customer_id == 42").matches(body));
+        assert!(json("$.customer_id == 42.0").matches(body));
+        assert!(!json("$.customer_id == 43").matches(body));
+        assert!(json("$.customer_id != 43").matches(body));
+        assert!(json("$.customer_id < 43").matches(body));
+        assert!(json("$.customer_id <= 42").matches(body));
+        assert!(json("$.customer_id > 41").matches(body));
+        assert!(json("$.customer_id >= 42").matches(body));
+        assert!(json(r#"$.region == "eu""#).matches(body));
+        assert!(!json(r#"$.region == "us""#).matches(body));
+
+        // Mismatched types are only ever unequal.
+        assert!(!json(r#"$.customer_id == "42""#).matches(body));
+        assert!(!json(r#"$.customer_id < "43""#).matches(body));
+        assert!(json(r#"$.customer_id != "42""#).matches(body));
+
+        // Paths selecting several nodes match if any node does.
+        assert!(json(r#"$.tags[*] == "b""#).matches(body));
+        assert!(json("$.items[*].qty > 5").matches(body));
+        assert!(!json("$.items[*].qty > 7").matches(body));
+
+        // Operators inside filters belong to the path.
+        assert!(json(r#"$.items[?@.qty > 5].sku == "y""#).matches(body));
+        assert!(json("$.items[?@.sku == 'x']").matches(body));
+
+        // Bare paths test for existence.
+        assert!(json("$.region").matches(body));
+        assert!(!json("$.missing").matches(body));
+
+        // Unparseable bodies never match.
+        assert!(!json("$.customer_id == 42").matches(b"customer_id: 42"));
+        assert!(!json("$.missing").matches(b""));
+
+        // Invalid expressions are rejected.
+        for expr in ["", "customer_id == 42", "$.customer_id ==", "$.a == x"] {
+            assert_eq!(
+                Predicate::parse(PredicateKind::Json, expr.as_bytes())
+                    .unwrap_err(),
+                ParsingError::BadFormat,
+            );
+        }
+    }
+
+    #[test]
+    fn test_yaml_predicates() {
+        fn yaml(expr: &str) -> Predicate {
+            Predicate::parse(PredicateKind::Yaml, expr.as_bytes()).unwrap()
+        }
+
+        let body = b"customer_id: 42\nregion: eu\ntags:\n  - a\n  - b\n";
+
+        assert!(yaml("$.customer_id == 42").matches(body));
+        assert!(yaml("$.customer_id > 41").matches(body));
+        assert!(yaml(r#"$.region == "eu""#).matches(body));
+        assert!(yaml(r#"$.tags[*] == "b""#).matches(body));
+        assert!(!yaml("$.missing").matches(body));
+
+        // JSON is a subset of YAML, so JSON bodies work too.
+        assert!(yaml("$.customer_id == 42").matches(br#"{"customer_id": 42}"#));
+
+        // Unparseable bodies, or those that can't be represented as JSON,
+        // never match.
+        assert!(!yaml("$.customer_id").matches(b"customer_id: [42"));
+        assert!(!yaml("$.customer_id").matches(b"[1, 2]: x\ncustomer_id: 1"));
+    }
+
+    #[test]
+    fn test_regex_predicates() {
+        fn regex(expr: &str) -> Predicate {
+            Predicate::parse(PredicateKind::Regex, expr.as_bytes()).unwrap()
+        }
+
+        assert!(regex("^ERROR").matches(b"ERROR: disk full"));
+        assert!(!regex("^ERROR").matches(b"WARN: ERROR soon"));
+        assert!(regex(r"customer=\d+").matches(b"id=1 customer=42"));
+
+        // Bodies need not be valid UTF-8.
+        assert!(regex(r"(?-u)\xff\x00").matches(b"\x01\xff\x00\x02"));
+        assert!(regex("abc").matches(b"\xffabc"));
+
+        assert_eq!(
+            Predicate::parse(PredicateKind::Regex, b"(unclosed").unwrap_err(),
+            ParsingError::BadFormat,
+        );
+    }
+
+    #[test]
+    fn test_select() {
+        let jobs = [
+            job(1, JobState::Ready, br#"{"customer_id": 42}"#),
+            job(2, JobState::Ready, br#"{"customer_id": 7}"#),
+            job(3, JobState::Buried, br#"{"customer_id": 42}"#),
+            job(4, JobState::Ready, b"not json"),
+            job(5, JobState::Ready, br#"{"customer_id": 42, "x": 1}"#),
+        ];
+
+        let query = JobQuery {
+            state: JobStateKind::Ready,
+            selector: Selector::Predicate(json("$.customer_id == 42")),
+            scan_limit: usize::MAX,
+        };
+        assert_eq!(query.select(&jobs), [1, 5]);
+
+        let query = JobQuery {
+            state: JobStateKind::Buried,
+            selector: Selector::Predicate(json("$.customer_id")),
+            scan_limit: usize::MAX,
+        };
+        assert_eq!(query.select(&jobs), [3]);
+
+        // Truncating the body beyond the scan limit leaves invalid JSON in the
+        // longer job, so only the shorter one matches.
+        let query = JobQuery {
+            state: JobStateKind::Ready,
+            selector: Selector::Predicate(json("$.customer_id == 42")),
+            scan_limit: 20,
+        };
+        assert_eq!(query.select(&jobs), [1]);
+
+        // Regular expressions only see the bytes within the scan limit.
+        let query = JobQuery {
+            state: JobStateKind::Ready,
+            selector: Selector::Predicate(
+                Predicate::parse(PredicateKind::Regex, b"x").unwrap(),
+            ),
+            scan_limit: 20,
+        };
+        assert_eq!(query.select(&jobs), [] as [u64; 0]);
+
+        // ID ranges are inclusive, and ignore the body and scan limit.
+        let query = JobQuery {
+            state: JobStateKind::Ready,
+            selector: Selector::Ids(2..=4),
+            scan_limit: 0,
+        };
+        assert_eq!(query.select(&jobs), [2, 4]);
+    }
+}
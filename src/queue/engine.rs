@@ -0,0 +1,992 @@
+//! The engine shared by every client of a queue.
+//!
+//! Each tube is a shard of the engine with its own lock, so commands on
+//! different tubes run in parallel. Commands naming a job by its ID find its
+//! tube through an index, itself split between many locks, and each client's
+//! own state is locked separately again. Only creating and removing tubes
+//! locks the map of every tube.
+//!
+//! Locks are only ever taken in this order, so can't deadlock: the map of
+//! tubes; tubes, in name order if more than one; waiting reserves; clients;
+//! and lastly the job index and the ticker's wake time, neither of which is
+//! held while locking anything else. Changes are recorded in the store by a
+//! separate task, so it's never written to with anything locked.
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::SystemTime;
+
+use tokio::sync::{broadcast, oneshot, Notify};
+use tokio::time::{Duration, Instant};
+
+use super::store::{JobStore, StoredJob, StoredState, Writer};
+use super::tube::{Shard, Tube};
+use super::{Error, JobData};
+use crate::events::{Event, EventBus, EventKind};
+use crate::query::JobQuery;
+use crate::types::job::Job;
+use crate::types::protocol::{JobStats, ServerStats, TubeStats};
+use crate::types::states::{JobState, JobStateKind};
+
+/// The tube every client starts out using and watching, which always exists.
+pub(super) const DEFAULT_TUBE: &[u8] = b"default";
+
+/// Reserves are refused with `DEADLINE_SOON` once any job the client has
+/// reserved is this close to its deadline.
+pub(super) const SAFETY_MARGIN: Duration = Duration::from_secs(1);
+
+/// The number of locks the job index is split between.
+const INDEX_BUCKETS: usize = 64;
+
+/// Finds the tube each job is in by its ID.
+#[derive(Debug)]
+pub(super) struct JobIndex(Vec<Mutex<HashMap<u64, Arc<Shard>>>>);
+
+impl JobIndex {
+    fn new() -> Self {
+        Self((0..INDEX_BUCKETS).map(|_| Mutex::default()).collect())
+    }
+
+    fn bucket(&self, id: u64) -> MutexGuard<'_, HashMap<u64, Arc<Shard>>> {
+        self.0[id as usize % INDEX_BUCKETS].lock().unwrap()
+    }
+
+    fn get(&self, id: u64) -> Option<Arc<Shard>> {
+        self.bucket(id).get(&id).cloned()
+    }
+
+    pub(super) fn insert(&self, id: u64, shard: &Arc<Shard>) {
+        self.bucket(id).insert(id, shard.clone());
+    }
+
+    pub(super) fn remove(&self, id: u64) {
+        self.bucket(id).remove(&id);
+    }
+}
+
+/// A single client, such as a connection to the server.
+#[derive(Debug)]
+pub(super) struct Client {
+    pub(super) id: u64,
+    state: Mutex<ClientState>,
+}
+
+impl Client {
+    pub(super) fn lock(&self) -> MutexGuard<'_, ClientState> {
+        self.state.lock().unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct ClientState {
+    used: Arc<Shard>,
+    watched: BTreeMap<Vec<u8>, Arc<Shard>>,
+    /// The deadline of each job the client has reserved.
+    pub(super) reserved: BTreeMap<u64, Instant>,
+    /// The reserve the client last started waiting on.
+    waiting: Option<Arc<Waiter>>,
+    /// Whether the client has ever put, and ever reserved.
+    producer: bool,
+    worker: bool,
+    /// Counts of the commands the client has sent.
+    pub(super) stats: ServerStats,
+}
+
+/// A reserve waiting on a job from any of the tubes its client watched when
+/// it started, queued on each of them.
+#[derive(Debug)]
+pub(super) struct Waiter {
+    pub(super) client: Arc<Client>,
+    tubes: Vec<Arc<Shard>>,
+    /// Where to send the job, taken by the first tube to reserve one for the
+    /// reserve, or once it stops waiting.
+    pub(super) tx: Mutex<Option<oneshot::Sender<JobData>>>,
+}
+
+impl Waiter {
+    pub(super) fn is_waiting(&self) -> bool {
+        self.tx.lock().unwrap().is_some()
+    }
+}
+
+/// The outcome of starting a reserve.
+#[derive(Debug)]
+pub(super) enum Reservation {
+    /// A job was ready, and is now reserved.
+    Reserved(JobData),
+    /// The client is waiting for a job to be sent on `rx`, until the
+    /// deadline of one it's already reserved is soon, if any.
+    Waiting {
+        waiter: Arc<Waiter>,
+        rx: oneshot::Receiver<JobData>,
+        soon: Option<Instant>,
+    },
+}
+
+/// Converts a job to the form it's stored in.
+pub(super) fn to_stored(job: &Job, now: Instant) -> StoredJob {
+    StoredJob {
+        id: job.id,
+        tube: job.tube.clone(),
+        pri: job.pri,
+        delay: job.delay,
+        ttr: job.ttr,
+        data: job.data.clone(),
+        state: match job.state {
+            JobState::Delayed { until } => StoredState::Delayed {
+                until: SystemTime::now() + until.saturating_duration_since(now),
+            },
+            JobState::Buried => StoredState::Buried,
+            // Reservations aren't recorded.
+            JobState::Ready | JobState::Reserved { .. } => StoredState::Ready,
+        },
+        reserves: job.reserves,
+        timeouts: job.timeouts,
+        releases: job.releases,
+        buries: job.buries,
+        kicks: job.kicks,
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Engine {
+    tubes: RwLock<BTreeMap<Vec<u8>, Arc<Shard>>>,
+    pub(super) index: JobIndex,
+    clients: Mutex<HashMap<u64, Arc<Client>>>,
+    /// Counts of the commands sent by clients since disconnected.
+    retired: Mutex<ServerStats>,
+    last_job_id: AtomicU64,
+    last_client_id: AtomicU64,
+    total_jobs: AtomicU64,
+    job_timeouts: AtomicU64,
+    started: Instant,
+    draining: AtomicBool,
+    pub(super) events: EventBus,
+    /// Records changes to jobs in the store.
+    pub(super) writer: Writer,
+    /// When the ticker's next due to run, if ever.
+    wake_at: Mutex<Option<Instant>>,
+    /// Wakes the ticker when it's due to run sooner.
+    pub(super) changed: Arc<Notify>,
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        // Lets the ticker see it's no longer needed.
+        self.changed.notify_one();
+    }
+}
+
+impl Engine {
+    /// Creates the engine, restoring any jobs recovered from `store`.
+    pub(super) fn new(
+        event_buffer: usize,
+        mut store: Box<dyn JobStore>,
+    ) -> io::Result<Self> {
+        let recovered = store.recover()?;
+        let pauses = store.recover_pauses()?;
+
+        let default = Arc::new(Shard::new(DEFAULT_TUBE));
+        let engine = Self {
+            tubes: RwLock::new(BTreeMap::from([(
+                DEFAULT_TUBE.to_vec(),
+                default,
+            )])),
+            index: JobIndex::new(),
+            clients: Mutex::default(),
+            retired: Mutex::new(ServerStats::new()),
+            last_job_id: AtomicU64::new(0),
+            last_client_id: AtomicU64::new(0),
+            total_jobs: AtomicU64::new(0),
+            job_timeouts: AtomicU64::new(0),
+            started: Instant::now(),
+            draining: AtomicBool::new(false),
+            events: EventBus::new(event_buffer),
+            writer: Writer::spawn(store),
+            wake_at: Mutex::default(),
+            changed: Arc::new(Notify::new()),
+        };
+
+        let now = Instant::now();
+        for job in recovered {
+            engine.restore(job, now);
+        }
+        for pause in pauses {
+            // Tubes without jobs are left to be created afresh, unpaused.
+            let Some(shard) = engine.tube(&pause.tube) else {
+                continue;
+            };
+            if let Ok(left) = pause.until.duration_since(SystemTime::now()) {
+                shard.lock().restore_pause(&engine, pause.pause, now + left);
+            }
+        }
+
+        Ok(engine)
+    }
+
+    /// Adds a job recovered from the store.
+    fn restore(&self, job: StoredJob, now: Instant) {
+        let id = job.id;
+        self.last_job_id.fetch_max(id, Ordering::Relaxed);
+        self.total_jobs.fetch_add(1, Ordering::Relaxed);
+
+        let state = match job.state {
+            StoredState::Ready => JobState::Ready,
+            // Delays that passed while stopped are ready straight away.
+            StoredState::Delayed { until } => JobState::Delayed {
+                until: now
+                    + until
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default(),
+            },
+            StoredState::Buried => JobState::Buried,
+        };
+        let (shard, ()) = self.pin(&job.tube, |_| {});
+        let mut tube = shard.lock();
+        tube.insert(
+            self,
+            &shard,
+            Job {
+                id,
+                tube: job.tube,
+                pri: job.pri,
+                delay: job.delay,
+                data: job.data,
+                state,
+                created: now,
+                ttr: job.ttr,
+                reserves: job.reserves,
+                timeouts: job.timeouts,
+                releases: job.releases,
+                buries: job.buries,
+                kicks: job.kicks,
+            },
+        );
+        tube.total_jobs += 1;
+    }
+
+    pub(super) fn count_timeout(&self) {
+        self.job_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub(super) fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    // Timekeeping.
+
+    /// Makes sure the ticker runs by `at`.
+    pub(super) fn schedule(&self, at: Instant) {
+        let mut wake_at = self.wake_at.lock().unwrap();
+        if wake_at.is_none_or(|wake_at| at < wake_at) {
+            *wake_at = Some(at);
+            self.changed.notify_one();
+        }
+    }
+
+    /// Brings every tube up to `now`, returning when to next do so, if ever.
+    pub(super) fn tick(&self, now: Instant) -> Option<Instant> {
+        let shards: Vec<_> =
+            self.tubes.read().unwrap().values().cloned().collect();
+
+        // Anything scheduled meanwhile wakes the ticker straight away.
+        *self.wake_at.lock().unwrap() = None;
+        let next = shards
+            .iter()
+            .filter_map(|shard| {
+                let mut tube = shard.lock();
+                tube.tick(self, now);
+                tube.next_tick()
+            })
+            .min();
+
+        let mut wake_at = self.wake_at.lock().unwrap();
+        *wake_at = [*wake_at, next].into_iter().flatten().min();
+        *wake_at
+    }
+
+    // Finding tubes.
+
+    /// Returns a tube if it exists.
+    fn tube(&self, name: &[u8]) -> Option<Arc<Shard>> {
+        self.tubes.read().unwrap().get(name).cloned()
+    }
+
+    /// Runs `f` on a tube, creating it if need be, such that it can't be
+    /// removed in the meantime. `f` should make the tube used, so it can't be
+    /// removed afterwards either.
+    fn pin<T>(
+        &self,
+        name: &[u8],
+        f: impl FnOnce(&mut Tube) -> T,
+    ) -> (Arc<Shard>, T) {
+        {
+            let tubes = self.tubes.read().unwrap();
+            if let Some(shard) = tubes.get(name) {
+                let ret = f(&mut shard.lock());
+                return (shard.clone(), ret);
+            }
+        }
+
+        let mut tubes = self.tubes.write().unwrap();
+        let shard = tubes
+            .entry(name.to_vec())
+            .or_insert_with(|| Arc::new(Shard::new(name)))
+            .clone();
+        let ret = f(&mut shard.lock());
+        (shard, ret)
+    }
+
+    /// Runs `f` on a tube that should then stop using it, removing it if
+    /// it's then unused.
+    fn unpin(&self, shard: &Arc<Shard>, f: impl FnOnce(&mut Tube)) {
+        let mut tube = shard.lock();
+        f(&mut tube);
+        let unused = tube.is_unused();
+        drop(tube);
+
+        if unused {
+            self.remove_if_unused(shard);
+        }
+    }
+
+    /// Removes a tube once nothing refers to it, other than the default tube.
+    fn remove_if_unused(&self, shard: &Arc<Shard>) {
+        if shard.name == DEFAULT_TUBE {
+            return;
+        }
+
+        let mut tubes = self.tubes.write().unwrap();
+        if shard.lock().is_unused()
+            && tubes
+                .get(&shard.name)
+                .is_some_and(|current| Arc::ptr_eq(current, shard))
+        {
+            tubes.remove(&shard.name);
+        }
+    }
+
+    /// Runs `f` on a tube, once it's caught up with the time, then hands any
+    /// jobs made ready to waiting clients.
+    fn update<T>(
+        &self,
+        shard: &Arc<Shard>,
+        now: Instant,
+        f: impl FnOnce(&mut Tube) -> T,
+    ) -> T {
+        let mut tube = shard.lock();
+        tube.tick(self, now);
+        let ret = f(&mut tube);
+        tube.dispatch(self, now);
+        let unused = tube.is_unused();
+        drop(tube);
+
+        if unused {
+            self.remove_if_unused(shard);
+        }
+        ret
+    }
+
+    /// As `update`, on the tube holding a job, or `NotFound` if there's no
+    /// such job.
+    fn update_job<T>(
+        &self,
+        id: u64,
+        now: Instant,
+        f: impl FnOnce(&mut Tube) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut f = Some(f);
+        let mut shard = self.index.get(id).ok_or(Error::NotFound)?;
+        loop {
+            if let Some(ret) = self.update(&shard, now, |tube| {
+                tube.contains(id).then(|| f.take().unwrap()(tube))
+            }) {
+                return ret;
+            }
+
+            // The job was moved to another tube since it was looked up, or
+            // deleted.
+            match self.index.get(id) {
+                Some(other) if !Arc::ptr_eq(&other, &shard) => shard = other,
+                _ => return Err(Error::NotFound),
+            }
+        }
+    }
+
+    /// As `update`, on a tube by its name, if it exists.
+    fn update_tube<T>(
+        &self,
+        name: &[u8],
+        now: Instant,
+        f: impl FnOnce(&mut Tube) -> T,
+    ) -> Option<T> {
+        let shard = self.tube(name)?;
+        Some(self.update(&shard, now, f))
+    }
+
+    /// As `update`, on the tube a client's using.
+    fn update_used<T>(
+        &self,
+        client: &Client,
+        now: Instant,
+        f: impl FnOnce(&mut Tube) -> T,
+    ) -> T {
+        let used = client.lock().used.clone();
+        self.update(&used, now, f)
+    }
+
+    // Clients.
+
+    /// Adds a client using and watching the default tube.
+    pub(super) fn connect(&self) -> Arc<Client> {
+        let id = self.last_client_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (default, ()) = self.pin(DEFAULT_TUBE, |tube| {
+            tube.using += 1;
+            tube.watching += 1;
+        });
+
+        let client = Arc::new(Client {
+            id,
+            state: Mutex::new(ClientState {
+                used: default.clone(),
+                watched: BTreeMap::from([(DEFAULT_TUBE.to_vec(), default)]),
+                reserved: BTreeMap::new(),
+                waiting: None,
+                producer: false,
+                worker: false,
+                stats: ServerStats::default(),
+            }),
+        });
+        self.clients.lock().unwrap().insert(id, client.clone());
+
+        client
+    }
+
+    /// Removes a client, returning any jobs it had reserved to the ready
+    /// queue.
+    pub(super) fn disconnect(&self, client: &Arc<Client>, now: Instant) {
+        let (waiting, reserved, used, watched, stats) = {
+            let mut c = client.lock();
+            (
+                c.waiting.take(),
+                std::mem::take(&mut c.reserved),
+                c.used.clone(),
+                std::mem::take(&mut c.watched),
+                std::mem::take(&mut c.stats),
+            )
+        };
+
+        if let Some(waiter) = waiting {
+            self.stop_waiting(&waiter);
+        }
+        for &id in reserved.keys() {
+            let _ = self.update_job(id, now, |tube| {
+                tube.abandon(id, client.id);
+                Ok(())
+            });
+        }
+        self.unpin(&used, |tube| tube.using -= 1);
+        for shard in watched.values() {
+            self.unpin(shard, |tube| tube.watching -= 1);
+        }
+
+        self.clients.lock().unwrap().remove(&client.id);
+        self.retired.lock().unwrap().add_commands(&stats);
+    }
+
+    // Reserving.
+
+    /// Starts a reserve for the client, reserving a job straight away if
+    /// there's one ready. Otherwise, unless it should give up immediately,
+    /// the client waits for a tube it watches to send it a job.
+    ///
+    /// The reserve is queued on every tube the client watches before looking
+    /// for a ready job, so a job made ready meanwhile isn't missed. It's then
+    /// offered the ready jobs from the most to the least urgent, taking the
+    /// first unless clients that have waited longer take them first.
+    pub(super) fn reserve(
+        &self,
+        client: &Arc<Client>,
+        wait: bool,
+        now: Instant,
+    ) -> Result<Reservation, Error> {
+        let (tx, mut rx) = oneshot::channel();
+        let (waiter, replaced) = {
+            let mut c = client.lock();
+            c.worker = true;
+            let waiter = Arc::new(Waiter {
+                client: client.clone(),
+                tubes: c.watched.values().cloned().collect(),
+                tx: Mutex::new(Some(tx)),
+            });
+            (waiter.clone(), c.waiting.replace(waiter))
+        };
+        // Any reserve already waiting is ended by dropping its sender.
+        if let Some(replaced) = replaced {
+            self.stop_waiting(&replaced);
+        }
+
+        for shard in &waiter.tubes {
+            let mut tube = shard.lock();
+            tube.tick(self, now);
+            tube.wait(waiter.clone());
+        }
+
+        let mut ready: Vec<_> = waiter
+            .tubes
+            .iter()
+            .filter_map(|shard| shard.lock().next_ready(now).zip(Some(shard)))
+            .collect();
+        ready.sort_by_key(|&(next, _)| next);
+        for (_, shard) in ready {
+            if !waiter.is_waiting() {
+                break;
+            }
+            shard.lock().dispatch(self, now);
+        }
+
+        if let Ok(job) = rx.try_recv() {
+            self.stop_waiting(&waiter);
+            return Ok(Reservation::Reserved(job));
+        }
+
+        let soon = client
+            .lock()
+            .reserved
+            .values()
+            .min()
+            .map(|&deadline| deadline - SAFETY_MARGIN);
+        let soon_now = soon.is_some_and(|soon| soon <= now);
+        if soon_now || !wait {
+            // A job may have been reserved just now.
+            self.stop_waiting(&waiter);
+            if let Ok(job) = rx.try_recv() {
+                return Ok(Reservation::Reserved(job));
+            }
+            return Err(match soon_now {
+                true => Error::DeadlineSoon,
+                false => Error::TimedOut,
+            });
+        }
+
+        Ok(Reservation::Waiting { waiter, rx, soon })
+    }
+
+    /// Stops a reserve waiting, and removes it from the tubes it was queued
+    /// on. Once stopped, any job reserved for it has already been sent.
+    pub(super) fn stop_waiting(&self, waiter: &Arc<Waiter>) {
+        waiter.tx.lock().unwrap().take();
+        for shard in &waiter.tubes {
+            shard.lock().stop_waiting(waiter);
+        }
+
+        let mut c = waiter.client.lock();
+        if c.waiting.as_ref().is_some_and(|w| Arc::ptr_eq(w, waiter)) {
+            c.waiting = None;
+        }
+    }
+
+    /// Returns a job reserved for a client that stopped waiting before it
+    /// received it, without counting a release.
+    pub(super) fn unreserve(&self, client: &Client, id: u64, now: Instant) {
+        let _ = self.update_job(id, now, |tube| {
+            tube.unreserve(id, client.id);
+            Ok(())
+        });
+    }
+
+    pub(super) fn reserve_job(
+        &self,
+        client: &Arc<Client>,
+        id: u64,
+        now: Instant,
+    ) -> Result<JobData, Error> {
+        client.lock().worker = true;
+        self.update_job(id, now, |tube| tube.reserve_job(self, id, client, now))
+    }
+
+    // Commands on the client's own tubes and jobs.
+
+    /// Puts a job into the client's used tube once it's recorded in the
+    /// store, which the tube's kept for meanwhile. Jobs are only reserved
+    /// once recorded, but in order of their IDs, so jobs of the same priority
+    /// are still reserved in the order they were put.
+    pub(super) async fn put(
+        &self,
+        client: &Client,
+        pri: u32,
+        delay: u32,
+        ttr: u32,
+        data: Vec<u8>,
+    ) -> Result<u64, Error> {
+        if self.draining() {
+            return Err(Error::Draining);
+        }
+
+        let used = {
+            let mut c = client.lock();
+            c.producer = true;
+            c.used.clone()
+        };
+        used.lock().pending += 1;
+
+        let now = Instant::now();
+        let id = self.last_job_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let job = Job {
+            id,
+            tube: used.name.clone(),
+            pri,
+            delay,
+            data,
+            state: match delay {
+                0 => JobState::Ready,
+                delay => JobState::Delayed {
+                    until: now + Duration::from_secs(delay.into()),
+                },
+            },
+            created: now,
+            // As with beanstalkd, every job gets at least a second.
+            ttr: ttr.max(1),
+            reserves: 0,
+            timeouts: 0,
+            releases: 0,
+            buries: 0,
+            kicks: 0,
+        };
+        let recorded = self.writer.create(to_stored(&job, now)).await;
+
+        self.update(&used, Instant::now(), |tube| {
+            tube.pending -= 1;
+            if !recorded {
+                return Err(Error::InternalError);
+            }
+
+            tube.insert(self, &used, job);
+            tube.total_jobs += 1;
+            self.total_jobs.fetch_add(1, Ordering::Relaxed);
+            self.events.publish(Event::job(
+                EventKind::Put,
+                used.name.clone(),
+                id,
+            ));
+
+            Ok(id)
+        })
+    }
+
+    /// Waits for every change made so far to be recorded in the store.
+    pub(super) async fn flush(&self) {
+        self.writer.flush().await;
+    }
+
+    /// As `flush`, then checkpoints the store.
+    pub(super) async fn checkpoint(&self) {
+        self.writer.checkpoint().await;
+    }
+
+    pub(super) fn use_tube(&self, client: &Client, name: &[u8]) -> Vec<u8> {
+        let (shard, ()) = self.pin(name, |tube| tube.using += 1);
+        let old = std::mem::replace(&mut client.lock().used, shard);
+        self.unpin(&old, |tube| tube.using -= 1);
+
+        name.to_vec()
+    }
+
+    pub(super) fn watch(&self, client: &Client, name: &[u8]) -> u32 {
+        if !client.lock().watched.contains_key(name) {
+            let (shard, ()) = self.pin(name, |tube| tube.watching += 1);
+            let replaced = client.lock().watched.insert(name.to_vec(), shard);
+            if let Some(shard) = replaced {
+                self.unpin(&shard, |tube| tube.watching -= 1);
+            }
+        }
+
+        client.lock().watched.len() as u32
+    }
+
+    pub(super) fn ignore(
+        &self,
+        client: &Client,
+        name: &[u8],
+    ) -> Result<u32, Error> {
+        let (ignored, count) = {
+            let mut c = client.lock();
+            if c.watched.len() == 1 && c.watched.contains_key(name) {
+                return Err(Error::NotIgnored);
+            }
+            (c.watched.remove(name), c.watched.len())
+        };
+        if let Some(shard) = ignored {
+            self.unpin(&shard, |tube| tube.watching -= 1);
+        }
+
+        Ok(count as u32)
+    }
+
+    pub(super) fn release(
+        &self,
+        client: &Client,
+        id: u64,
+        pri: u32,
+        delay: u32,
+        now: Instant,
+    ) -> Result<(), Error> {
+        self.update_job(id, now, |tube| {
+            tube.release(self, client.id, id, pri, delay, now)
+        })
+    }
+
+    pub(super) fn bury(
+        &self,
+        client: &Client,
+        id: u64,
+        pri: u32,
+        now: Instant,
+    ) -> Result<(), Error> {
+        self.update_job(id, now, |tube| {
+            tube.bury(self, client.id, id, pri, now)
+        })
+    }
+
+    pub(super) fn touch(
+        &self,
+        client: &Arc<Client>,
+        id: u64,
+        now: Instant,
+    ) -> Result<(), Error> {
+        self.update_job(id, now, |tube| tube.touch(self, client, id, now))
+    }
+
+    /// Deletes a job reserved by the client, or that isn't reserved at all.
+    pub(super) fn delete(
+        &self,
+        client: &Client,
+        id: u64,
+        now: Instant,
+    ) -> Result<(), Error> {
+        self.update_job(id, now, |tube| tube.delete(self, client.id, id))
+    }
+
+    pub(super) fn kick(
+        &self,
+        client: &Client,
+        bound: u64,
+        now: Instant,
+    ) -> u64 {
+        self.update_used(client, now, |tube| tube.kick(self, bound, now))
+    }
+
+    pub(super) fn kick_job(&self, id: u64, now: Instant) -> Result<(), Error> {
+        self.update_job(id, now, |tube| tube.kick_job(self, id, now))
+    }
+
+    // Peeking.
+
+    pub(super) fn peek(&self, id: u64, now: Instant) -> Result<JobData, Error> {
+        self.update_job(id, now, |tube| Ok(tube.peek(id)))
+    }
+
+    pub(super) fn peek_state(
+        &self,
+        client: &Client,
+        state: JobStateKind,
+        now: Instant,
+    ) -> Result<JobData, Error> {
+        self.update_used(client, now, |tube| tube.peek_state(state))
+    }
+
+    // Stats.
+
+    pub(super) fn stats_job(
+        &self,
+        id: u64,
+        now: Instant,
+    ) -> Result<JobStats, Error> {
+        self.update_job(id, now, |tube| Ok(tube.stats_job(id, now)))
+    }
+
+    pub(super) fn stats_tube(
+        &self,
+        name: &[u8],
+        now: Instant,
+    ) -> Option<TubeStats> {
+        self.update_tube(name, now, |tube| tube.stats(name, now))
+    }
+
+    /// Returns the server-wide stats, including job and client counts.
+    pub(super) fn stats(&self, now: Instant) -> ServerStats {
+        let mut stats = self.retired.lock().unwrap().clone();
+
+        let clients: Vec<_> =
+            self.clients.lock().unwrap().values().cloned().collect();
+        let mut waiters = vec![];
+        for client in clients {
+            let c = client.lock();
+            stats.add_commands(&c.stats);
+            stats.current_producers += u64::from(c.producer);
+            stats.current_workers += u64::from(c.worker);
+            waiters.extend(c.waiting.clone());
+        }
+        stats.current_waiting =
+            waiters.iter().filter(|waiter| waiter.is_waiting()).count() as u64;
+
+        let shards: Vec<_> =
+            self.tubes.read().unwrap().values().cloned().collect();
+        for shard in &shards {
+            let mut tube = shard.lock();
+            tube.tick(self, now);
+            tube.add_job_counts(&mut stats);
+        }
+        stats.current_tubes = shards.len() as u64;
+        stats.total_jobs = self.total_jobs.load(Ordering::Relaxed);
+        stats.job_timeouts = self.job_timeouts.load(Ordering::Relaxed);
+        stats.set_uptime(
+            now.saturating_duration_since(self.started)
+                .as_secs()
+                .try_into()
+                .unwrap_or(u32::MAX),
+        );
+        stats.set_draining(self.draining());
+
+        stats
+    }
+
+    pub(super) fn tube_names(&self) -> Vec<Vec<u8>> {
+        self.tubes.read().unwrap().keys().cloned().collect()
+    }
+
+    pub(super) fn used_tube(&self, client: &Client) -> Vec<u8> {
+        client.lock().used.name.clone()
+    }
+
+    pub(super) fn watched_tubes(&self, client: &Client) -> Vec<Vec<u8>> {
+        client.lock().watched.keys().cloned().collect()
+    }
+
+    pub(super) fn has_reservations(&self, client: &Client) -> bool {
+        !client.lock().reserved.is_empty()
+    }
+
+    pub(super) fn job_tube(&self, id: u64) -> Option<Vec<u8>> {
+        self.index.get(id).map(|shard| shard.name.clone())
+    }
+
+    pub(super) fn pause_tube(
+        &self,
+        name: &[u8],
+        delay: u32,
+        now: Instant,
+    ) -> Result<(), Error> {
+        self.update_tube(name, now, |tube| tube.pause(self, name, delay, now))
+            .ok_or(Error::NotFound)
+    }
+
+    // Bulk operations, selecting jobs in a tube with a query.
+
+    /// Returns the IDs of the jobs in a tube selected by a query, in the
+    /// order they were created.
+    pub(super) fn select(
+        &self,
+        name: &[u8],
+        query: &JobQuery,
+        now: Instant,
+    ) -> Vec<u64> {
+        self.update_tube(name, now, |tube| tube.select(query))
+            .unwrap_or_default()
+    }
+
+    pub(super) fn reprioritise(
+        &self,
+        name: &[u8],
+        query: &JobQuery,
+        pri: u32,
+        now: Instant,
+    ) -> u64 {
+        self.update_tube(name, now, |tube| {
+            tube.reprioritise(self, query, pri, now)
+        })
+        .unwrap_or(0)
+    }
+
+    /// Moves the selected jobs to another tube, creating it if need be.
+    pub(super) fn move_jobs(
+        &self,
+        from: &[u8],
+        to: &[u8],
+        query: &JobQuery,
+        now: Instant,
+    ) -> u64 {
+        if from == to || query.state == JobStateKind::Reserved {
+            return 0;
+        }
+
+        loop {
+            // Holding the map of tubes keeps either tube from being removed
+            // until the jobs are moved.
+            let tubes = self.tubes.read().unwrap();
+            let Some(from_shard) = tubes.get(from).cloned() else {
+                return 0;
+            };
+            let Some(to_shard) = tubes.get(to).cloned() else {
+                drop(tubes);
+                self.pin(to, |_| {});
+                continue;
+            };
+
+            let (mut src, mut dst) = if from < to {
+                let src = from_shard.lock();
+                (src, to_shard.lock())
+            } else {
+                let dst = to_shard.lock();
+                (from_shard.lock(), dst)
+            };
+            src.tick(self, now);
+            dst.tick(self, now);
+            let moved = src.move_jobs(self, &mut dst, &to_shard, query, now);
+            let unused = [src.is_unused(), dst.is_unused()];
+            drop((src, dst, tubes));
+
+            for (shard, unused) in [from_shard, to_shard].iter().zip(unused) {
+                if unused {
+                    self.remove_if_unused(shard);
+                }
+            }
+            return moved;
+        }
+    }
+
+    pub(super) fn delete_jobs(
+        &self,
+        name: &[u8],
+        query: &JobQuery,
+        dry_run: bool,
+        now: Instant,
+    ) -> Vec<u64> {
+        self.update_tube(name, now, |tube| {
+            tube.delete_jobs(self, query, dry_run)
+        })
+        .unwrap_or_default()
+    }
+
+    pub(super) fn kick_jobs(
+        &self,
+        name: &[u8],
+        query: &JobQuery,
+        dry_run: bool,
+        now: Instant,
+    ) -> Vec<u64> {
+        self.update_tube(name, now, |tube| {
+            tube.kick_jobs(self, query, dry_run, now)
+        })
+        .unwrap_or_default()
+    }
+}
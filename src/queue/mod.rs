@@ -0,0 +1,976 @@
+//! The queue engine, usable directly from Rust with no server involved.
+//!
+//! A `Queue` is a single client of an engine, with the same view of it as a
+//! connection to the server: it uses one tube, watches others, and holds its
+//! own reservations. Its methods mirror the protocol's commands, with the same
+//! semantics. Further clients of the same engine are opened with `connect`,
+//! and each client's reservations are released once it's dropped.
+//!
+//! Jobs are kept in memory, and recorded in a `JobStore` to be recovered
+//! from when the queue next starts, if one is given to `with_store`: a
+//! `WalStore`, or a `SqliteStore` with the `sqlite` feature.
+//!
+//! ```
+//! # #[tokio::main]
+//! # async fn main() {
+//! use enchanted_beans::queue::Queue;
+//!
+//! let producer = Queue::new(64);
+//! let worker = producer.connect();
+//!
+//! let id = producer.put(0, 0, 60, b"hello".to_vec()).await.unwrap();
+//! let job = worker.reserve().await.unwrap();
+//! assert_eq!((job.id, &job.data[..]), (id, &b"hello"[..]));
+//! worker.delete(job.id).await.unwrap();
+//! # }
+//! ```
+mod engine;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
+mod tube;
+mod wal;
+
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Weak};
+
+use tokio::select;
+use tokio::sync::{broadcast, oneshot, Notify};
+use tokio::time::{sleep_until, Duration, Instant};
+
+use self::engine::{Client, Engine, Reservation, Waiter};
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStore;
+pub use self::store::{
+    JobStore, MemoryStore, StoredJob, StoredPause, StoredState,
+};
+pub use self::wal::{SyncPolicy, WalStore};
+use crate::events::Event;
+use crate::query::JobQuery;
+use crate::types::protocol::{
+    BeanstalkResponse, JobStats, ServerStats, TubeStats,
+};
+use crate::types::states::JobStateKind;
+
+/// A command couldn't be carried out, for one of the reasons the protocol
+/// gives.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The job or tube doesn't exist, or isn't in the right state.
+    NotFound,
+    /// The queue is in drain mode, so isn't accepting jobs.
+    Draining,
+    /// No job became ready before the reserve timed out.
+    TimedOut,
+    /// A job reserved by this client is close to its deadline.
+    DeadlineSoon,
+    /// The tube is the only one being watched.
+    NotIgnored,
+    /// The job couldn't be recorded in the store.
+    InternalError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotFound => "not found",
+            Self::Draining => "draining",
+            Self::TimedOut => "timed out",
+            Self::DeadlineSoon => "deadline soon",
+            Self::NotIgnored => "not ignored",
+            Self::InternalError => "internal error",
+        })
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for BeanstalkResponse {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::NotFound => Self::NotFound,
+            Error::Draining => Self::Draining,
+            Error::TimedOut => Self::TimedOut,
+            Error::DeadlineSoon => Self::DeadlineSoon,
+            Error::NotIgnored => Self::NotIgnored,
+            Error::InternalError => Self::InternalError,
+        }
+    }
+}
+
+/// A job's ID and body, as returned by reserves and peeks.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct JobData {
+    pub id: u64,
+    pub data: Vec<u8>,
+}
+
+/// Makes delayed jobs ready, times out reservations, and unpauses tubes as
+/// time passes, for as long as the engine exists.
+async fn tick(engine: Weak<Engine>, changed: Arc<Notify>) {
+    loop {
+        let next = match engine.upgrade() {
+            Some(engine) => engine.tick(Instant::now()),
+            None => return,
+        };
+
+        select! {
+            () = changed.notified() => {},
+            () = sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {},
+        }
+    }
+}
+
+/// A client of a queue engine. See the module documentation.
+#[derive(Debug)]
+pub struct Queue {
+    engine: Arc<Engine>,
+    client: Arc<Client>,
+}
+
+impl Queue {
+    /// Creates an empty queue kept only in memory, buffering up to
+    /// `event_buffer` events for each subscriber, and returns its first
+    /// client.
+    ///
+    /// Must be called within a Tokio runtime, which keeps time for the queue.
+    pub fn new(event_buffer: usize) -> Self {
+        Self::with_store(event_buffer, MemoryStore)
+            .expect("nothing to recover from memory")
+    }
+
+    /// As `new`, but recording jobs in `store`, and starting with any jobs
+    /// recovered from it.
+    pub fn with_store(
+        event_buffer: usize,
+        store: impl JobStore + 'static,
+    ) -> io::Result<Self> {
+        let engine = Arc::new(Engine::new(event_buffer, Box::new(store))?);
+        tokio::spawn(tick(Arc::downgrade(&engine), engine.changed.clone()));
+
+        Ok(Self::open(engine))
+    }
+
+    /// Opens another client of the same queue, using and watching only the
+    /// `default` tube.
+    pub fn connect(&self) -> Self {
+        Self::open(self.engine.clone())
+    }
+
+    fn open(engine: Arc<Engine>) -> Self {
+        let client = engine.connect();
+        Self { engine, client }
+    }
+
+    /// Counts a command sent by this client in the server stats.
+    ///
+    /// Every command is counted once, however many jobs it affects. Those
+    /// without a counter of their own are counted as the standard command
+    /// they act as: `reserve-job` as `reserve`, `kick-job` and `kick-jobs` as
+    /// `kick`, and `delete-jobs` as `delete`. Dry runs and other extensions
+    /// aren't counted.
+    fn count(&self, f: impl FnOnce(&mut ServerStats)) {
+        f(&mut self.client.lock().stats);
+    }
+
+    // Producer commands.
+
+    /// Puts a job into the used tube, returning its ID. Jobs with a delay
+    /// become ready once it's passed, in seconds.
+    pub async fn put(
+        &self,
+        pri: u32,
+        delay: u32,
+        ttr: u32,
+        data: Vec<u8>,
+    ) -> Result<u64, Error> {
+        self.count(|s| s.cmd_put += 1);
+        // Spawned so the job's still added once recorded, even if this is
+        // cancelled while it's being recorded.
+        let (engine, client) = (self.engine.clone(), self.client.clone());
+        tokio::spawn(
+            async move { engine.put(&client, pri, delay, ttr, data).await },
+        )
+        .await
+        .expect("put panicked")
+    }
+
+    /// Waits for every change made so far, by any client, to be recorded in
+    /// the store.
+    pub async fn flush(&self) {
+        self.engine.flush().await;
+    }
+
+    /// As `flush`, then compacts the store so it's quick to recover from,
+    /// such as by checkpointing SQLite's WAL. For use just before exiting.
+    pub async fn checkpoint(&self) {
+        self.engine.checkpoint().await;
+    }
+
+    /// Uses a tube for `put`, `peek-*`, and `kick`, returning its name.
+    pub async fn use_tube(&self, tube: &[u8]) -> Vec<u8> {
+        self.count(|s| s.cmd_use += 1);
+        self.engine.use_tube(&self.client, tube)
+    }
+
+    // Worker commands.
+
+    /// Reserves the most urgent ready job in any watched tube, waiting for
+    /// one if there's none.
+    pub async fn reserve(&self) -> Result<JobData, Error> {
+        self.count(|s| s.cmd_reserve += 1);
+        self.wait_for_job(None).await
+    }
+
+    /// As `reserve`, but giving up after `timeout` seconds with `TimedOut`.
+    /// A timeout of zero only reserves a job if one is ready.
+    pub async fn reserve_with_timeout(
+        &self,
+        timeout: u32,
+    ) -> Result<JobData, Error> {
+        self.count(|s| s.cmd_reserve_with_timeout += 1);
+        self.wait_for_job(Some(Duration::from_secs(timeout.into())))
+            .await
+    }
+
+    /// Waits for a job to be reserved, until the timeout passes or the
+    /// deadline of a job already reserved is soon. Cancel-safe: a job
+    /// reserved for a reserve that's dropped is made ready again.
+    ///
+    /// A client waits on one reserve at a time, so starting another ends any
+    /// already waiting with `TimedOut`.
+    async fn wait_for_job(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<JobData, Error> {
+        let now = Instant::now();
+        let wait = timeout != Some(Duration::ZERO);
+
+        let (waiter, rx, soon) =
+            match self.engine.reserve(&self.client, wait, now)? {
+                Reservation::Reserved(job) => return Ok(job),
+                Reservation::Waiting { waiter, rx, soon } => (waiter, rx, soon),
+            };
+
+        let mut waiting = Waiting {
+            queue: self,
+            waiter,
+            rx: Some(rx),
+        };
+        let until =
+            [soon, timeout.map(|t| now + t)].into_iter().flatten().min();
+
+        select! {
+            job = waiting.rx.as_mut().unwrap() => {
+                waiting.rx = None;
+                // Leaves the queues of the tubes that didn't send the job.
+                self.engine.stop_waiting(&waiting.waiter);
+                // Only if another reserve by this client took its place.
+                job.map_err(|_| Error::TimedOut)
+            },
+            () = sleep_until(until.unwrap_or(now)), if until.is_some() => {
+                // A job may have been reserved just as time ran out.
+                if let Some(job) = waiting.stop() {
+                    return Ok(job);
+                }
+                match soon.is_some_and(|soon| soon <= Instant::now()) {
+                    true => Err(Error::DeadlineSoon),
+                    false => Err(Error::TimedOut),
+                }
+            },
+        }
+    }
+
+    /// Reserves a job by its ID, whatever tube it's in, if it isn't already
+    /// reserved.
+    pub async fn reserve_job(&self, id: u64) -> Result<JobData, Error> {
+        self.count(|s| s.cmd_reserve += 1);
+        self.engine.reserve_job(&self.client, id, Instant::now())
+    }
+
+    /// Releases a job reserved by this client back into the ready queue, or
+    /// delays it if `delay` is non-zero.
+    pub async fn release(
+        &self,
+        id: u64,
+        pri: u32,
+        delay: u32,
+    ) -> Result<(), Error> {
+        self.count(|s| s.cmd_release += 1);
+        self.engine
+            .release(&self.client, id, pri, delay, Instant::now())
+    }
+
+    /// Deletes a job reserved by this client, or that isn't reserved.
+    pub async fn delete(&self, id: u64) -> Result<(), Error> {
+        self.count(|s| s.cmd_delete += 1);
+        self.engine.delete(&self.client, id, Instant::now())
+    }
+
+    /// Buries a job reserved by this client.
+    pub async fn bury(&self, id: u64, pri: u32) -> Result<(), Error> {
+        self.count(|s| s.cmd_bury += 1);
+        self.engine.bury(&self.client, id, pri, Instant::now())
+    }
+
+    /// Restarts the TTR of a job reserved by this client.
+    pub async fn touch(&self, id: u64) -> Result<(), Error> {
+        self.count(|s| s.cmd_touch += 1);
+        self.engine.touch(&self.client, id, Instant::now())
+    }
+
+    /// Watches a tube for `reserve`, returning the number of tubes watched.
+    pub async fn watch(&self, tube: &[u8]) -> u32 {
+        self.count(|s| s.cmd_watch += 1);
+        self.engine.watch(&self.client, tube)
+    }
+
+    /// Stops watching a tube, returning the number of tubes still watched.
+    pub async fn ignore(&self, tube: &[u8]) -> Result<u32, Error> {
+        self.count(|s| s.cmd_ignore += 1);
+        self.engine.ignore(&self.client, tube)
+    }
+
+    // Other commands.
+
+    /// Returns a job by its ID, in any state.
+    pub async fn peek(&self, id: u64) -> Result<JobData, Error> {
+        self.count(|s| s.cmd_peek += 1);
+        self.engine.peek(id, Instant::now())
+    }
+
+    /// Returns the next job to be reserved from the used tube.
+    pub async fn peek_ready(&self) -> Result<JobData, Error> {
+        self.count(|s| s.cmd_peek_ready += 1);
+        self.peek_state(JobStateKind::Ready)
+    }
+
+    /// Returns the next delayed job to become ready in the used tube.
+    pub async fn peek_delayed(&self) -> Result<JobData, Error> {
+        self.count(|s| s.cmd_peek_delayed += 1);
+        self.peek_state(JobStateKind::Delayed)
+    }
+
+    /// Returns the next buried job to be kicked in the used tube.
+    pub async fn peek_buried(&self) -> Result<JobData, Error> {
+        self.count(|s| s.cmd_peek_buried += 1);
+        self.peek_state(JobStateKind::Buried)
+    }
+
+    fn peek_state(&self, state: JobStateKind) -> Result<JobData, Error> {
+        self.engine.peek_state(&self.client, state, Instant::now())
+    }
+
+    /// Kicks up to `bound` jobs in the used tube, returning the number kicked.
+    /// Buried jobs are kicked if there are any, otherwise delayed jobs.
+    pub async fn kick(&self, bound: u64) -> u64 {
+        self.count(|s| s.cmd_kick += 1);
+        self.engine.kick(&self.client, bound, Instant::now())
+    }
+
+    /// Kicks a single buried or delayed job.
+    pub async fn kick_job(&self, id: u64) -> Result<(), Error> {
+        self.count(|s| s.cmd_kick += 1);
+        self.engine.kick_job(id, Instant::now())
+    }
+
+    pub async fn stats_job(&self, id: u64) -> Result<JobStats, Error> {
+        self.count(|s| s.cmd_stats_job += 1);
+        self.engine.stats_job(id, Instant::now())
+    }
+
+    pub async fn stats_tube(&self, tube: &[u8]) -> Result<TubeStats, Error> {
+        self.count(|s| s.cmd_stats_tube += 1);
+        self.engine
+            .stats_tube(tube, Instant::now())
+            .ok_or(Error::NotFound)
+    }
+
+    /// Returns the server-wide stats. Connection counts and the largest job
+    /// size are left for the server to fill in.
+    pub async fn stats(&self) -> ServerStats {
+        self.count(|s| s.cmd_stats += 1);
+        self.engine.stats(Instant::now())
+    }
+
+    pub async fn list_tubes(&self) -> Vec<Vec<u8>> {
+        self.count(|s| s.cmd_list_tubes += 1);
+        self.engine.tube_names()
+    }
+
+    pub async fn list_tube_used(&self) -> Vec<u8> {
+        self.count(|s| s.cmd_list_tube_used += 1);
+        self.engine.used_tube(&self.client)
+    }
+
+    pub async fn list_tubes_watched(&self) -> Vec<Vec<u8>> {
+        self.count(|s| s.cmd_list_tubes_watched += 1);
+        self.engine.watched_tubes(&self.client)
+    }
+
+    /// Stops jobs being reserved from a tube for `delay` seconds.
+    pub async fn pause_tube(
+        &self,
+        tube: &[u8],
+        delay: u32,
+    ) -> Result<(), Error> {
+        self.count(|s| s.cmd_pause_tube += 1);
+        self.engine.pause_tube(tube, delay, Instant::now())
+    }
+
+    // Extensions, acting on the jobs a query selects in a tube.
+
+    /// Returns the IDs of the selected jobs.
+    pub async fn query_jobs(&self, tube: &[u8], query: &JobQuery) -> Vec<u64> {
+        self.engine.select(tube, query, Instant::now())
+    }
+
+    /// Sets the priority of the selected jobs, returning how many there were.
+    pub async fn reprioritise_jobs(
+        &self,
+        tube: &[u8],
+        query: &JobQuery,
+        pri: u32,
+    ) -> u64 {
+        self.engine.reprioritise(tube, query, pri, Instant::now())
+    }
+
+    /// Moves the selected jobs to another tube, returning how many there
+    /// were.
+    pub async fn move_jobs(
+        &self,
+        from: &[u8],
+        to: &[u8],
+        query: &JobQuery,
+    ) -> u64 {
+        self.engine.move_jobs(from, to, query, Instant::now())
+    }
+
+    /// Deletes the selected jobs, or with `dry_run`, doesn't, returning
+    /// their IDs either way.
+    pub async fn delete_jobs(
+        &self,
+        tube: &[u8],
+        query: &JobQuery,
+        dry_run: bool,
+    ) -> Vec<u64> {
+        if !dry_run {
+            self.count(|s| s.cmd_delete += 1);
+        }
+        self.engine
+            .delete_jobs(tube, query, dry_run, Instant::now())
+    }
+
+    /// Kicks the selected jobs, or with `dry_run`, doesn't, returning their
+    /// IDs either way.
+    pub async fn kick_jobs(
+        &self,
+        tube: &[u8],
+        query: &JobQuery,
+        dry_run: bool,
+    ) -> Vec<u64> {
+        if !dry_run {
+            self.count(|s| s.cmd_kick += 1);
+        }
+        self.engine.kick_jobs(tube, query, dry_run, Instant::now())
+    }
+
+    /// Returns a receiver of every lifecycle event from now on, to be
+    /// filtered with a `Subscription`.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.engine.subscribe()
+    }
+
+    // Introspection, which isn't counted in the stats.
+
+    /// Returns the tube this client is using.
+    pub fn used_tube(&self) -> Vec<u8> {
+        self.engine.used_tube(&self.client)
+    }
+
+    /// Returns the tubes this client is watching.
+    pub fn watched_tubes(&self) -> Vec<Vec<u8>> {
+        self.engine.watched_tubes(&self.client)
+    }
+
+    /// Returns the tube a job is in, if it exists.
+    pub fn job_tube(&self, id: u64) -> Option<Vec<u8>> {
+        self.engine.job_tube(id)
+    }
+
+    /// Returns whether this client has any jobs reserved.
+    pub fn has_reservations(&self) -> bool {
+        self.engine.has_reservations(&self.client)
+    }
+
+    /// As `stats`, without counting a command.
+    pub fn server_stats(&self) -> ServerStats {
+        self.engine.stats(Instant::now())
+    }
+
+    /// Returns the stats of every tube, without counting any commands.
+    pub fn tube_stats(&self) -> Vec<TubeStats> {
+        let now = Instant::now();
+        self.engine
+            .tube_names()
+            .iter()
+            .filter_map(|tube| self.engine.stats_tube(tube, now))
+            .collect()
+    }
+
+    /// Returns whether the queue is in drain mode.
+    pub fn draining(&self) -> bool {
+        self.engine.draining()
+    }
+
+    /// Enters or leaves drain mode, in which `put` is refused with
+    /// `Draining` while every other command works as usual.
+    pub fn set_draining(&self, draining: bool) {
+        self.engine.set_draining(draining);
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        self.engine.disconnect(&self.client, Instant::now());
+    }
+}
+
+/// A reserve waiting for a job, which stops waiting once dropped.
+struct Waiting<'a> {
+    queue: &'a Queue,
+    waiter: Arc<Waiter>,
+    rx: Option<oneshot::Receiver<JobData>>,
+}
+
+impl Waiting<'_> {
+    /// Stops waiting, returning any job reserved in the meantime.
+    fn stop(&mut self) -> Option<JobData> {
+        let mut rx = self.rx.take()?;
+        self.queue.engine.stop_waiting(&self.waiter);
+        rx.try_recv().ok()
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if let Some(job) = self.stop() {
+            let queue = self.queue;
+            queue
+                .engine
+                .unreserve(&queue.client, job.id, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::ops::RangeInclusive;
+    use std::sync::Mutex;
+
+    use tokio::time::{advance, timeout};
+
+    use super::*;
+    use crate::events::EventKind;
+    use crate::query::Selector;
+
+    /// Stores jobs and pauses in maps shared between queues, as if on disk.
+    #[derive(Clone, Default)]
+    struct MapStore {
+        jobs: Arc<Mutex<BTreeMap<u64, StoredJob>>>,
+        pauses: Arc<Mutex<BTreeMap<Vec<u8>, StoredPause>>>,
+    }
+
+    impl JobStore for MapStore {
+        fn create(&mut self, job: &StoredJob) -> io::Result<()> {
+            self.update(job)
+        }
+
+        fn update(&mut self, job: &StoredJob) -> io::Result<()> {
+            self.jobs.lock().unwrap().insert(job.id, job.clone());
+            Ok(())
+        }
+
+        fn delete(&mut self, id: u64) -> io::Result<()> {
+            self.jobs.lock().unwrap().remove(&id);
+            Ok(())
+        }
+
+        fn recover(&mut self) -> io::Result<Vec<StoredJob>> {
+            Ok(self.jobs.lock().unwrap().values().cloned().collect())
+        }
+
+        fn pause(&mut self, pause: &StoredPause) -> io::Result<()> {
+            let mut pauses = self.pauses.lock().unwrap();
+            pauses.insert(pause.tube.clone(), pause.clone());
+            Ok(())
+        }
+
+        fn recover_pauses(&mut self) -> io::Result<Vec<StoredPause>> {
+            Ok(self.pauses.lock().unwrap().values().cloned().collect())
+        }
+    }
+
+    fn by_id(state: JobStateKind, ids: RangeInclusive<u64>) -> JobQuery {
+        JobQuery {
+            state,
+            selector: Selector::Ids(ids),
+            scan_limit: 0,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lifecycle() {
+        let producer = Queue::new(16);
+        let worker = producer.connect();
+
+        let low = producer.put(10, 0, 5, b"low".to_vec()).await.unwrap();
+        let high = producer.put(1, 0, 5, b"high".to_vec()).await.unwrap();
+        assert_eq!(producer.peek_ready().await.unwrap().id, high);
+
+        // The most urgent job is reserved first.
+        let job = worker.reserve().await.unwrap();
+        assert_eq!(
+            job,
+            JobData {
+                id: high,
+                data: b"high".to_vec()
+            }
+        );
+        assert!(worker.has_reservations());
+
+        // Only the client holding a reservation can act on it.
+        assert_eq!(producer.release(high, 1, 0).await, Err(Error::NotFound));
+        assert_eq!(producer.delete(high).await, Err(Error::NotFound));
+
+        worker.bury(high, 2).await.unwrap();
+        assert_eq!(producer.peek_buried().await.unwrap().id, high);
+        assert_eq!(producer.kick(10).await, 1);
+        assert_eq!(worker.reserve_job(low).await.unwrap().id, low);
+        worker.release(low, 10, 3).await.unwrap();
+        assert_eq!(producer.peek_delayed().await.unwrap().id, low);
+
+        let stats = producer.stats_job(high).await.unwrap();
+        assert_eq!((stats.reserves, stats.buries, stats.kicks), (1, 1, 1));
+
+        // Deleting ready jobs needs no reservation.
+        producer.delete(high).await.unwrap();
+        assert_eq!(producer.peek(high).await, Err(Error::NotFound));
+
+        // Delayed jobs become ready once their delay passes.
+        assert_eq!(worker.reserve_with_timeout(0).await, Err(Error::TimedOut));
+        let job = worker.reserve_with_timeout(5).await.unwrap();
+        assert_eq!(job.id, low);
+
+        let stats = producer.server_stats();
+        assert_eq!(stats.cmd_put, 2);
+        assert_eq!((stats.cmd_reserve, stats.cmd_kick), (2, 1));
+        assert_eq!(stats.current_jobs_reserved, 1);
+        assert_eq!(stats.current_producers, 1);
+        assert_eq!(stats.current_workers, 1);
+        assert_eq!(stats.total_jobs, 2);
+
+        // Dropping a client releases its reservations.
+        drop(worker);
+        assert_eq!(producer.peek_ready().await.unwrap().id, low);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tubes() {
+        let producer = Queue::new(16);
+        let worker = producer.connect();
+
+        assert_eq!(producer.use_tube(b"emails").await, b"emails");
+        assert_eq!(producer.list_tube_used().await, b"emails");
+        producer.put(0, 0, 5, b"email".to_vec()).await.unwrap();
+        assert_eq!(producer.list_tubes().await, [&b"default"[..], b"emails"]);
+
+        // Jobs are only reserved from watched tubes.
+        assert_eq!(worker.reserve_with_timeout(0).await, Err(Error::TimedOut));
+        assert_eq!(worker.watch(b"emails").await, 2);
+        assert_eq!(worker.ignore(b"default").await, Ok(1));
+        assert_eq!(worker.ignore(b"emails").await, Err(Error::NotIgnored));
+
+        // Nor from paused tubes, until they're unpaused.
+        producer.pause_tube(b"emails", 10).await.unwrap();
+        assert_eq!(worker.reserve_with_timeout(0).await, Err(Error::TimedOut));
+        let stats = producer.stats_tube(b"emails").await.unwrap();
+        assert_eq!((stats.pause, stats.current_watching), (10, 1));
+
+        let job = worker.reserve().await.unwrap();
+        assert_eq!(job.data, b"email");
+        worker.delete(job.id).await.unwrap();
+
+        // Tubes are removed once nothing refers to them.
+        drop(worker);
+        producer.use_tube(b"default").await;
+        assert_eq!(producer.list_tubes().await, [b"default"]);
+        assert_eq!(
+            producer.stats_tube(b"emails").await.unwrap_err(),
+            Error::NotFound
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_waiting() {
+        let producer = Queue::new(16);
+        let worker = producer.connect();
+
+        // Waiting workers are handed jobs as they're put.
+        let (job, id) = tokio::join!(worker.reserve(), async {
+            advance(Duration::from_secs(1)).await;
+            producer.put(0, 0, 5, vec![]).await.unwrap()
+        });
+        assert_eq!(job.unwrap().id, id);
+
+        // Jobs time out once their TTR passes, and reserves are refused just
+        // before.
+        assert_eq!(worker.reserve().await, Err(Error::DeadlineSoon));
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(worker.touch(id).await, Err(Error::NotFound));
+        assert_eq!(producer.stats_job(id).await.unwrap().timeouts, 1);
+
+        // A job reserved for a reserve that's dropped is made ready again.
+        producer.delete(id).await.unwrap();
+        let mut reserve = Box::pin(worker.reserve());
+        assert!(timeout(Duration::ZERO, &mut reserve).await.is_err());
+        assert_eq!(producer.server_stats().current_waiting, 1);
+
+        let id = producer.put(0, 0, 5, vec![]).await.unwrap();
+        assert_eq!(producer.server_stats().current_jobs_reserved, 1);
+        drop(reserve);
+        let stats = producer.server_stats();
+        assert_eq!(stats.current_jobs_ready, 1);
+        assert_eq!(stats.current_waiting, 0);
+        assert_eq!(producer.stats_job(id).await.unwrap().reserves, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timekeeping() {
+        let producer = Queue::new(16);
+        let worker = producer.connect();
+
+        // Delayed jobs are handed to waiting clients once the ticker makes
+        // them ready.
+        let started = Instant::now();
+        let id = producer.put(0, 2, 5, vec![]).await.unwrap();
+        assert_eq!(worker.reserve().await.unwrap().id, id);
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+
+        // Waiting reserves end a second before a reserved job times out.
+        let started = Instant::now();
+        assert_eq!(worker.reserve().await, Err(Error::DeadlineSoon));
+        assert_eq!(started.elapsed(), Duration::from_secs(4));
+        worker.delete(id).await.unwrap();
+
+        // Jobs in a paused tube are only handed to waiting clients once the
+        // pause is over...
+        producer.pause_tube(b"default", 10).await.unwrap();
+        let started = Instant::now();
+        let (job, id) = tokio::join!(worker.reserve(), async {
+            producer.put(0, 0, 5, vec![]).await.unwrap()
+        });
+        assert_eq!(job.unwrap().id, id);
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+        worker.delete(id).await.unwrap();
+
+        // ...or the tube is unpaused.
+        producer.pause_tube(b"default", 10).await.unwrap();
+        let started = Instant::now();
+        let (job, id) = tokio::join!(worker.reserve(), async {
+            let id = producer.put(0, 0, 5, vec![]).await.unwrap();
+            advance(Duration::from_secs(1)).await;
+            producer.pause_tube(b"default", 0).await.unwrap();
+            id
+        });
+        assert_eq!(job.unwrap().id, id);
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_events() {
+        let producer = Queue::new(16);
+        let worker = producer.connect();
+        let mut events = producer.subscribe();
+
+        let id = producer.put(0, 0, 5, vec![]).await.unwrap();
+        worker.reserve().await.unwrap();
+        worker.touch(id).await.unwrap();
+        worker.release(id, 0, 0).await.unwrap();
+        worker.reserve_job(id).await.unwrap();
+
+        // A reserve dropped once its job's sent puts it back unseen.
+        worker.delete(id).await.unwrap();
+        let mut reserve = Box::pin(worker.reserve());
+        assert!(timeout(Duration::ZERO, &mut reserve).await.is_err());
+        let id = producer.put(0, 0, 5, vec![]).await.unwrap();
+        drop(reserve);
+
+        let mut kinds = vec![];
+        while let Ok(event) = events.try_recv() {
+            kinds.push(event.event);
+        }
+        // Only reserving publishes reserve events, not touching.
+        use EventKind::*;
+        assert_eq!(
+            kinds,
+            [Put, Reserve, Release, Reserve, Delete, Put, Reserve]
+        );
+        assert_eq!(producer.stats_job(id).await.unwrap().reserves, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bulk() {
+        let queue = Queue::new(16);
+        for _ in 0..4 {
+            queue.put(0, 0, 5, vec![]).await.unwrap();
+        }
+        for _ in 0..2 {
+            let job = queue.reserve().await.unwrap();
+            queue.bury(job.id, 0).await.unwrap();
+        }
+
+        let buried = by_id(JobStateKind::Buried, 1..=4);
+        assert_eq!(queue.query_jobs(b"default", &buried).await, [1, 2]);
+        assert_eq!(queue.kick_jobs(b"default", &buried, true).await, [1, 2]);
+        assert_eq!(queue.peek_buried().await.unwrap().id, 1);
+
+        let ready = by_id(JobStateKind::Ready, 1..=4);
+        assert_eq!(queue.reprioritise_jobs(b"default", &ready, 5).await, 2);
+        assert_eq!(queue.move_jobs(b"default", b"b", &buried).await, 2);
+        assert_eq!(queue.query_jobs(b"b", &buried).await, [1, 2]);
+        assert_eq!(queue.delete_jobs(b"default", &ready, true).await, [3, 4]);
+        assert_eq!(queue.delete_jobs(b"default", &ready, false).await, [3, 4]);
+        assert_eq!(queue.server_stats().current_jobs_ready, 0);
+
+        assert_eq!(queue.kick_jobs(b"b", &buried, false).await, [1, 2]);
+
+        // Bulk commands count once, as the command they act as, unless
+        // they're dry runs.
+        let stats = queue.server_stats();
+        assert_eq!((stats.cmd_delete, stats.cmd_kick), (1, 1));
+        assert_eq!(queue.stats_tube(b"default").await.unwrap().cmd_delete, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_buried_order() {
+        let queue = Queue::new(16);
+        for _ in 0..4 {
+            queue.put(0, 0, 5, vec![]).await.unwrap();
+        }
+        for id in [3, 1, 4, 2] {
+            queue.reserve_job(id).await.unwrap();
+            queue.bury(id, 0).await.unwrap();
+        }
+
+        // Jobs taken from the middle of the buried jobs leave the rest in the
+        // order they were buried.
+        let middle = by_id(JobStateKind::Buried, 4..=4);
+        assert_eq!(queue.delete_jobs(b"default", &middle, false).await, [4]);
+        queue.kick_job(1).await.unwrap();
+        queue.reserve_job(1).await.unwrap();
+        queue.bury(1, 0).await.unwrap();
+        assert_eq!(queue.peek_buried().await.unwrap().id, 3);
+        assert_eq!(queue.kick(2).await, 2);
+        assert_eq!(queue.peek_buried().await.unwrap().id, 1);
+        assert_eq!(queue.peek_ready().await.unwrap().id, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_move() {
+        let producer = Queue::new(16);
+        let worker = producer.connect();
+        let mut events = producer.subscribe();
+        worker.watch(b"b").await;
+        worker.ignore(b"default").await.unwrap();
+
+        // Moved jobs are handed to clients waiting on their new tube.
+        let ready = by_id(JobStateKind::Ready, 1..=1);
+        let (job, moved) = tokio::join!(worker.reserve(), async {
+            producer.put(0, 0, 5, vec![]).await.unwrap();
+            advance(Duration::from_secs(1)).await;
+            producer.move_jobs(b"default", b"b", &ready).await
+        });
+        assert_eq!((job.unwrap().id, moved), (1, 1));
+        worker.delete(1).await.unwrap();
+
+        // Delayed jobs keep their deadline.
+        let started = Instant::now();
+        let id = producer.put(0, 10, 5, vec![]).await.unwrap();
+        advance(Duration::from_secs(4)).await;
+        let delayed = by_id(JobStateKind::Delayed, id..=id);
+        assert_eq!(producer.move_jobs(b"default", b"b", &delayed).await, 1);
+        assert_eq!(worker.reserve().await.unwrap().id, id);
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+
+        assert_eq!(producer.stats_tube(b"b").await.unwrap().total_jobs, 2);
+        let mut moves = vec![];
+        while let Ok(event) = events.try_recv() {
+            if event.event == EventKind::Move {
+                moves.push((event.from.unwrap(), event.tube, event.id));
+            }
+        }
+        let moved = |id| (b"default".to_vec(), b"b".to_vec(), Some(id));
+        assert_eq!(moves, [moved(1), moved(id)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reprioritise() {
+        let queue = Queue::new(16);
+        for pri in [1, 2, 3] {
+            queue.put(pri, 0, 5, vec![]).await.unwrap();
+        }
+
+        // Reprioritised jobs are reserved in their new order.
+        let ready = |id| by_id(JobStateKind::Ready, id..=id);
+        assert_eq!(queue.reprioritise_jobs(b"default", &ready(3), 0).await, 1);
+        assert_eq!(queue.reprioritise_jobs(b"default", &ready(1), 9).await, 1);
+        assert_eq!(queue.peek_ready().await.unwrap().id, 3);
+        for id in [3, 2, 1] {
+            assert_eq!(queue.reserve().await.unwrap().id, id);
+        }
+        assert_eq!(queue.stats_job(1).await.unwrap().pri, 9);
+
+        // Reserved jobs keep their priority until released.
+        let reserved = by_id(JobStateKind::Reserved, 1..=3);
+        assert_eq!(queue.reprioritise_jobs(b"default", &reserved, 0).await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_store() {
+        let store = MapStore::default();
+        let queue = Queue::with_store(16, store.clone()).unwrap();
+
+        queue.use_tube(b"a").await;
+        let ready = queue.put(1, 0, 5, b"ready".to_vec()).await.unwrap();
+        let buried = queue.put(2, 0, 5, b"buried".to_vec()).await.unwrap();
+        let deleted = queue.put(3, 0, 5, vec![]).await.unwrap();
+        let delayed = queue.put(4, 60, 5, vec![]).await.unwrap();
+        queue.reserve_job(buried).await.unwrap();
+        queue.bury(buried, 5).await.unwrap();
+        queue.delete(deleted).await.unwrap();
+        // Reservations aren't recorded.
+        queue.reserve_job(ready).await.unwrap();
+        queue.pause_tube(b"a", 60).await.unwrap();
+        queue.flush().await;
+        drop(queue);
+
+        let queue = Queue::with_store(16, store).unwrap();
+        queue.use_tube(b"a").await;
+        assert_eq!(queue.peek_ready().await.unwrap().id, ready);
+        assert_eq!(queue.peek_delayed().await.unwrap().id, delayed);
+        let stats = queue.stats_job(buried).await.unwrap();
+        assert_eq!((stats.pri, stats.buries), (5, 1));
+        assert_eq!(queue.peek(deleted).await, Err(Error::NotFound));
+
+        // So are tubes' pauses.
+        let stats = queue.stats_tube(b"a").await.unwrap();
+        assert_eq!(stats.pause, 60);
+        assert!((58..=60).contains(&stats.pause_time_left));
+
+        // IDs carry on from those recovered.
+        assert_eq!(queue.put(0, 0, 5, vec![]).await, Ok(delayed + 1));
+    }
+}
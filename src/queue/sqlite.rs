@@ -0,0 +1,408 @@
+//! A `JobStore` keeping jobs in a SQLite database, so they can be recovered
+//! after a restart or crash, and inspected with standard SQL tools while the
+//! queue is stopped.
+//!
+//! Changes are committed in batches, each batch in one transaction, and a job
+//! is durable once its `INSERTED` is sent. Other changes are committed with
+//! the batch they're made in, moments later, so a crash may lose the last few
+//! of them. The database is in WAL mode with full syncing, its cost shared
+//! between every change in a batch.
+//!
+//! Jobs are kept in the `jobs` table, one row each, and the `tubes` view
+//! counts them by tube and state:
+//!
+//! ```sql
+//! SELECT id, pri, state, data FROM jobs WHERE tube = 'emails';
+//! SELECT * FROM tubes;
+//! ```
+//!
+//! Delayed jobs' `delayed_until` is in milliseconds since the Unix epoch, as
+//! is `paused_until` in the `pauses` table, which keeps each tube's last
+//! pause. Reserved jobs are stored in the state they were in before being
+//! reserved.
+//!
+//! SQLite itself is built into the binary, so needs nothing installed.
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::types::{ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, Row};
+
+use super::store::{JobStore, StoredJob, StoredPause, StoredState};
+
+/// The version of the schema below, kept in the database's `user_version`.
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE jobs (
+        id INTEGER PRIMARY KEY,
+        tube TEXT NOT NULL,
+        pri INTEGER NOT NULL,
+        delay INTEGER NOT NULL,
+        ttr INTEGER NOT NULL,
+        state TEXT NOT NULL CHECK (state IN ('ready', 'delayed', 'buried')),
+        delayed_until INTEGER,
+        reserves INTEGER NOT NULL,
+        timeouts INTEGER NOT NULL,
+        releases INTEGER NOT NULL,
+        buries INTEGER NOT NULL,
+        kicks INTEGER NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE INDEX jobs_by_tube ON jobs (tube, state);
+    CREATE VIEW tubes AS
+        SELECT
+            tube AS name,
+            sum(state = 'ready') AS ready,
+            sum(state = 'delayed') AS delayed,
+            sum(state = 'buried') AS buried
+        FROM jobs
+        GROUP BY tube;
+    CREATE TABLE pauses (
+        tube TEXT PRIMARY KEY,
+        pause INTEGER NOT NULL,
+        paused_until INTEGER NOT NULL
+    );
+    PRAGMA user_version = 1;
+";
+
+/// Stores jobs in a SQLite database file.
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it if need be.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let conn = Connection::open(path).map_err(io::Error::other)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL; PRAGMA synchronous = FULL;",
+        )
+        .map_err(io::Error::other)?;
+
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(io::Error::other)?;
+        match version {
+            0 => conn
+                .execute_batch(&format!("BEGIN; {SCHEMA} COMMIT;"))
+                .map_err(io::Error::other)?,
+            SCHEMA_VERSION => {},
+            _ => {
+                return Err(io::Error::other(format!(
+                    "unsupported schema version {version}"
+                )))
+            },
+        }
+
+        Ok(Self { conn })
+    }
+}
+
+fn state_name(state: &StoredState) -> &'static str {
+    match state {
+        StoredState::Ready => "ready",
+        StoredState::Delayed { .. } => "delayed",
+        StoredState::Buried => "buried",
+    }
+}
+
+/// Returns a time in milliseconds since the Unix epoch.
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        .try_into()
+        .unwrap_or(i64::MAX)
+}
+
+fn delayed_until(state: &StoredState) -> Option<i64> {
+    match state {
+        StoredState::Delayed { until } => Some(millis(*until)),
+        _ => None,
+    }
+}
+
+/// Binds a tube name as text rather than a blob, so it can be compared with
+/// string literals.
+fn text(bytes: &[u8]) -> ToSqlOutput<'_> {
+    ToSqlOutput::Borrowed(ValueRef::Text(bytes))
+}
+
+fn to_job(row: &Row<'_>) -> rusqlite::Result<StoredJob> {
+    let state = match row.get_ref(5)?.as_str()? {
+        "ready" => StoredState::Ready,
+        "delayed" => StoredState::Delayed {
+            until: UNIX_EPOCH + Duration::from_millis(row.get(6)?),
+        },
+        "buried" => StoredState::Buried,
+        other => {
+            return Err(rusqlite::Error::InvalidColumnType(
+                5,
+                other.to_owned(),
+                rusqlite::types::Type::Text,
+            ))
+        },
+    };
+
+    Ok(StoredJob {
+        id: row.get(0)?,
+        tube: row.get_ref(1)?.as_bytes()?.to_vec(),
+        pri: row.get(2)?,
+        delay: row.get(3)?,
+        ttr: row.get(4)?,
+        data: row.get(12)?,
+        state,
+        reserves: row.get(7)?,
+        timeouts: row.get(8)?,
+        releases: row.get(9)?,
+        buries: row.get(10)?,
+        kicks: row.get(11)?,
+    })
+}
+
+impl SqliteStore {
+    /// Starts a transaction for the changes until the next flush, unless one
+    /// has been already.
+    fn begin(&self) -> io::Result<()> {
+        if self.conn.is_autocommit() {
+            self.conn.execute_batch("BEGIN").map_err(io::Error::other)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl JobStore for SqliteStore {
+    fn create(&mut self, job: &StoredJob) -> io::Result<()> {
+        self.begin()?;
+        let mut stmt = self
+            .conn
+            .prepare_cached(
+                "INSERT INTO jobs (
+                    id, tube, pri, delay, ttr, state, delayed_until, reserves,
+                    timeouts, releases, buries, kicks, data
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .map_err(io::Error::other)?;
+        stmt.execute(params![
+            job.id,
+            text(&job.tube),
+            job.pri,
+            job.delay,
+            job.ttr,
+            state_name(&job.state),
+            delayed_until(&job.state),
+            job.reserves,
+            job.timeouts,
+            job.releases,
+            job.buries,
+            job.kicks,
+            job.data,
+        ])
+        .map_err(io::Error::other)?;
+
+        Ok(())
+    }
+
+    fn update(&mut self, job: &StoredJob) -> io::Result<()> {
+        self.begin()?;
+        // Bodies never change, so needn't be written again.
+        let mut stmt = self
+            .conn
+            .prepare_cached(
+                "UPDATE jobs SET
+                    tube = ?, pri = ?, delay = ?, state = ?, delayed_until = ?,
+                    reserves = ?, timeouts = ?, releases = ?, buries = ?,
+                    kicks = ?
+                WHERE id = ?",
+            )
+            .map_err(io::Error::other)?;
+        stmt.execute(params![
+            text(&job.tube),
+            job.pri,
+            job.delay,
+            state_name(&job.state),
+            delayed_until(&job.state),
+            job.reserves,
+            job.timeouts,
+            job.releases,
+            job.buries,
+            job.kicks,
+            job.id,
+        ])
+        .map_err(io::Error::other)?;
+
+        Ok(())
+    }
+
+    fn delete(&mut self, id: u64) -> io::Result<()> {
+        self.begin()?;
+        self.conn
+            .prepare_cached("DELETE FROM jobs WHERE id = ?")
+            .and_then(|mut stmt| stmt.execute([id]))
+            .map_err(io::Error::other)?;
+
+        Ok(())
+    }
+
+    fn recover(&mut self) -> io::Result<Vec<StoredJob>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT
+                    id, tube, pri, delay, ttr, state, delayed_until, reserves,
+                    timeouts, releases, buries, kicks, data
+                FROM jobs ORDER BY id",
+            )
+            .map_err(io::Error::other)?;
+
+        stmt.query_map([], to_job)
+            .and_then(|rows| rows.collect())
+            .map_err(io::Error::other)
+    }
+
+    fn pause(&mut self, pause: &StoredPause) -> io::Result<()> {
+        self.begin()?;
+        self.conn
+            .prepare_cached(
+                "INSERT OR REPLACE INTO pauses (tube, pause, paused_until)
+                VALUES (?, ?, ?)",
+            )
+            .and_then(|mut stmt| {
+                stmt.execute(params![
+                    text(&pause.tube),
+                    pause.pause,
+                    millis(pause.until),
+                ])
+            })
+            .map_err(io::Error::other)?;
+
+        Ok(())
+    }
+
+    fn recover_pauses(&mut self) -> io::Result<Vec<StoredPause>> {
+        // Pauses that have ended are forgotten.
+        self.conn
+            .execute(
+                "DELETE FROM pauses WHERE paused_until <= ?",
+                [millis(SystemTime::now())],
+            )
+            .map_err(io::Error::other)?;
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT tube, pause, paused_until FROM pauses")
+            .map_err(io::Error::other)?;
+        stmt.query_map([], |row| {
+            Ok(StoredPause {
+                tube: row.get_ref(0)?.as_bytes()?.to_vec(),
+                pause: row.get(1)?,
+                until: UNIX_EPOCH + Duration::from_millis(row.get(2)?),
+            })
+        })
+        .and_then(|rows| rows.collect())
+        .map_err(io::Error::other)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.conn.is_autocommit() {
+            self.conn
+                .execute_batch("COMMIT")
+                .map_err(io::Error::other)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the WAL into the database and empties it, so the next open
+    /// needn't replay it.
+    fn checkpoint(&mut self) -> io::Result<()> {
+        let busy: bool = self
+            .conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))
+            .map_err(io::Error::other)?;
+        match busy {
+            true => Err(io::Error::other("database busy")),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_sqlite_store() {
+        let dir = std::env::temp_dir()
+            .join(format!("ebeans-test-sqlite-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("jobs.db");
+
+        let until = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let job = |id, state| StoredJob {
+            id,
+            tube: b"emails".to_vec(),
+            pri: 10,
+            delay: 0,
+            ttr: 60,
+            data: b"body\r\n\0".to_vec(),
+            state,
+            reserves: 0,
+            timeouts: 0,
+            releases: 0,
+            buries: 0,
+            kicks: 0,
+        };
+
+        let mut store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.recover().unwrap(), []);
+        store.create(&job(1, StoredState::Ready)).unwrap();
+        store.create(&job(2, StoredState::Ready)).unwrap();
+        store
+            .create(&job(3, StoredState::Delayed { until }))
+            .unwrap();
+        assert!(store.create(&job(1, StoredState::Ready)).is_err());
+
+        let buried = StoredJob {
+            pri: 20,
+            buries: 1,
+            ..job(2, StoredState::Buried)
+        };
+        store.update(&buried).unwrap();
+        store.delete(1).unwrap();
+
+        // Only pauses yet to end are recovered.
+        let pause = |tube: &[u8], until| StoredPause {
+            tube: tube.to_vec(),
+            pause: 60,
+            until,
+        };
+        let later = SystemTime::now() + Duration::from_secs(60);
+        store.pause(&pause(b"emails", later)).unwrap();
+        store.pause(&pause(b"old", UNIX_EPOCH)).unwrap();
+        store.flush().unwrap();
+
+        // Checkpointing empties the WAL into the database.
+        let wal = path.with_extension("db-wal");
+        assert!(fs::metadata(&wal).unwrap().len() > 0);
+        store.checkpoint().unwrap();
+        assert_eq!(fs::metadata(&wal).unwrap().len(), 0);
+        drop(store);
+
+        let mut store = SqliteStore::open(&path).unwrap();
+        assert_eq!(
+            store.recover().unwrap(),
+            [buried, job(3, StoredState::Delayed { until })]
+        );
+        let later = UNIX_EPOCH + Duration::from_millis(millis(later) as u64);
+        assert_eq!(store.recover_pauses().unwrap(), [pause(b"emails", later)]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
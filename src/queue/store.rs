@@ -0,0 +1,273 @@
+//! Persistence for a queue's jobs, behind the `JobStore` trait.
+//!
+//! The engine keeps every job in memory, and records each change to a job in
+//! its store as it happens, so the jobs can be recovered when it next starts.
+//! Reserves, touches, and timeouts aren't recorded, so a reserved job is
+//! recovered in the state it was in before it was reserved, as with
+//! beanstalkd. Tubes exist only while they have jobs or clients, so aren't
+//! recorded either, but their pauses are, and restored to the tubes of jobs
+//! recovered.
+use std::io;
+use std::time::SystemTime;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tracing::error;
+
+/// A job as recorded in a store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoredJob {
+    pub id: u64,
+    pub tube: Vec<u8>,
+    pub pri: u32,
+    /// The delay given by the last put or release, in seconds.
+    pub delay: u32,
+    pub ttr: u32,
+    pub data: Vec<u8>,
+    pub state: StoredState,
+    pub reserves: u64,
+    pub timeouts: u64,
+    pub releases: u64,
+    pub buries: u64,
+    pub kicks: u64,
+}
+
+/// The state of a stored job. Delays are kept as wall-clock times, so they
+/// carry on passing while the queue isn't running.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StoredState {
+    Ready,
+    Delayed { until: SystemTime },
+    Buried,
+}
+
+/// A tube's pause as recorded in a store, with its end as a wall-clock time
+/// like a delayed job's.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoredPause {
+    pub tube: Vec<u8>,
+    /// The delay given by the last `pause-tube`, in seconds.
+    pub pause: u32,
+    pub until: SystemTime,
+}
+
+/// Somewhere to record jobs, and to recover them from on startup.
+///
+/// Changes are written in batches on a blocking thread, off the engine's
+/// locks, each batch followed by a `flush`. A put is only answered once its
+/// job's batch is flushed, and if `create` or the flush fails, the job is
+/// refused with `INTERNAL_ERROR`. Other failures are logged, leaving the
+/// engine to carry on without the change recorded.
+pub trait JobStore: Send {
+    /// Records a newly put job.
+    fn create(&mut self, job: &StoredJob) -> io::Result<()>;
+
+    /// Records a job's state, priority, tube, or counters changing, with the
+    /// job as it now is.
+    fn update(&mut self, job: &StoredJob) -> io::Result<()>;
+
+    /// Records a job being deleted.
+    fn delete(&mut self, id: u64) -> io::Result<()>;
+
+    /// Returns every job recorded and not deleted, to restore when the engine
+    /// starts.
+    fn recover(&mut self) -> io::Result<Vec<StoredJob>>;
+
+    /// Records a tube being paused, or unpaused with a pause of zero,
+    /// replacing any pause recorded for it before.
+    fn pause(&mut self, _pause: &StoredPause) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the pauses recorded that are yet to end, to restore to any
+    /// tubes with jobs when the engine starts.
+    fn recover_pauses(&mut self) -> io::Result<Vec<StoredPause>> {
+        Ok(vec![])
+    }
+
+    /// Makes the changes since the last flush durable, such as by committing
+    /// them in one transaction.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Compacts what's been recorded so far, so it's quick to recover from,
+    /// before the queue stops.
+    fn checkpoint(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps jobs only in memory, as the engine does anyway, so they're lost
+/// once the process exits.
+#[derive(Debug, Default)]
+pub struct MemoryStore;
+
+impl JobStore for MemoryStore {
+    fn create(&mut self, _: &StoredJob) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn update(&mut self, _: &StoredJob) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&mut self, _: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn recover(&mut self) -> io::Result<Vec<StoredJob>> {
+        Ok(vec![])
+    }
+}
+
+/// The most changes written to the store in one batch.
+const BATCH: usize = 1024;
+
+/// A change to be recorded in the store.
+enum Change {
+    /// Creates a job, then says whether it's been recorded.
+    Create(StoredJob, oneshot::Sender<bool>),
+    Update(StoredJob),
+    Delete(u64),
+    Pause(StoredPause),
+    /// Says once every change before it has been recorded.
+    Flush(oneshot::Sender<()>),
+    /// As `Flush`, then checkpoints the store.
+    Checkpoint(oneshot::Sender<()>),
+}
+
+/// Records changes in a store without holding up the engine, by queuing them
+/// for a task to write in batches. Each batch is written on a blocking
+/// thread then flushed as one, so every client's changes share a commit.
+#[derive(Debug)]
+pub(super) struct Writer(mpsc::UnboundedSender<Change>);
+
+impl Writer {
+    /// Starts writing to `store`, until the writer's dropped.
+    pub(super) fn spawn(store: Box<dyn JobStore>) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write(store, rx));
+        Self(tx)
+    }
+
+    fn send(&self, change: Change) {
+        if self.0.send(change).is_err() {
+            error!("failed to record change: store writer stopped");
+        }
+    }
+
+    /// Records a new job, returning whether it was recorded.
+    pub(super) async fn create(&self, job: StoredJob) -> bool {
+        let (tx, rx) = oneshot::channel();
+        self.send(Change::Create(job, tx));
+        rx.await.unwrap_or(false)
+    }
+
+    pub(super) fn update(&self, job: StoredJob) {
+        self.send(Change::Update(job));
+    }
+
+    pub(super) fn delete(&self, id: u64) {
+        self.send(Change::Delete(id));
+    }
+
+    pub(super) fn pause(&self, pause: StoredPause) {
+        self.send(Change::Pause(pause));
+    }
+
+    /// Waits for every change made so far to be recorded.
+    pub(super) async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+        self.send(Change::Flush(tx));
+        let _ = rx.await;
+    }
+
+    /// Waits for every change made so far to be recorded, then checkpoints
+    /// the store.
+    pub(super) async fn checkpoint(&self) {
+        let (tx, rx) = oneshot::channel();
+        self.send(Change::Checkpoint(tx));
+        let _ = rx.await;
+    }
+}
+
+/// Writes each batch of changes received on `rx` to `store`.
+async fn write(
+    mut store: Box<dyn JobStore>,
+    mut rx: mpsc::UnboundedReceiver<Change>,
+) {
+    let mut changes = Vec::with_capacity(BATCH);
+    while rx.recv_many(&mut changes, BATCH).await > 0 {
+        let batch = std::mem::replace(&mut changes, Vec::with_capacity(BATCH));
+        store = match task::spawn_blocking(move || write_batch(store, batch))
+            .await
+        {
+            Ok(store) => store,
+            Err(error) => {
+                error!(%error, "store writer failed");
+                return;
+            },
+        };
+    }
+}
+
+fn write_batch(
+    mut store: Box<dyn JobStore>,
+    batch: Vec<Change>,
+) -> Box<dyn JobStore> {
+    let mut created = vec![];
+    let mut flushes = vec![];
+    let mut checkpoints = vec![];
+    for change in batch {
+        match change {
+            Change::Create(job, tx) => match store.create(&job) {
+                Ok(()) => created.push(tx),
+                Err(error) => {
+                    error!(id = job.id, %error, "failed to record job");
+                    let _ = tx.send(false);
+                },
+            },
+            Change::Update(job) => {
+                if let Err(error) = store.update(&job) {
+                    error!(id = job.id, %error, "failed to record job");
+                }
+            },
+            Change::Delete(id) => {
+                if let Err(error) = store.delete(id) {
+                    error!(id, %error, "failed to record job deletion");
+                }
+            },
+            Change::Pause(pause) => {
+                if let Err(error) = store.pause(&pause) {
+                    let tube = String::from_utf8_lossy(&pause.tube);
+                    error!(%tube, %error, "failed to record pause");
+                }
+            },
+            Change::Flush(tx) => flushes.push(tx),
+            Change::Checkpoint(tx) => checkpoints.push(tx),
+        }
+    }
+
+    let flushed = store.flush();
+    if let Err(error) = &flushed {
+        error!(%error, "failed to flush store");
+    }
+    for tx in created {
+        let _ = tx.send(flushed.is_ok());
+    }
+    for tx in flushes {
+        let _ = tx.send(());
+    }
+
+    if !checkpoints.is_empty() {
+        if let Err(error) = store.checkpoint() {
+            error!(%error, "failed to checkpoint store");
+        }
+        for tx in checkpoints {
+            let _ = tx.send(());
+        }
+    }
+
+    store
+}
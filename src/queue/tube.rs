@@ -0,0 +1,760 @@
+//! A single tube: the jobs in it, indexed by state, and the clients waiting
+//! to reserve from it. Each tube is a shard of the engine with its own lock.
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use tokio::time::{Duration, Instant};
+
+use super::engine::{to_stored, Client, Engine, Waiter};
+use super::store::StoredPause;
+use super::{Error, JobData};
+use crate::events::{Event, EventKind};
+use crate::query::JobQuery;
+use crate::types::job::Job;
+use crate::types::protocol::{JobStats, ServerStats, TubeStats};
+use crate::types::states::{JobState, JobStateKind};
+
+/// Ready jobs with a priority below this are urgent.
+const URGENT: u32 = 1024;
+
+/// A tube, locked on its own.
+#[derive(Debug)]
+pub(super) struct Shard {
+    pub(super) name: Vec<u8>,
+    tube: Mutex<Tube>,
+}
+
+impl Shard {
+    pub(super) fn new(name: &[u8]) -> Self {
+        Self {
+            name: name.to_vec(),
+            tube: Mutex::default(),
+        }
+    }
+
+    pub(super) fn lock(&self) -> MutexGuard<'_, Tube> {
+        self.tube.lock().unwrap()
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Tube {
+    /// Every job in the tube, in any state, in the order they were created.
+    jobs: BTreeMap<u64, Job>,
+    /// Ready jobs, in the order they're reserved.
+    ready: BTreeSet<(u32, u64)>,
+    /// Delayed jobs, in the order they become ready.
+    delayed: BTreeSet<(Instant, u64)>,
+    /// Buried jobs, in the order they're kicked, by when they were buried.
+    buried: BTreeSet<(u64, u64)>,
+    /// When each buried job was buried, counting burials in this tube.
+    burials: HashMap<u64, u64>,
+    next_burial: u64,
+    /// Reserved jobs, in the order their TTRs run out.
+    deadlines: BTreeSet<(Instant, u64)>,
+    /// The client each reserved job is reserved by.
+    reservers: HashMap<u64, Arc<Client>>,
+    /// Reserves waiting on a job from this tube, among any others their
+    /// clients watch, in the order they started waiting.
+    waiters: VecDeque<Arc<Waiter>>,
+    /// Clients using and watching the tube, and jobs put to it still being
+    /// recorded, which keep it from being removed.
+    pub(super) using: u64,
+    pub(super) watching: u64,
+    pub(super) pending: u64,
+    pub(super) total_jobs: u64,
+    cmd_delete: u64,
+    cmd_pause_tube: u64,
+    /// The delay given by the last `pause-tube`, in seconds.
+    pause: u32,
+    paused_until: Option<Instant>,
+}
+
+impl Tube {
+    fn is_paused(&self, now: Instant) -> bool {
+        self.paused_until.is_some_and(|until| until > now)
+    }
+
+    pub(super) fn is_unused(&self) -> bool {
+        self.jobs.is_empty()
+            && self.using == 0
+            && self.watching == 0
+            && self.pending == 0
+    }
+
+    pub(super) fn contains(&self, id: u64) -> bool {
+        self.jobs.contains_key(&id)
+    }
+
+    fn publish(&self, engine: &Engine, event: EventKind, id: u64) {
+        let tube = self.jobs[&id].tube.clone();
+        engine.events.publish(Event::job(event, tube, id));
+    }
+
+    /// Records a job's changes in the store.
+    fn record(&self, engine: &Engine, id: u64, now: Instant) {
+        engine.writer.update(to_stored(&self.jobs[&id], now));
+    }
+
+    fn data(&self, id: u64) -> JobData {
+        JobData {
+            id,
+            data: self.jobs[&id].data.clone(),
+        }
+    }
+
+    fn job_mut(&mut self, id: u64) -> &mut Job {
+        self.jobs.get_mut(&id).unwrap()
+    }
+
+    /// Returns a job if it's reserved by `client`.
+    fn reserved_by(&self, id: u64, client: u64) -> Result<&Job, Error> {
+        match self.jobs.get(&id) {
+            Some(
+                job @ Job {
+                    state: JobState::Reserved { by, .. },
+                    ..
+                },
+            ) if *by == client => Ok(job),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    // Adding and removing jobs.
+
+    /// Adds a new job to the tube in the state it's in, which mustn't be
+    /// reserved.
+    pub(super) fn insert(
+        &mut self,
+        engine: &Engine,
+        shard: &Arc<Shard>,
+        mut job: Job,
+    ) {
+        let id = job.id;
+        let state = job.state;
+        job.state = JobState::Ready;
+        job.tube = shard.name.clone();
+        self.jobs.insert(id, job);
+        engine.index.insert(id, shard);
+
+        match state {
+            JobState::Delayed { until } => {
+                self.attach_delayed(engine, id, until)
+            },
+            JobState::Buried => self.attach_buried(id),
+            JobState::Ready | JobState::Reserved { .. } => {
+                self.attach_ready(id)
+            },
+        }
+    }
+
+    /// Takes a job out of the tube, leaving it in the state it was in, which
+    /// mustn't be reserved, to be inserted into another.
+    fn take(&mut self, id: u64) -> Job {
+        self.detach(id);
+        self.jobs.remove(&id).unwrap()
+    }
+
+    /// Removes a job entirely.
+    fn remove(&mut self, engine: &Engine, id: u64) {
+        engine.writer.delete(id);
+        self.detach(id);
+        self.jobs.remove(&id);
+        engine.index.remove(id);
+    }
+
+    // Moving jobs between states.
+
+    /// Removes a job from the index of its current state, leaving it to be
+    /// attached to another.
+    fn detach(&mut self, id: u64) {
+        let job = &self.jobs[&id];
+        match job.state {
+            JobState::Ready => {
+                self.ready.remove(&(job.pri, id));
+            },
+            JobState::Delayed { until } => {
+                self.delayed.remove(&(until, id));
+            },
+            JobState::Buried => {
+                let burial = self.burials.remove(&id).unwrap();
+                self.buried.remove(&(burial, id));
+            },
+            JobState::Reserved { at, .. } => {
+                let ttr = Duration::from_secs(job.ttr.into());
+                self.deadlines.remove(&(at + ttr, id));
+                if let Some(client) = self.reservers.remove(&id) {
+                    client.lock().reserved.remove(&id);
+                }
+            },
+        }
+    }
+
+    fn attach_ready(&mut self, id: u64) {
+        let job = self.job_mut(id);
+        job.state = JobState::Ready;
+        let key = (job.pri, id);
+        self.ready.insert(key);
+    }
+
+    fn attach_delayed(&mut self, engine: &Engine, id: u64, until: Instant) {
+        self.job_mut(id).state = JobState::Delayed { until };
+        self.delayed.insert((until, id));
+        engine.schedule(until);
+    }
+
+    fn attach_buried(&mut self, id: u64) {
+        self.job_mut(id).state = JobState::Buried;
+        let burial = self.next_burial;
+        self.next_burial += 1;
+        self.buried.insert((burial, id));
+        self.burials.insert(id, burial);
+    }
+
+    fn attach_reserved(
+        &mut self,
+        engine: &Engine,
+        id: u64,
+        client: &Arc<Client>,
+        now: Instant,
+    ) {
+        let job = self.job_mut(id);
+        job.state = JobState::Reserved {
+            at: now,
+            by: client.id,
+        };
+        job.reserves += 1;
+        let deadline = now + Duration::from_secs(job.ttr.into());
+
+        self.deadlines.insert((deadline, id));
+        self.reservers.insert(id, client.clone());
+        client.lock().reserved.insert(id, deadline);
+        engine.schedule(deadline);
+    }
+
+    /// Makes a job ready, or delays it if `delay` is non-zero.
+    fn attach_ready_after(
+        &mut self,
+        engine: &Engine,
+        id: u64,
+        delay: u32,
+        now: Instant,
+    ) {
+        self.job_mut(id).delay = delay;
+        match delay {
+            0 => self.attach_ready(id),
+            delay => self.attach_delayed(
+                engine,
+                id,
+                now + Duration::from_secs(delay.into()),
+            ),
+        }
+    }
+
+    // Timekeeping.
+
+    /// Makes delayed jobs ready, times out reservations, and unpauses the
+    /// tube, as of `now`, then hands any jobs now ready to waiting clients.
+    pub(super) fn tick(&mut self, engine: &Engine, now: Instant) {
+        while let Some(&(until, id)) = self.delayed.first() {
+            if until > now {
+                break;
+            }
+            self.detach(id);
+            self.attach_ready(id);
+        }
+
+        while let Some(&(deadline, id)) = self.deadlines.first() {
+            if deadline > now {
+                break;
+            }
+            self.detach(id);
+            self.attach_ready(id);
+            self.job_mut(id).timeouts += 1;
+            engine.count_timeout();
+            self.publish(engine, EventKind::Timeout, id);
+        }
+
+        if self.paused_until.is_some_and(|until| until <= now) {
+            self.paused_until = None;
+        }
+
+        self.dispatch(engine, now);
+    }
+
+    /// Returns when `tick` next has something to do, if ever.
+    pub(super) fn next_tick(&self) -> Option<Instant> {
+        let delayed = self.delayed.first().map(|&(until, _)| until);
+        let deadline = self.deadlines.first().map(|&(deadline, _)| deadline);
+
+        [delayed, deadline, self.paused_until]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    // Reserving.
+
+    /// Returns the priority and ID of the next job to be reserved, unless
+    /// the tube is paused.
+    pub(super) fn next_ready(&self, now: Instant) -> Option<(u32, u64)> {
+        match self.is_paused(now) {
+            true => None,
+            false => self.ready.first().copied(),
+        }
+    }
+
+    /// Adds a reserve to those waiting on the tube.
+    pub(super) fn wait(&mut self, waiter: Arc<Waiter>) {
+        self.waiters.push_back(waiter);
+    }
+
+    /// Removes a reserve from those waiting on the tube.
+    pub(super) fn stop_waiting(&mut self, waiter: &Arc<Waiter>) {
+        self.waiters.retain(|w| !Arc::ptr_eq(w, waiter));
+    }
+
+    /// Reserves a job for each waiting reserve, in the order they started
+    /// waiting, for as long as there are jobs for them. Reserves already
+    /// given a job by another tube, or that stopped waiting, are skipped.
+    pub(super) fn dispatch(&mut self, engine: &Engine, now: Instant) {
+        while let Some((_, id)) = self.next_ready(now) {
+            let Some(waiter) = self.waiters.pop_front() else {
+                break;
+            };
+            // Held until the job's sent, so a reserve that finds it's been
+            // claimed knows its job is waiting for it.
+            let mut tx = waiter.tx.lock().unwrap();
+            let Some(sender) = tx.take() else {
+                continue;
+            };
+
+            self.detach(id);
+            self.attach_reserved(engine, id, &waiter.client, now);
+            match sender.send(self.data(id)) {
+                Ok(()) => self.publish(engine, EventKind::Reserve, id),
+                // The reserve was dropped before it could stop waiting.
+                Err(_) => self.unreserve(id, waiter.client.id),
+            }
+        }
+    }
+
+    /// Reserves a job by its ID for `client`, if it isn't already reserved.
+    pub(super) fn reserve_job(
+        &mut self,
+        engine: &Engine,
+        id: u64,
+        client: &Arc<Client>,
+        now: Instant,
+    ) -> Result<JobData, Error> {
+        if self.jobs[&id].state.kind() == JobStateKind::Reserved {
+            return Err(Error::NotFound);
+        }
+
+        self.detach(id);
+        self.attach_reserved(engine, id, client, now);
+        self.publish(engine, EventKind::Reserve, id);
+
+        Ok(self.data(id))
+    }
+
+    /// Returns a job reserved by a client that didn't receive it, without
+    /// counting a release.
+    pub(super) fn unreserve(&mut self, id: u64, client: u64) {
+        if self.reserved_by(id, client).is_ok() {
+            self.detach(id);
+            self.job_mut(id).reserves -= 1;
+            self.attach_ready(id);
+        }
+    }
+
+    /// Makes a job reserved by a client that's disconnected ready again.
+    pub(super) fn abandon(&mut self, id: u64, client: u64) {
+        if self.reserved_by(id, client).is_ok() {
+            self.detach(id);
+            self.attach_ready(id);
+        }
+    }
+
+    // Commands on jobs.
+
+    pub(super) fn release(
+        &mut self,
+        engine: &Engine,
+        client: u64,
+        id: u64,
+        pri: u32,
+        delay: u32,
+        now: Instant,
+    ) -> Result<(), Error> {
+        self.reserved_by(id, client)?;
+
+        self.detach(id);
+        let job = self.job_mut(id);
+        job.pri = pri;
+        job.releases += 1;
+        self.attach_ready_after(engine, id, delay, now);
+        self.record(engine, id, now);
+        self.publish(engine, EventKind::Release, id);
+
+        Ok(())
+    }
+
+    pub(super) fn bury(
+        &mut self,
+        engine: &Engine,
+        client: u64,
+        id: u64,
+        pri: u32,
+        now: Instant,
+    ) -> Result<(), Error> {
+        self.reserved_by(id, client)?;
+
+        self.detach(id);
+        let job = self.job_mut(id);
+        job.pri = pri;
+        job.buries += 1;
+        self.attach_buried(id);
+        self.record(engine, id, now);
+        self.publish(engine, EventKind::Bury, id);
+
+        Ok(())
+    }
+
+    pub(super) fn touch(
+        &mut self,
+        engine: &Engine,
+        client: &Arc<Client>,
+        id: u64,
+        now: Instant,
+    ) -> Result<(), Error> {
+        self.reserved_by(id, client.id)?;
+
+        self.detach(id);
+        self.attach_reserved(engine, id, client, now);
+        // Touching isn't reserving again.
+        self.job_mut(id).reserves -= 1;
+
+        Ok(())
+    }
+
+    /// Deletes a job reserved by the client, or that isn't reserved at all.
+    pub(super) fn delete(
+        &mut self,
+        engine: &Engine,
+        client: u64,
+        id: u64,
+    ) -> Result<(), Error> {
+        if let JobState::Reserved { by, .. } = self.jobs[&id].state {
+            if by != client {
+                return Err(Error::NotFound);
+            }
+        }
+
+        self.publish(engine, EventKind::Delete, id);
+        self.cmd_delete += 1;
+        self.remove(engine, id);
+
+        Ok(())
+    }
+
+    /// Kicks up to `bound` jobs: buried jobs first, and only once there are
+    /// none, delayed jobs.
+    pub(super) fn kick(
+        &mut self,
+        engine: &Engine,
+        bound: u64,
+        now: Instant,
+    ) -> u64 {
+        let bound = bound.try_into().unwrap_or(usize::MAX);
+        let ids: Vec<u64> = if self.buried.is_empty() {
+            self.delayed.iter().take(bound).map(|&(_, id)| id).collect()
+        } else {
+            self.buried.iter().take(bound).map(|&(_, id)| id).collect()
+        };
+
+        for &id in &ids {
+            self.kick_one(engine, id, now);
+        }
+        self.dispatch(engine, now);
+
+        ids.len() as u64
+    }
+
+    pub(super) fn kick_job(
+        &mut self,
+        engine: &Engine,
+        id: u64,
+        now: Instant,
+    ) -> Result<(), Error> {
+        match self.jobs[&id].state.kind() {
+            JobStateKind::Buried | JobStateKind::Delayed => {},
+            _ => return Err(Error::NotFound),
+        }
+
+        self.kick_one(engine, id, now);
+        self.dispatch(engine, now);
+
+        Ok(())
+    }
+
+    fn kick_one(&mut self, engine: &Engine, id: u64, now: Instant) {
+        self.detach(id);
+        self.attach_ready(id);
+        self.job_mut(id).kicks += 1;
+        self.record(engine, id, now);
+        self.publish(engine, EventKind::Kick, id);
+    }
+
+    /// Stops jobs being reserved from the tube for `delay` seconds.
+    pub(super) fn pause(
+        &mut self,
+        engine: &Engine,
+        name: &[u8],
+        delay: u32,
+        now: Instant,
+    ) {
+        self.cmd_pause_tube += 1;
+        self.pause = delay;
+        self.paused_until =
+            (delay > 0).then(|| now + Duration::from_secs(delay.into()));
+        if let Some(until) = self.paused_until {
+            engine.schedule(until);
+        }
+        engine.writer.pause(StoredPause {
+            tube: name.to_vec(),
+            pause: delay,
+            until: SystemTime::now() + Duration::from_secs(delay.into()),
+        });
+        engine.events.publish(Event::pause(name.to_vec(), delay));
+        self.dispatch(engine, now);
+    }
+
+    /// Pauses the tube until `until`, as recovered from the store.
+    pub(super) fn restore_pause(
+        &mut self,
+        engine: &Engine,
+        pause: u32,
+        until: Instant,
+    ) {
+        self.pause = pause;
+        self.paused_until = Some(until);
+        engine.schedule(until);
+    }
+
+    // Peeking and stats.
+
+    pub(super) fn peek(&self, id: u64) -> JobData {
+        self.data(id)
+    }
+
+    pub(super) fn peek_state(
+        &self,
+        state: JobStateKind,
+    ) -> Result<JobData, Error> {
+        let id = match state {
+            JobStateKind::Ready => self.ready.first().map(|&(_, id)| id),
+            JobStateKind::Delayed => self.delayed.first().map(|&(_, id)| id),
+            JobStateKind::Buried => self.buried.first().map(|&(_, id)| id),
+            JobStateKind::Reserved => None,
+        };
+
+        id.map(|id| self.data(id)).ok_or(Error::NotFound)
+    }
+
+    pub(super) fn stats_job(&self, id: u64, now: Instant) -> JobStats {
+        let job = &self.jobs[&id];
+
+        let secs = |d: Duration| d.as_secs().try_into().unwrap_or(u32::MAX);
+        let time_left = match job.state {
+            JobState::Reserved { at, .. } => secs(
+                (at + Duration::from_secs(job.ttr.into()))
+                    .saturating_duration_since(now),
+            ),
+            JobState::Delayed { until } => {
+                secs(until.saturating_duration_since(now))
+            },
+            JobState::Ready | JobState::Buried => 0,
+        };
+
+        JobStats {
+            id,
+            tube: job.tube.clone(),
+            state: job.state,
+            pri: job.pri,
+            age: secs(now.saturating_duration_since(job.created)),
+            delay: job.delay,
+            ttr: job.ttr,
+            time_left,
+            file: 0,
+            reserves: job.reserves,
+            timeouts: job.timeouts,
+            releases: job.releases,
+            buries: job.buries,
+            kicks: job.kicks,
+        }
+    }
+
+    pub(super) fn stats(&self, name: &[u8], now: Instant) -> TubeStats {
+        let waiting = self
+            .waiters
+            .iter()
+            .filter(|waiter| waiter.is_waiting())
+            .count();
+
+        TubeStats {
+            name: name.to_vec(),
+            current_jobs_urgent: self.ready.range(..(URGENT, 0)).count() as u64,
+            current_jobs_ready: self.ready.len() as u64,
+            current_jobs_reserved: self.deadlines.len() as u64,
+            current_jobs_delayed: self.delayed.len() as u64,
+            current_jobs_buried: self.buried.len() as u64,
+            total_jobs: self.total_jobs,
+            current_using: self.using,
+            current_waiting: waiting as u64,
+            current_watching: self.watching,
+            pause: self.pause,
+            cmd_delete: self.cmd_delete,
+            cmd_pause_tube: self.cmd_pause_tube,
+            pause_time_left: self
+                .paused_until
+                .map(|until| until.saturating_duration_since(now).as_secs())
+                .unwrap_or(0)
+                .try_into()
+                .unwrap_or(u32::MAX),
+        }
+    }
+
+    /// Adds the tube's job counts to the server-wide stats.
+    pub(super) fn add_job_counts(&self, stats: &mut ServerStats) {
+        stats.current_jobs_urgent +=
+            self.ready.range(..(URGENT, 0)).count() as u64;
+        stats.current_jobs_ready += self.ready.len() as u64;
+        stats.current_jobs_reserved += self.deadlines.len() as u64;
+        stats.current_jobs_delayed += self.delayed.len() as u64;
+        stats.current_jobs_buried += self.buried.len() as u64;
+    }
+
+    // Bulk operations, selecting jobs with a query.
+
+    /// Returns the IDs of the jobs selected by a query, in the order they
+    /// were created.
+    pub(super) fn select(&self, query: &JobQuery) -> Vec<u64> {
+        query.select(self.jobs.values())
+    }
+
+    pub(super) fn reprioritise(
+        &mut self,
+        engine: &Engine,
+        query: &JobQuery,
+        pri: u32,
+        now: Instant,
+    ) -> u64 {
+        if query.state == JobStateKind::Reserved {
+            return 0;
+        }
+
+        let ids = self.select(query);
+        for &id in &ids {
+            // Only ready jobs are ordered by their priority.
+            if query.state == JobStateKind::Ready {
+                self.detach(id);
+                self.job_mut(id).pri = pri;
+                self.attach_ready(id);
+            } else {
+                self.job_mut(id).pri = pri;
+            }
+            self.record(engine, id, now);
+        }
+
+        ids.len() as u64
+    }
+
+    /// Moves the selected jobs to the tube `to`, keeping them in the same
+    /// state, and buried jobs in the same order.
+    pub(super) fn move_jobs(
+        &mut self,
+        engine: &Engine,
+        to: &mut Tube,
+        to_shard: &Arc<Shard>,
+        query: &JobQuery,
+        now: Instant,
+    ) -> u64 {
+        if query.state == JobStateKind::Reserved {
+            return 0;
+        }
+
+        let mut ids = self.select(query);
+        if query.state == JobStateKind::Buried {
+            let selected: BTreeSet<u64> = ids.into_iter().collect();
+            ids = self
+                .buried
+                .iter()
+                .map(|&(_, id)| id)
+                .filter(|id| selected.contains(id))
+                .collect();
+        }
+
+        for &id in &ids {
+            let job = self.take(id);
+            let from = job.tube.clone();
+            to.insert(engine, to_shard, job);
+            to.record(engine, id, now);
+            to.total_jobs += 1;
+            engine.events.publish(Event::moved(
+                from,
+                to_shard.name.clone(),
+                id,
+            ));
+        }
+        to.dispatch(engine, now);
+
+        ids.len() as u64
+    }
+
+    pub(super) fn delete_jobs(
+        &mut self,
+        engine: &Engine,
+        query: &JobQuery,
+        dry_run: bool,
+    ) -> Vec<u64> {
+        if query.state == JobStateKind::Reserved {
+            return vec![];
+        }
+
+        let ids = self.select(query);
+        if !dry_run {
+            for &id in &ids {
+                self.publish(engine, EventKind::Delete, id);
+                self.remove(engine, id);
+            }
+            self.cmd_delete += 1;
+        }
+
+        ids
+    }
+
+    pub(super) fn kick_jobs(
+        &mut self,
+        engine: &Engine,
+        query: &JobQuery,
+        dry_run: bool,
+        now: Instant,
+    ) -> Vec<u64> {
+        if !matches!(query.state, JobStateKind::Buried | JobStateKind::Delayed)
+        {
+            return vec![];
+        }
+
+        let ids = self.select(query);
+        if !dry_run {
+            for &id in &ids {
+                self.kick_one(engine, id, now);
+            }
+            self.dispatch(engine, now);
+        }
+
+        ids
+    }
+}
@@ -0,0 +1,808 @@
+//! A `JobStore` appending each change to a write-ahead log, a directory of
+//! numbered `binlog.N` files replayed in order when the queue next starts.
+//!
+//! Each record is a job as created or updated, a deletion, or a pause, framed
+//! with its length and a checksum, so a record torn by a crash is noticed and
+//! ignored along with anything after it in the same file. Writing carries on
+//! in a new file once the last reaches its size limit, or after a failed
+//! write. Records overtaken by later ones are dropped by compacting: writing
+//! the jobs and pauses still live into new files, then removing the old. The
+//! log is compacted when starting a new file finds it over twice the size of
+//! what's live, and on checkpoint, before the queue stops.
+//!
+//! How durable a change is depends on the `SyncPolicy`. Synced on every
+//! flush, a job is durable once its `INSERTED` is sent, as with
+//! `SqliteStore`. Synced at most once a period, a crash of the machine may
+//! lose the changes of the last period, including jobs already answered.
+//! Never synced, it may lose whatever the OS hadn't yet written. Either way,
+//! a crash of the process alone loses nothing flushed.
+//!
+//! The directory is locked while open, so two queues can't share it.
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::warn;
+
+use super::store::{JobStore, StoredJob, StoredPause, StoredState};
+
+/// Starts every log file, naming the format and its version.
+const MAGIC: &[u8; 8] = b"ebwal\0\0\x01";
+
+/// The length of the length and checksum framing each record.
+const FRAME: u64 = 12;
+
+/// Record types, each the first byte of a record.
+const CREATE: u8 = 1;
+/// As `CREATE`, without the body, which never changes.
+const UPDATE: u8 = 2;
+const DELETE: u8 = 3;
+const PAUSE: u8 = 4;
+
+/// When the log is synced to disk.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyncPolicy {
+    /// Syncs on flushing, if it's been this long since the last sync, so
+    /// with a zero period on every flush.
+    Every(Duration),
+    /// Never syncs, leaving it to the OS.
+    Never,
+}
+
+/// Stores jobs in a write-ahead log in a directory.
+#[derive(Debug)]
+pub struct WalStore {
+    dir: PathBuf,
+    file_size: u64,
+    sync: SyncPolicy,
+    last_sync: Instant,
+    /// Held to keep the directory locked.
+    _lock: File,
+    /// The numbers of the log files, oldest first.
+    files: Vec<u64>,
+    /// The last log file, and its length, unless it's yet to be started or
+    /// is finished with.
+    current: Option<(BufWriter<File>, u64)>,
+    /// The total length of every log file.
+    written: u64,
+    /// The lengths of the records still live, for each job and pause.
+    live_jobs: HashMap<u64, Live>,
+    live_pauses: HashMap<Vec<u8>, u64>,
+    /// The sum of the lengths above.
+    live: u64,
+    /// What was replayed on opening, until taken.
+    jobs: Option<BTreeMap<u64, StoredJob>>,
+    pauses: Option<HashMap<Vec<u8>, StoredPause>>,
+}
+
+/// The lengths of a job's creation and last update records.
+#[derive(Clone, Copy, Debug, Default)]
+struct Live {
+    created: u64,
+    updated: u64,
+}
+
+impl Live {
+    fn total(&self) -> u64 {
+        self.created + self.updated
+    }
+}
+
+/// The jobs and pauses left by replaying the log.
+#[derive(Debug, Default)]
+struct Replay {
+    jobs: BTreeMap<u64, StoredJob>,
+    pauses: HashMap<Vec<u8>, StoredPause>,
+}
+
+impl WalStore {
+    /// Opens the log in `dir`, creating the directory if need be, and replays
+    /// it. A new file is started once the last reaches `file_size` bytes.
+    pub fn open(
+        dir: &Path,
+        file_size: u64,
+        sync: SyncPolicy,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let lock = File::create(dir.join("lock"))?;
+        lock.try_lock()
+            .map_err(|_| io::Error::other("locked by another process"))?;
+
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let number = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("binlog."))
+                .and_then(|number| number.parse::<u64>().ok());
+            files.extend(number);
+        }
+        files.sort_unstable();
+
+        let mut store = Self {
+            dir: dir.to_path_buf(),
+            file_size,
+            sync,
+            last_sync: Instant::now(),
+            _lock: lock,
+            files,
+            current: None,
+            written: 0,
+            live_jobs: HashMap::new(),
+            live_pauses: HashMap::new(),
+            live: 0,
+            jobs: None,
+            pauses: None,
+        };
+        let replay = store.replay()?;
+        store.jobs = Some(replay.jobs);
+        store.pauses = Some(replay.pauses);
+
+        Ok(store)
+    }
+
+    fn path(&self, number: u64) -> PathBuf {
+        self.dir.join(format!("binlog.{number}"))
+    }
+
+    fn syncs(&self) -> bool {
+        self.sync != SyncPolicy::Never
+    }
+
+    /// Reads every log file in order, tallying what's live.
+    fn replay(&mut self) -> io::Result<Replay> {
+        if let Some((writer, _)) = &mut self.current {
+            writer.flush()?;
+        }
+
+        let mut replay = Replay::default();
+        self.written = 0;
+        self.live_jobs.clear();
+        self.live_pauses.clear();
+        for number in self.files.clone() {
+            let path = self.path(number);
+            let file = File::open(&path)?;
+            self.written += file.metadata()?.len();
+            if let Err(error) = self.replay_file(file, &mut replay) {
+                warn!(
+                    path = %path.display(),
+                    %error,
+                    "ignoring the rest of damaged log file",
+                );
+            }
+        }
+        self.live = self.live_jobs.values().map(Live::total).sum::<u64>()
+            + self.live_pauses.values().sum::<u64>();
+
+        Ok(replay)
+    }
+
+    fn replay_file(
+        &mut self,
+        file: File,
+        replay: &mut Replay,
+    ) -> io::Result<()> {
+        let mut reader = BufReader::new(file);
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a log file"));
+        }
+
+        while let Some(payload) = read_record(&mut reader)? {
+            let len = FRAME + payload.len() as u64;
+            let mut decoder = Decoder(&payload[..]);
+            match decoder.u8()? {
+                CREATE => {
+                    let job = decoder.job(true)?;
+                    let live = Live {
+                        created: len,
+                        updated: 0,
+                    };
+                    self.live_jobs.insert(job.id, live);
+                    replay.jobs.insert(job.id, job);
+                },
+                UPDATE => {
+                    let mut job = decoder.job(false)?;
+                    let id = job.id;
+                    // Updates to jobs whose creation was lost are dropped.
+                    if let Some(old) = replay.jobs.get_mut(&id) {
+                        job.data = std::mem::take(&mut old.data);
+                        *old = job;
+                    }
+                    if let Some(live) = self.live_jobs.get_mut(&id) {
+                        live.updated = len;
+                    }
+                },
+                DELETE => {
+                    let id = decoder.u64()?;
+                    replay.jobs.remove(&id);
+                    self.live_jobs.remove(&id);
+                },
+                PAUSE => {
+                    let pause = decoder.pause()?;
+                    self.live_pauses.insert(pause.tube.clone(), len);
+                    replay.pauses.insert(pause.tube.clone(), pause);
+                },
+                other => {
+                    return Err(invalid(&format!("unknown record {other}")))
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts log file `number`, to write to next.
+    fn start(&mut self, number: u64) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.path(number))?;
+        file.write_all(MAGIC)?;
+        if self.syncs() {
+            File::open(&self.dir)?.sync_all()?;
+        }
+        self.files.push(number);
+        self.written += MAGIC.len() as u64;
+        self.current = Some((BufWriter::new(file), MAGIC.len() as u64));
+
+        Ok(())
+    }
+
+    /// Flushes the current file, and syncs it unless told never to.
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some((mut writer, _)) = self.current.take() {
+            writer.flush()?;
+            if self.syncs() {
+                writer.get_ref().sync_data()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a record to the current file, starting one if there's none or
+    /// it's full, and returns the record's length.
+    fn append(&mut self, payload: &[u8]) -> io::Result<u64> {
+        let full = self
+            .current
+            .as_ref()
+            .is_none_or(|(_, len)| *len >= self.file_size);
+        if full {
+            self.finish()?;
+            if self.written > 2 * self.live + self.file_size {
+                self.compact()?;
+            } else {
+                self.start(self.files.last().map_or(1, |last| last + 1))?;
+            }
+        }
+
+        let (writer, file_len) = self.current.as_mut().expect("file started");
+        let len = FRAME + payload.len() as u64;
+        if let Err(error) = write_record(writer, payload) {
+            // The record may be half written, hiding anything after it in
+            // the same file, so carry on in a new one.
+            self.current = None;
+            return Err(error);
+        }
+        *file_len += len;
+        self.written += len;
+
+        Ok(len)
+    }
+
+    /// Writes the jobs and pauses live into new files, then removes the old.
+    /// Until they're removed, replaying the old files then the new gives the
+    /// same, so a crash part way loses nothing.
+    fn compact(&mut self) -> io::Result<()> {
+        self.finish()?;
+        let replay = self.replay()?;
+        let old = self.files.len();
+        self.written = 0;
+        self.live = 0;
+
+        let mut number = self.files.last().map_or(1, |last| last + 1);
+        self.start(number)?;
+        self.live_jobs.clear();
+        self.live_pauses.clear();
+        let now = SystemTime::now();
+        let pauses = replay.pauses.values().filter(|pause| pause.until > now);
+        let records = replay
+            .jobs
+            .values()
+            .map(|job| (encode_job(CREATE, job), Some(job.id), None))
+            .chain(pauses.map(|pause| {
+                (encode_pause(pause), None, Some(pause.tube.clone()))
+            }));
+        for (payload, id, tube) in records {
+            let full = self
+                .current
+                .as_ref()
+                .is_some_and(|(_, len)| *len >= self.file_size);
+            if full {
+                self.finish()?;
+                number += 1;
+                self.start(number)?;
+            }
+
+            let (writer, file_len) = self.current.as_mut().expect("started");
+            write_record(writer, &payload)?;
+            let len = FRAME + payload.len() as u64;
+            *file_len += len;
+            self.written += len;
+            self.live += len;
+            if let Some(id) = id {
+                let live = Live {
+                    created: len,
+                    updated: 0,
+                };
+                self.live_jobs.insert(id, live);
+            }
+            if let Some(tube) = tube {
+                self.live_pauses.insert(tube, len);
+            }
+        }
+        // Later changes carry on in the last file, so it's kept open.
+        let syncs = self.syncs();
+        if let Some((writer, _)) = &mut self.current {
+            writer.flush()?;
+            if syncs {
+                writer.get_ref().sync_data()?;
+            }
+        }
+
+        for number in self.files.drain(..old).collect::<Vec<_>>() {
+            fs::remove_file(self.path(number))?;
+        }
+        if self.syncs() {
+            File::open(&self.dir)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl JobStore for WalStore {
+    fn create(&mut self, job: &StoredJob) -> io::Result<()> {
+        let len = self.append(&encode_job(CREATE, job))?;
+        let live = Live {
+            created: len,
+            updated: 0,
+        };
+        if let Some(old) = self.live_jobs.insert(job.id, live) {
+            self.live -= old.total();
+        }
+        self.live += len;
+
+        Ok(())
+    }
+
+    fn update(&mut self, job: &StoredJob) -> io::Result<()> {
+        let len = self.append(&encode_job(UPDATE, job))?;
+        if let Some(live) = self.live_jobs.get_mut(&job.id) {
+            self.live = self.live - live.updated + len;
+            live.updated = len;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&mut self, id: u64) -> io::Result<()> {
+        let mut payload = vec![DELETE];
+        payload.extend(id.to_le_bytes());
+        self.append(&payload)?;
+        if let Some(live) = self.live_jobs.remove(&id) {
+            self.live -= live.total();
+        }
+
+        Ok(())
+    }
+
+    fn recover(&mut self) -> io::Result<Vec<StoredJob>> {
+        let jobs = match self.jobs.take() {
+            Some(jobs) => jobs,
+            None => self.replay()?.jobs,
+        };
+
+        Ok(jobs.into_values().collect())
+    }
+
+    fn pause(&mut self, pause: &StoredPause) -> io::Result<()> {
+        let len = self.append(&encode_pause(pause))?;
+        if let Some(old) = self.live_pauses.insert(pause.tube.clone(), len) {
+            self.live -= old;
+        }
+        self.live += len;
+
+        Ok(())
+    }
+
+    fn recover_pauses(&mut self) -> io::Result<Vec<StoredPause>> {
+        let pauses = match self.pauses.take() {
+            Some(pauses) => pauses,
+            None => self.replay()?.pauses,
+        };
+
+        // Pauses that have ended are forgotten.
+        let now = SystemTime::now();
+        Ok(pauses
+            .into_values()
+            .filter(|pause| pause.until > now)
+            .collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let Some((writer, _)) = &mut self.current else {
+            return Ok(());
+        };
+        writer.flush()?;
+        if let SyncPolicy::Every(period) = self.sync {
+            if self.last_sync.elapsed() >= period {
+                writer.get_ref().sync_data()?;
+                self.last_sync = Instant::now();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compacts the log, unless it holds nothing but what's live.
+    fn checkpoint(&mut self) -> io::Result<()> {
+        let headers = MAGIC.len() as u64 * self.files.len() as u64;
+        match self.written > self.live + headers {
+            true => self.compact(),
+            false => self.flush(),
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The 64-bit FNV-1a hash of `bytes`.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+fn write_record(writer: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::other("record too large"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&checksum(payload).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+/// Reads the next record's payload, or `None` at the end of the file.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut frame = [0; FRAME as usize];
+    match reader.read(&mut frame[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut frame[1..])?,
+    }
+    let len = u32::from_le_bytes(frame[..4].try_into().unwrap());
+    let sum = u64::from_le_bytes(frame[4..].try_into().unwrap());
+
+    // Read through `take`, so a damaged length can't allocate gigabytes.
+    let mut payload = vec![];
+    reader.take(len.into()).read_to_end(&mut payload)?;
+    if payload.len() != len as usize {
+        return Err(invalid("truncated record"));
+    }
+    if checksum(&payload) != sum {
+        return Err(invalid("checksum mismatch"));
+    }
+
+    Ok(Some(payload))
+}
+
+/// Returns a time in milliseconds since the Unix epoch.
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+fn put_bytes(payload: &mut Vec<u8>, bytes: &[u8]) {
+    payload.extend((bytes.len() as u32).to_le_bytes());
+    payload.extend(bytes);
+}
+
+/// Encodes a job as a record of type `kind`, with its body if it's a
+/// `CREATE`.
+fn encode_job(kind: u8, job: &StoredJob) -> Vec<u8> {
+    let (state, until) = match job.state {
+        StoredState::Ready => (0, 0),
+        StoredState::Delayed { until } => (1, millis(until)),
+        StoredState::Buried => (2, 0),
+    };
+
+    let mut payload = vec![kind];
+    payload.extend(job.id.to_le_bytes());
+    payload.extend(job.pri.to_le_bytes());
+    payload.extend(job.delay.to_le_bytes());
+    payload.extend(job.ttr.to_le_bytes());
+    payload.push(state);
+    payload.extend(until.to_le_bytes());
+    for counter in [
+        job.reserves,
+        job.timeouts,
+        job.releases,
+        job.buries,
+        job.kicks,
+    ] {
+        payload.extend(counter.to_le_bytes());
+    }
+    put_bytes(&mut payload, &job.tube);
+    if kind == CREATE {
+        put_bytes(&mut payload, &job.data);
+    }
+
+    payload
+}
+
+fn encode_pause(pause: &StoredPause) -> Vec<u8> {
+    let mut payload = vec![PAUSE];
+    payload.extend(pause.pause.to_le_bytes());
+    payload.extend(millis(pause.until).to_le_bytes());
+    put_bytes(&mut payload, &pause.tube);
+
+    payload
+}
+
+/// Reads the fields of a record in turn.
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let (bytes, rest) = self
+            .0
+            .split_first_chunk()
+            .ok_or_else(|| invalid("record too short"))?;
+        self.0 = rest;
+        Ok(*bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        self.take().map(u8::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    fn time(&mut self) -> io::Result<SystemTime> {
+        Ok(UNIX_EPOCH + Duration::from_millis(self.u64()?))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        if self.0.len() < len {
+            return Err(invalid("record too short"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes.to_vec())
+    }
+
+    /// Decodes a job, with its body only if `with_data`.
+    fn job(&mut self, with_data: bool) -> io::Result<StoredJob> {
+        let id = self.u64()?;
+        let pri = self.u32()?;
+        let delay = self.u32()?;
+        let ttr = self.u32()?;
+        let state = match self.u8()? {
+            0 => {
+                self.u64()?;
+                StoredState::Ready
+            },
+            1 => StoredState::Delayed {
+                until: self.time()?,
+            },
+            2 => {
+                self.u64()?;
+                StoredState::Buried
+            },
+            other => return Err(invalid(&format!("unknown state {other}"))),
+        };
+
+        Ok(StoredJob {
+            id,
+            pri,
+            delay,
+            ttr,
+            state,
+            reserves: self.u64()?,
+            timeouts: self.u64()?,
+            releases: self.u64()?,
+            buries: self.u64()?,
+            kicks: self.u64()?,
+            tube: self.bytes()?,
+            data: match with_data {
+                true => self.bytes()?,
+                false => vec![],
+            },
+        })
+    }
+
+    fn pause(&mut self) -> io::Result<StoredPause> {
+        Ok(StoredPause {
+            pause: self.u32()?,
+            until: self.time()?,
+            tube: self.bytes()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: u64, state: StoredState) -> StoredJob {
+        StoredJob {
+            id,
+            tube: b"emails".to_vec(),
+            pri: 10,
+            delay: 0,
+            ttr: 60,
+            data: b"body\r\n\0".to_vec(),
+            state,
+            reserves: 0,
+            timeouts: 0,
+            releases: 0,
+            buries: 0,
+            kicks: 0,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("ebeans-test-wal-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_str().unwrap().starts_with("binlog.")
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_wal_store() {
+        let dir = temp_dir("store");
+        let sync = SyncPolicy::Every(Duration::ZERO);
+
+        let until = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let mut store = WalStore::open(&dir, 1 << 20, sync).unwrap();
+        assert_eq!(store.recover().unwrap(), []);
+        assert!(WalStore::open(&dir, 1 << 20, sync).is_err());
+        store.create(&job(1, StoredState::Ready)).unwrap();
+        store.create(&job(2, StoredState::Ready)).unwrap();
+        store
+            .create(&job(3, StoredState::Delayed { until }))
+            .unwrap();
+
+        let buried = StoredJob {
+            pri: 20,
+            buries: 1,
+            ..job(2, StoredState::Buried)
+        };
+        store.update(&buried).unwrap();
+        store.delete(1).unwrap();
+
+        // Only pauses yet to end are recovered.
+        let pause = |tube: &[u8], until| StoredPause {
+            tube: tube.to_vec(),
+            pause: 60,
+            until,
+        };
+        let later = SystemTime::now() + Duration::from_secs(60);
+        store.pause(&pause(b"emails", later)).unwrap();
+        store.pause(&pause(b"old", UNIX_EPOCH)).unwrap();
+        store.flush().unwrap();
+        drop(store);
+
+        let recovered = [buried, job(3, StoredState::Delayed { until })];
+        let mut store = WalStore::open(&dir, 1 << 20, sync).unwrap();
+        assert_eq!(store.recover().unwrap(), recovered);
+        assert_eq!(
+            store.recover_pauses().unwrap(),
+            [pause(b"emails", millis_rounded(later))],
+        );
+
+        // Checkpointing leaves only what's live, in one file.
+        store.checkpoint().unwrap();
+        assert_eq!(files(&dir), 1);
+        assert_eq!(store.recover().unwrap(), recovered);
+        drop(store);
+
+        let mut store = WalStore::open(&dir, 1 << 20, sync).unwrap();
+        assert_eq!(store.recover().unwrap(), recovered);
+        assert_eq!(store.recover_pauses().unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_wal_rotation_and_damage() {
+        let dir = temp_dir("rotation");
+
+        // Files fill after a few records, and the log's compacted once
+        // mostly deleted jobs.
+        let mut store = WalStore::open(&dir, 256, SyncPolicy::Never).unwrap();
+        for id in 1..=100 {
+            store.create(&job(id, StoredState::Ready)).unwrap();
+            if id % 10 != 0 {
+                store.delete(id).unwrap();
+            }
+        }
+        store.flush().unwrap();
+        assert!((2..10).contains(&files(&dir)), "{}", files(&dir));
+        drop(store);
+
+        // A torn record at the end of the last file is ignored.
+        let mut store = WalStore::open(&dir, 256, SyncPolicy::Never).unwrap();
+        let ids = |store: &mut WalStore| {
+            let jobs = store.recover().unwrap();
+            jobs.iter().map(|job| job.id).collect::<Vec<_>>()
+        };
+        let expected: Vec<u64> = (1..=10).map(|n| n * 10).collect();
+        assert_eq!(ids(&mut store), expected);
+        store.checkpoint().unwrap();
+        store.create(&job(101, StoredState::Ready)).unwrap();
+        store.flush().unwrap();
+        drop(store);
+
+        let last = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_str().unwrap().contains("binlog."))
+            .max_by_key(|path| {
+                let name = path.file_name().unwrap().to_str().unwrap();
+                name["binlog.".len()..].parse::<u64>().unwrap()
+            })
+            .unwrap();
+        let len = fs::metadata(&last).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&last)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut store = WalStore::open(&dir, 256, SyncPolicy::Never).unwrap();
+        assert_eq!(ids(&mut store), expected);
+
+        // Later records go in a new file, after the damage.
+        store.create(&job(102, StoredState::Ready)).unwrap();
+        store.flush().unwrap();
+        drop(store);
+        let mut store = WalStore::open(&dir, 256, SyncPolicy::Never).unwrap();
+        let mut with_new = expected.clone();
+        with_new.push(102);
+        assert_eq!(ids(&mut store), with_new);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Drops the sub-millisecond part of a time, as recording it does.
+    fn millis_rounded(time: SystemTime) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis(time))
+    }
+}
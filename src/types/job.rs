@@ -1,11 +1,15 @@
-use std::time::Instant;
+use tokio::time::Instant;
 
 use super::states::JobState;
 
 #[derive(Debug)]
-pub(crate) struct Job {
+pub struct Job {
     pub(crate) id: u64,
+    /// The tube the job is in.
+    pub(crate) tube: Vec<u8>,
     pub(crate) pri: u32,
+    /// The delay given by the last put or release, in seconds.
+    pub(crate) delay: u32,
     pub(crate) data: Vec<u8>,
     pub(crate) state: JobState, // also contains state-specific data
     pub(crate) created: Instant,
@@ -1,10 +1,19 @@
 use serde::Serialize;
 
 use super::serialisable::BeanstalkSerialisable;
-use super::states::JobState;
+use super::states::{JobState, JobStateKind};
+use crate::events::Event;
+use crate::latency::LatencyStats;
+use crate::query::PredicateKind;
+use crate::util::serialise_lossy_str;
 
 /// A command sent by the client to the server.
-#[derive(Clone, Debug, Eq, PartialEq)]
+///
+/// Serialises as an object with the command's name under `command` alongside
+/// its arguments, for logging. Job bodies and predicates aren't part of the
+/// command, so are never included.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
 pub enum BeanstalkCommand {
     /// Places a job onto the currently `use`d queue.
     ///
@@ -56,12 +65,18 @@ pub enum BeanstalkCommand {
     /// `WATCHING <number of watched tubes>`.
     ///
     /// On the wire: `watch <tube>`
-    Watch { tube: Vec<u8> },
+    Watch {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        tube: Vec<u8>,
+    },
     /// Reverses the effect of `watch` on this client. Returns `WATCHING <n>` or
     /// `NOT_IGNORED` if this would remove the last queue in the watchlist.
     ///
     /// On the wire: `ignore <tube>`
-    Ignore { tube: Vec<u8> },
+    Ignore {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        tube: Vec<u8>,
+    },
     /// Returns the data for the job with this ID, regardless of its state.
     /// Response is either `FOUND <id> <bytes>` or `NOT_FOUND`, in common with
     /// all requests in the `peek` family.
@@ -114,12 +129,23 @@ pub enum BeanstalkCommand {
     /// pause status.
     ///
     /// On the wire: `stats <tube>`
-    StatsTube { tube: Vec<u8> },
+    StatsTube {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        tube: Vec<u8>,
+    },
     /// Exposes information about the server, including global job counts by
     /// state, number of each command executed, and various internal statuses.
     ///
     /// On the wire: `stats`
+    #[serde(rename = "stats")]
     StatsServer,
+    /// Extension: returns latency statistics for each command handled by the
+    /// server since it started, measured from parsing each command to flushing
+    /// its response. Returns an `OK <n_bytes>` response with a YAML dictionary
+    /// keyed by command name.
+    ///
+    /// On the wire: `stats-latency`
+    StatsLatency,
     /// Returns a list of which tubes currently exist (have been `use`d by any
     /// consumer).
     ///
@@ -145,9 +171,205 @@ pub enum BeanstalkCommand {
     /// `delay` seconds. Returns `PAUSED` or `NOT_FOUND`.
     ///
     /// On the wire: `pause-tube <tube> <delay>`
-    PauseTube { tube: Vec<u8>, delay: u32 },
+    PauseTube {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        tube: Vec<u8>,
+        delay: u32,
+    },
     /// On the wire: `use <tube>`
-    Use { tube: Vec<u8> },
+    Use {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        tube: Vec<u8>,
+    },
+    /// Extension: lists the IDs of jobs in a tube and state whose bodies
+    /// satisfy a predicate, as described in the `query` module. The predicate
+    /// expression follows the command line as `n_bytes` of data, in the same
+    /// way as a `put`. Returns an `OK <n_bytes>` response with associated data
+    /// encoding a YAML-format list of IDs. Bodies that can't be parsed in the
+    /// predicate's format are treated as not matching.
+    ///
+    /// On the wire: `query-jobs <tube> <state> <kind> <n_bytes>`
+    QueryJobs {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        tube: Vec<u8>,
+        state: JobStateKind,
+        kind: PredicateKind,
+        n_bytes: u32,
+    },
+    /// Extension: sets the priority of every job in a tube and state whose
+    /// body satisfies a predicate, which follows the command line as with
+    /// `query-jobs`. Only the ready, delayed, and buried states are accepted.
+    /// Returns `REPRIORITISED <count>` with the number of jobs changed.
+    ///
+    /// On the wire: `reprioritise-jobs <tube> <state> <kind> <pri> <n_bytes>`
+    ReprioritiseJobs {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        tube: Vec<u8>,
+        state: JobStateKind,
+        kind: PredicateKind,
+        pri: u32,
+        n_bytes: u32,
+    },
+    /// Extension: moves every job in a tube and state whose body satisfies a
+    /// predicate to another tube, where the predicate follows the command line
+    /// as with `query-jobs`. Only the ready, delayed, and buried states are
+    /// accepted. Moved jobs keep their ID, priority, remaining delay, and
+    /// counters, and any clients waiting to reserve from the destination tube
+    /// are woken. Returns `MOVED <count>` with the number of jobs moved.
+    ///
+    /// On the wire: `move-jobs <from> <to> <state> <kind> <n_bytes>`
+    MoveJobs {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        from: Vec<u8>,
+        #[serde(serialize_with = "serialise_lossy_str")]
+        to: Vec<u8>,
+        state: JobStateKind,
+        kind: PredicateKind,
+        n_bytes: u32,
+    },
+    /// Extension: as `move-jobs`, but selects jobs with IDs from `first` to
+    /// `last` inclusive rather than by their content.
+    ///
+    /// On the wire: `move-jobs-by-id <from> <to> <state> <first> <last>`
+    MoveJobsById {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        from: Vec<u8>,
+        #[serde(serialize_with = "serialise_lossy_str")]
+        to: Vec<u8>,
+        state: JobStateKind,
+        first: u64,
+        last: u64,
+    },
+    /// Extension: deletes every job in a tube and state whose body satisfies a
+    /// predicate, which follows the command line as with `query-jobs`. Only
+    /// the ready, delayed, and buried states are accepted. Returns
+    /// `DELETED <count>` with the number of jobs deleted.
+    ///
+    /// With the trailing `dry-run` flag, nothing is deleted and an
+    /// `OK <n_bytes>` response lists the jobs that would have been.
+    ///
+    /// On the wire: `delete-jobs <tube> <state> <kind> <n_bytes> [dry-run]`
+    DeleteJobs {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        tube: Vec<u8>,
+        state: JobStateKind,
+        kind: PredicateKind,
+        n_bytes: u32,
+        dry_run: bool,
+    },
+    /// Extension: kicks every job in a tube and state whose body satisfies a
+    /// predicate, which follows the command line as with `query-jobs`. Only
+    /// the buried and delayed states are accepted. Returns `KICKED <count>`
+    /// with the number of jobs kicked.
+    ///
+    /// With the trailing `dry-run` flag, nothing is kicked and an
+    /// `OK <n_bytes>` response lists the jobs that would have been.
+    ///
+    /// On the wire: `kick-jobs <tube> <state> <kind> <n_bytes> [dry-run]`
+    KickJobs {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        tube: Vec<u8>,
+        state: JobStateKind,
+        kind: PredicateKind,
+        n_bytes: u32,
+        dry_run: bool,
+    },
+    /// Extension: subscribes this client to lifecycle events for jobs in a
+    /// tube, and to the tube being paused. Once subscribed, each event is sent
+    /// as an `EVENT` response between the responses to any other commands,
+    /// until the client unsubscribes. Can be repeated to subscribe to more
+    /// tubes. Returns `SUBSCRIBED`.
+    ///
+    /// On the wire: `subscribe <tube>`
+    Subscribe {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        tube: Vec<u8>,
+    },
+    /// Extension: as `subscribe`, but for events in every tube.
+    ///
+    /// On the wire: `subscribe-all`
+    SubscribeAll,
+    /// Extension: cancels any subscriptions made by this client. Events
+    /// queued for the client but not yet sent are dropped, so none follow the
+    /// `UNSUBSCRIBED` response.
+    ///
+    /// On the wire: `unsubscribe`
+    Unsubscribe,
+    /// Extension: authenticates this client as a user, as described in the
+    /// `auth` module, replacing any role it already had. Returns
+    /// `AUTHENTICATED`, or `NOT_PERMITTED` if the user doesn't exist or the
+    /// token is wrong.
+    ///
+    /// On the wire: `auth <user> <token>`
+    Auth {
+        #[serde(serialize_with = "serialise_lossy_str")]
+        user: Vec<u8>,
+        /// Never logged.
+        #[serde(skip)]
+        token: Vec<u8>,
+    },
+}
+
+impl BeanstalkCommand {
+    /// Returns the length of the data following this command's line, such as
+    /// a job body or predicate, for commands followed by any.
+    pub fn data_len(&self) -> Option<u32> {
+        use BeanstalkCommand::*;
+
+        match self {
+            Put { n_bytes, .. }
+            | QueryJobs { n_bytes, .. }
+            | ReprioritiseJobs { n_bytes, .. }
+            | MoveJobs { n_bytes, .. }
+            | DeleteJobs { n_bytes, .. }
+            | KickJobs { n_bytes, .. } => Some(*n_bytes),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of this command as sent on the wire.
+    pub fn name(&self) -> &'static str {
+        use BeanstalkCommand::*;
+
+        match self {
+            Put { .. } => "put",
+            Reserve => "reserve",
+            ReserveWithTimeout { .. } => "reserve-with-timeout",
+            ReserveJob { .. } => "reserve-job",
+            Release { .. } => "release",
+            Delete { .. } => "delete",
+            Bury { .. } => "bury",
+            Touch { .. } => "touch",
+            Watch { .. } => "watch",
+            Ignore { .. } => "ignore",
+            Peek { .. } => "peek",
+            PeekReady => "peek-ready",
+            PeekDelayed => "peek-delayed",
+            PeekBuried => "peek-buried",
+            Kick { .. } => "kick",
+            KickJob { .. } => "kick-job",
+            StatsJob { .. } => "stats-job",
+            StatsTube { .. } => "stats-tube",
+            StatsServer => "stats",
+            StatsLatency => "stats-latency",
+            ListTubes => "list-tubes",
+            ListTubeUsed => "list-tube-used",
+            ListTubesWatched => "list-tubes-watched",
+            Quit => "quit",
+            PauseTube { .. } => "pause-tube",
+            Use { .. } => "use",
+            QueryJobs { .. } => "query-jobs",
+            ReprioritiseJobs { .. } => "reprioritise-jobs",
+            MoveJobs { .. } => "move-jobs",
+            MoveJobsById { .. } => "move-jobs-by-id",
+            DeleteJobs { .. } => "delete-jobs",
+            KickJobs { .. } => "kick-jobs",
+            Subscribe { .. } => "subscribe",
+            SubscribeAll => "subscribe-all",
+            Unsubscribe => "unsubscribe",
+            Auth { .. } => "auth",
+        }
+    }
 }
 
 /// All possible response types to a `BeanstalkRequest`.
@@ -216,6 +438,11 @@ pub enum BeanstalkResponse {
     /// On the wire: `TIMED_OUT`.
     TimedOut,
     /// In response to a `reserve`, `reserve-with-timeout`, or `reserve-job`,
+    /// indicates the server is shutting down, so won't hand out any more jobs.
+    ///
+    /// On the wire: `SHUTTING_DOWN`.
+    ShuttingDown,
+    /// In response to a `reserve`, `reserve-with-timeout`, or `reserve-job`,
     /// provides the ID and data of the job that was just reserved.
     ///
     /// On the wire: `RESERVED <id> <n_bytes>` plus data.
@@ -244,6 +471,11 @@ pub enum BeanstalkResponse {
     ///
     /// On the wire: `DELETED`.
     Deleted,
+    /// In response to a `delete-jobs` command, indicates success with the
+    /// number of jobs deleted.
+    ///
+    /// On the wire: `DELETED <count>`.
+    DeletedCount { count: u64 },
     /// In response to a `release` command, indicates the job was successfully
     /// released back to the ready or delayed states.
     ///
@@ -275,10 +507,21 @@ pub enum BeanstalkResponse {
     /// On the wire: `FOUND <id> <n_bytes>` plus data.
     Found { id: u64, data: Vec<u8> },
     /// In response to a `kick`, indicates success with the number of jobs
-    /// kicked from the buried xor delayed states.
+    /// kicked from the buried xor delayed states. Also sent in response to a
+    /// `kick-jobs` with the number of matching jobs kicked.
     ///
     /// On the wire: `KICKED <count>`.
     KickedCount { count: u64 },
+    /// In response to a `reprioritise-jobs`, indicates success with the number
+    /// of jobs whose priority was set.
+    ///
+    /// On the wire: `REPRIORITISED <count>`.
+    ReprioritisedCount { count: u64 },
+    /// In response to a `move-jobs` or `move-jobs-by-id`, indicates success
+    /// with the number of jobs moved.
+    ///
+    /// On the wire: `MOVED <count>`.
+    MovedCount { count: u64 },
     /// In response to a `kick-job`, indicates success.
     ///
     /// On the wire: `KICKED`.
@@ -290,19 +533,62 @@ pub enum BeanstalkResponse {
     ///In response to a `stats`, indicates success.
     ///
     /// On the wire: `OK <n_bytes>` plus data in YAML dictionary format.
-    OkStats { data: ServerStats },
+    OkStats { data: Box<ServerStats> },
     ///In response to a `stats-tube`, indicates success.
     ///
     /// On the wire: `OK <n_bytes>` plus data in YAML dictionary format.
     OkStatsTube { data: TubeStats },
+    /// In response to a `stats-latency`, indicates success.
+    ///
+    /// On the wire: `OK <n_bytes>` plus data in YAML dictionary format.
+    OkStatsLatency { data: LatencyStats },
     ///In response to a `list-tubes` or `list-tubes-watched`, indicates success.
     ///
     /// On the wire: `OK <n_bytes>` plus data in YAML *list* format.
     OkListTubes { tubes: Vec<Vec<u8>> },
+    /// In response to a `query-jobs`, indicates success with the IDs of any
+    /// matching jobs.
+    ///
+    /// On the wire: `OK <n_bytes>` plus data in YAML *list* format.
+    OkJobIds { ids: Vec<u64> },
+    /// In response to a `delete-jobs` or `kick-jobs` with the `dry-run` flag,
+    /// indicates success with the jobs that would have been affected.
+    ///
+    /// On the wire: `OK <n_bytes>` plus data in YAML dictionary format.
+    OkDryRun { data: DryRunStats },
     /// In response to a `pause-tube`, indicates success.
     ///
     /// On the wire: `PAUSED`.
     Paused,
+    /// In response to an `auth`, indicates success.
+    ///
+    /// On the wire: `AUTHENTICATED`.
+    Authenticated,
+    /// Indicates the client isn't permitted to run a command, or in response
+    /// to an `auth`, that authentication failed. Can be sent in response to
+    /// any command that touches jobs when access control is enabled.
+    ///
+    /// On the wire: `NOT_PERMITTED`.
+    NotPermitted,
+    /// In response to a `subscribe` or `subscribe-all`, indicates success.
+    ///
+    /// On the wire: `SUBSCRIBED`.
+    Subscribed,
+    /// In response to an `unsubscribe`, indicates success.
+    ///
+    /// On the wire: `UNSUBSCRIBED`.
+    Unsubscribed,
+    /// Sent to subscribed clients as events happen, rather than in response to
+    /// a command.
+    ///
+    /// On the wire: `EVENT <n_bytes>` plus data in JSON (and so YAML)
+    /// dictionary format, on a single line.
+    Event { event: Event },
+    /// Sent to subscribed clients in place of events that were dropped because
+    /// the client wasn't reading them quickly enough.
+    ///
+    /// On the wire: `EVENTS_DROPPED <count>`.
+    EventsDropped { count: u64 },
 }
 
 impl BeanstalkSerialisable for BeanstalkResponse {
@@ -324,6 +610,7 @@ impl BeanstalkSerialisable for BeanstalkResponse {
             },
             DeadlineSoon => b"DEADLINE_SOON\r\n".to_vec(),
             TimedOut => b"TIMED_OUT\r\n".to_vec(),
+            ShuttingDown => b"SHUTTING_DOWN\r\n".to_vec(),
             Reserved { id, data } => [
                 format!("RESERVED {id} {}\r\n", data.len()).into_bytes(),
                 data.to_owned(), // TODO: reduce copying
@@ -344,6 +631,10 @@ impl BeanstalkSerialisable for BeanstalkResponse {
             },
             KickedCount { count } => format!("KICKED {count}\r\n").into(),
             Kicked => b"KICKED\r\n".to_vec(),
+            ReprioritisedCount { count } => {
+                format!("REPRIORITISED {count}\r\n").into()
+            },
+            MovedCount { count } => format!("MOVED {count}\r\n").into(),
             OkStatsJob { data } => {
                 let data = serde_yaml::to_string(data).unwrap();
                 format!("OK {}\r\n{data}\r\n", data.len()).into()
@@ -353,11 +644,37 @@ impl BeanstalkSerialisable for BeanstalkResponse {
                 format!("OK {}\r\n{data}\r\n", data.len()).into()
             },
             OkListTubes { tubes } => {
-                let data = serde_yaml::to_string(tubes).unwrap();
+                let tubes: Vec<_> =
+                    tubes.iter().map(|t| String::from_utf8_lossy(t)).collect();
+                let data = serde_yaml::to_string(&tubes).unwrap();
+                format!("OK {}\r\n{data}\r\n", data.len()).into()
+            },
+            OkStatsLatency { data } => {
+                let data = serde_yaml::to_string(data).unwrap();
+                format!("OK {}\r\n{data}\r\n", data.len()).into()
+            },
+            OkJobIds { ids } => {
+                let data = serde_yaml::to_string(ids).unwrap();
+                format!("OK {}\r\n{data}\r\n", data.len()).into()
+            },
+            OkDryRun { data } => {
+                let data = serde_yaml::to_string(data).unwrap();
                 format!("OK {}\r\n{data}\r\n", data.len()).into()
             },
             Paused => b"PAUSED\r\n".to_vec(),
+            Authenticated => b"AUTHENTICATED\r\n".to_vec(),
+            NotPermitted => b"NOT_PERMITTED\r\n".to_vec(),
+            Subscribed => b"SUBSCRIBED\r\n".to_vec(),
+            Unsubscribed => b"UNSUBSCRIBED\r\n".to_vec(),
+            Event { event } => {
+                let data = serde_json::to_string(event).unwrap();
+                format!("EVENT {}\r\n{data}\r\n", data.len()).into()
+            },
+            EventsDropped { count } => {
+                format!("EVENTS_DROPPED {count}\r\n").into()
+            },
             Deleted => b"DELETED\r\n".to_vec(),
+            DeletedCount { count } => format!("DELETED {count}\r\n").into(),
             Buried => b"BURIED\r\n".to_vec(),
             Touched => b"TOUCHED\r\n".to_vec(),
             OkStatsTube { data } => {
@@ -373,6 +690,7 @@ pub struct JobStats {
     /// job ID
     pub(crate) id: u64,
     /// tube containing job
+    #[serde(serialize_with = "serialise_lossy_str")]
     pub(crate) tube: Vec<u8>,
     /// job state
     pub(crate) state: JobState,
@@ -404,9 +722,27 @@ pub struct JobStats {
     pub(crate) kicks: u64, // TODO: size
 }
 
+#[derive(Debug, Serialize)]
+pub struct DryRunStats {
+    /// number of jobs that would be affected
+    pub(crate) count: u64,
+    /// IDs of the jobs that would be affected
+    pub(crate) ids: Vec<u64>,
+}
+
+impl From<Vec<u64>> for DryRunStats {
+    fn from(ids: Vec<u64>) -> Self {
+        Self {
+            count: ids.len() as u64,
+            ids,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct TubeStats {
     /// tube name
+    #[serde(serialize_with = "serialise_lossy_str")]
     pub(crate) name: Vec<u8>,
     /// number of jobs in ready state with priority < 1024
     #[serde(rename = "current-jobs-urgent")]
@@ -449,7 +785,7 @@ pub struct TubeStats {
     pub(crate) pause_time_left: u32,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct ServerStats {
     /// number of ready jobs with priority < 1024
     #[serde(rename = "current-jobs-urgent")]
@@ -593,11 +929,73 @@ pub struct ServerStats {
     /// is server is in drain mode
     pub(crate) draining: bool,
     /// random id string for this server process, generated every time beanstalkd process starts
+    #[serde(serialize_with = "serialise_lossy_str")]
     pub(crate) id: Vec<u8>,
     // hostname of the machine as determined by uname
+    #[serde(serialize_with = "serialise_lossy_str")]
     pub(crate) hostname: Vec<u8>,
     /// OS version as determined by uname
+    #[serde(serialize_with = "serialise_lossy_str")]
     pub(crate) os: Vec<u8>,
     // machine architecture as determined by uname
+    #[serde(serialize_with = "serialise_lossy_str")]
     pub(crate) platform: Vec<u8>,
 }
+
+impl ServerStats {
+    /// Creates stats for this server process with all counters at zero.
+    pub fn new() -> Self {
+        Self {
+            pid: std::process::id(),
+            version: env!("CARGO_PKG_VERSION"),
+            ..Default::default()
+        }
+    }
+
+    /// Adds the `cmd-*` counts of `other` to these.
+    pub fn add_commands(&mut self, other: &Self) {
+        self.cmd_put += other.cmd_put;
+        self.cmd_peek += other.cmd_peek;
+        self.cmd_peek_ready += other.cmd_peek_ready;
+        self.cmd_peek_delayed += other.cmd_peek_delayed;
+        self.cmd_peek_buried += other.cmd_peek_buried;
+        self.cmd_reserve += other.cmd_reserve;
+        self.cmd_reserve_with_timeout += other.cmd_reserve_with_timeout;
+        self.cmd_touch += other.cmd_touch;
+        self.cmd_use += other.cmd_use;
+        self.cmd_watch += other.cmd_watch;
+        self.cmd_ignore += other.cmd_ignore;
+        self.cmd_delete += other.cmd_delete;
+        self.cmd_release += other.cmd_release;
+        self.cmd_bury += other.cmd_bury;
+        self.cmd_kick += other.cmd_kick;
+        self.cmd_stats += other.cmd_stats;
+        self.cmd_stats_job += other.cmd_stats_job;
+        self.cmd_stats_tube += other.cmd_stats_tube;
+        self.cmd_list_tubes += other.cmd_list_tubes;
+        self.cmd_list_tube_used += other.cmd_list_tube_used;
+        self.cmd_list_tubes_watched += other.cmd_list_tubes_watched;
+        self.cmd_pause_tube += other.cmd_pause_tube;
+    }
+
+    /// Sets the number of seconds this server process has been running.
+    pub fn set_uptime(&mut self, uptime: u32) {
+        self.uptime = uptime;
+    }
+
+    /// Sets the number of connections currently open, and accepted in total.
+    pub fn set_connections(&mut self, current: u64, total: u64) {
+        self.current_connections = current;
+        self.total_connections = total;
+    }
+
+    /// Sets the largest job size accepted.
+    pub fn set_max_job_size(&mut self, max_job_size: u32) {
+        self.max_job_size = max_job_size.into();
+    }
+
+    /// Sets whether the server is in drain mode.
+    pub fn set_draining(&mut self, draining: bool) {
+        self.draining = draining;
+    }
+}
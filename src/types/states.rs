@@ -1,28 +1,66 @@
-use std::time::Instant;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 
-use serde::Serialize;
-
-#[derive(Debug)]
-pub(crate) enum JobState {
+#[derive(Clone, Copy, Debug)]
+pub enum JobState {
     Ready,
-    Delayed { until: Instant },
-    Reserved { at: Instant },
+    Delayed {
+        until: Instant,
+    },
+    /// Reserved by the client `by`, with its TTR counted from `at`, when it
+    /// was reserved or last touched.
+    Reserved {
+        at: Instant,
+        by: u64,
+    },
     Buried,
 }
 
+impl JobState {
+    /// Returns which state this is, discarding any state-specific data.
+    pub fn kind(&self) -> JobStateKind {
+        use JobState::*;
+
+        match self {
+            Ready => JobStateKind::Ready,
+            Delayed { until: _ } => JobStateKind::Delayed,
+            Reserved { .. } => JobStateKind::Reserved,
+            Buried => JobStateKind::Buried,
+        }
+    }
+}
+
 // This impl is used to allow JobStats to be serialised to YAML.
 impl Serialize for JobState {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        use JobState::*;
+        serializer.serialize_str(self.kind().name())
+    }
+}
+
+/// The state of a job without its state-specific data, as used when selecting
+/// jobs by state.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStateKind {
+    Ready,
+    Delayed,
+    Reserved,
+    Buried,
+}
+
+impl JobStateKind {
+    /// Returns the name of this state, as used on the wire.
+    pub fn name(&self) -> &'static str {
+        use JobStateKind::*;
 
-        serializer.serialize_str(match self {
+        match self {
             Ready => "ready",
-            Delayed { until: _ } => "delayed",
-            Reserved { at: _ } => "reserved",
+            Delayed => "delayed",
+            Reserved => "reserved",
             Buried => "buried",
-        })
+        }
     }
 }
@@ -1,5 +1,7 @@
 use std::ascii;
 
+use serde::Serializer;
+
 pub fn bytes_to_human_str(input: &[u8]) -> String {
     String::from_utf8(
         input
@@ -9,3 +11,30 @@ pub fn bytes_to_human_str(input: &[u8]) -> String {
     )
     .unwrap()
 }
+
+/// Serialises bytes as a string rather than a list of numbers, replacing any
+/// invalid UTF-8. Used for names in stats responses, which are always text.
+pub(crate) fn serialise_lossy_str<S>(
+    input: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&String::from_utf8_lossy(input))
+}
+
+/// As `serialise_lossy_str`, for optional names. Pair it with
+/// `skip_serializing_if = "Option::is_none"`.
+pub(crate) fn serialise_lossy_opt_str<S>(
+    input: &Option<Vec<u8>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match input {
+        Some(input) => serialise_lossy_str(input, serializer),
+        None => serializer.serialize_none(),
+    }
+}